@@ -0,0 +1,923 @@
+#![warn(clippy::all, clippy::pedantic, clippy::nursery)]
+//! # tunnelbana-auth
+//! A tower middleware to require HTTP Basic or bearer token authentication
+//! on specific routes, or route groups.
+//!
+//! Part of the [tunnelbana](https://github.com/randomairborne/tunnelbana) project.
+//!
+//! Passwords are stored as bcrypt or argon2 hashes, like the ones `htpasswd -B`
+//! writes. They're checked on tokio's blocking threads, so the services need
+//! a tokio runtime. Every response to a protected route gets
+//! `Cache-Control: private`, so shared caches never hand it to someone else.
+//!
+//! # Example
+//! ```rust
+//! use tower_http::services::ServeDir;
+//! use tower::ServiceBuilder;
+//! use tunnelbana_auth::AuthLayer;
+//!
+//! let config = r#"
+//!/staging/*
+//!  alice:$2b$05$t81wjJH/ASZeb5OCiBhCp.6edItZqFwzqSlfQl466momz0gCT5lmq
+//!  Bearer 6f1a1d2c0e9b
+//!/reports/{year}
+//!  Bearer 3b8e71c4a5d0
+//!"#;
+//! let groups = tunnelbana_auth::parse(config).expect("Failed to parse auth");
+//! let auth_mw = AuthLayer::new(groups).expect("Failed to route auth");
+//! let serve_dir = ServeDir::new("/var/www/html").append_index_html_on_directories(true);
+//! let service = ServiceBuilder::new()
+//!    .layer(auth_mw)
+//!    .service(serve_dir);
+//! ```
+use std::{
+    collections::HashSet,
+    fmt::{Debug, Formatter, Result as FmtResult},
+    future::Future,
+    num::NonZeroUsize,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+};
+
+use arc_swap::ArcSwap;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use http::{HeaderMap, HeaderValue, Request, Response, StatusCode, header};
+pub use matchit::InsertError;
+use matchit::Router;
+use percent_encoding::percent_decode_str;
+use subtle::ConstantTimeEq;
+use tokio::sync::Semaphore;
+use tower::{Layer, Service};
+
+#[macro_use]
+extern crate tracing;
+
+/// Most `Authorization` headers remembered as valid for each group, so
+/// password hashes aren't checked again on every request.
+const VERIFIED_CACHE_SIZE: usize = 1024;
+
+#[derive(Clone, Debug)]
+/// Added to the extensions of every response to a protected route which had valid credentials.
+pub struct Authenticated {
+    /// The path of the matching group, as written in the `_auth` file.
+    pub rule: Arc<str>,
+    /// The name of the user who signed in, or `None` for a bearer token.
+    pub user: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+/// Added to the extensions of every `401` sent for missing or invalid credentials.
+pub struct Unauthenticated {
+    /// The path of the matching group, as written in the `_auth` file.
+    pub rule: Arc<str>,
+}
+
+#[derive(Clone, Debug)]
+/// Request extension naming the path of the file a request is served from.
+///
+/// A layer further in can serve a request from another path than its own,
+/// like `/about.html` for `/about`. Groups are matched against this as well,
+/// so a group for the file can't be got past by asking for it under another path.
+pub struct ServedPath(pub String);
+
+#[derive(Clone)]
+/// Something which grants access to a group's routes.
+pub enum Credential {
+    /// A user name and the hash of their password, sent with HTTP Basic authentication
+    User {
+        name: String,
+        hash: PasswordHashKind,
+    },
+    /// A static token, sent as `Authorization: Bearer <token>`
+    Token(String),
+}
+
+impl Credential {
+    /// Parse an htpasswd-style `name:hash` line, where the hash is bcrypt or argon2.
+    /// # Errors
+    /// If there's no colon, the name is empty, or the hash can't be read.
+    pub fn user(line: &str) -> Result<Self, AuthParseErrorKind> {
+        let (name, hash) = line
+            .split_once(':')
+            .ok_or(AuthParseErrorKind::NoUserColon)?;
+        if name.is_empty() {
+            return Err(AuthParseErrorKind::EmptyUser);
+        }
+        Ok(Self::User {
+            name: name.to_owned(),
+            hash: hash.parse()?,
+        })
+    }
+
+    /// A bearer token.
+    /// # Errors
+    /// If the token is empty, or isn't valid in a header.
+    pub fn token(token: &str) -> Result<Self, AuthParseErrorKind> {
+        if token.is_empty() || HeaderValue::from_str(token).is_err() {
+            return Err(AuthParseErrorKind::InvalidToken);
+        }
+        Ok(Self::Token(token.to_owned()))
+    }
+}
+
+impl Debug for Credential {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        // hashes and tokens stay out of logs
+        match self {
+            Self::User { name, .. } => f.debug_struct("User").field("name", name).finish(),
+            Self::Token(_) => f.write_str("Token(..)"),
+        }
+    }
+}
+
+#[derive(Clone)]
+/// A password hash, in the PHC string format.
+pub enum PasswordHashKind {
+    /// `$2y$`, `$2b$`, `$2a$` or `$2x$`, as written by `htpasswd -B`
+    Bcrypt(String),
+    /// `$argon2id$`, `$argon2i$` or `$argon2d$`
+    Argon2(String),
+}
+
+impl std::str::FromStr for PasswordHashKind {
+    type Err = AuthParseErrorKind;
+
+    fn from_str(hash: &str) -> Result<Self, Self::Err> {
+        if hash.starts_with("$2") {
+            hash.parse::<bcrypt::HashParts>()
+                .map_err(|_| AuthParseErrorKind::InvalidHash)?;
+            Ok(Self::Bcrypt(hash.to_owned()))
+        } else if hash.starts_with("$argon2") {
+            PasswordHash::new(hash).map_err(|_| AuthParseErrorKind::InvalidHash)?;
+            Ok(Self::Argon2(hash.to_owned()))
+        } else {
+            Err(AuthParseErrorKind::UnknownHash)
+        }
+    }
+}
+
+impl PasswordHashKind {
+    fn verify(&self, password: &[u8]) -> bool {
+        match self {
+            Self::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Self::Argon2(hash) => PasswordHash::new(hash)
+                .is_ok_and(|hash| Argon2::default().verify_password(password, &hash).is_ok()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AuthGroup {
+    /// The path pattern, as written. A trailing `*` also protects the path above it.
+    pub path: String,
+    pub credentials: Vec<Credential>,
+    /// The line of the `_auth` file this group's path is on, starting at 1.
+    pub line: usize,
+}
+
+/// Parse a list of [`AuthGroup`]s from an `_auth` string, which is laid out
+/// like a `_headers` file: each unindented path is followed by indented
+/// `name:hash` or `Bearer token` lines.
+/// # Errors
+/// This function errors if you have an orphaned credential, if a hash or token is invalid,
+/// if a group has no credentials, if your path cannot be a matchit path, or if two groups
+/// conflict. The first error is returned.
+pub fn parse(auth_file: &str) -> Result<Vec<AuthGroup>, AuthParseError> {
+    let (groups, errors) = parse_all(auth_file);
+    errors.into_iter().next().map_or(Ok(groups), Err)
+}
+
+/// Like [`parse`], but carries on past errors, returning every group which
+/// could be parsed along with every error in the file.
+#[must_use]
+pub fn parse_all(auth_file: &str) -> (Vec<AuthGroup>, Vec<AuthParseError>) {
+    let mut groups = Vec::new();
+    let mut errors = Vec::new();
+    let mut current_ctx: Option<AuthGroup> = None;
+    for (idx, line) in auth_file.lines().enumerate() {
+        if line.is_empty() || line.trim().starts_with('#') {
+            continue;
+        }
+        if line.starts_with(['\t', ' ']) {
+            let Some(ctx) = current_ctx.as_mut() else {
+                errors.push(AuthParseError::new(
+                    AuthParseErrorKind::NoParseCtx,
+                    idx,
+                    indent(line),
+                ));
+                continue;
+            };
+            match parse_credential(line.trim()) {
+                Ok(credential) => ctx.credentials.push(credential),
+                Err(kind) => errors.push(AuthParseError::new(kind, idx, indent(line))),
+            }
+        } else {
+            let group = AuthGroup {
+                path: line.trim().to_string(),
+                credentials: Vec::new(),
+                line: idx + 1,
+            };
+            if let Some(group) = current_ctx.replace(group) {
+                groups.push(group);
+            }
+        }
+    }
+    groups.extend(current_ctx);
+
+    // catches groups which conflict with an earlier one, like `AuthLayer::new` would
+    let mut router = Router::new();
+    let mut checked = Vec::with_capacity(groups.len());
+    for group in groups {
+        if group.credentials.is_empty() {
+            errors.push(AuthParseError::new(
+                AuthParseErrorKind::NoCredentials,
+                group.line - 1,
+                1,
+            ));
+            continue;
+        }
+        let inserted = routes(&group.path).try_for_each(|path| router.insert(path, ()));
+        match inserted {
+            Ok(()) => checked.push(group),
+            Err(e) => errors.push(AuthParseError::new(
+                AuthParseErrorKind::Conflict(e),
+                group.line - 1,
+                1,
+            )),
+        }
+    }
+    errors.sort_by_key(|e| (e.row, e.column));
+    info!(groups = checked.len(), "Got auth groups");
+    (checked, errors)
+}
+
+/// Parse an indented `name:hash` or `Bearer token` line.
+fn parse_credential(line: &str) -> Result<Credential, AuthParseErrorKind> {
+    let (scheme, token) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Credential::token(token.trim())
+    } else {
+        Credential::user(line)
+    }
+}
+
+/// The column of the first character of `text` which isn't whitespace.
+fn indent(text: &str) -> usize {
+    text.chars().take_while(|c| c.is_whitespace()).count() + 1
+}
+
+/// The matchit paths a group's path is routed as.
+///
+/// A `*` character will register for all subpaths, and also the path above it,
+/// with and without its trailing slash, since clean URLs can serve a directory at either.
+pub fn routes(path: &str) -> impl Iterator<Item = String> + use<> {
+    let Some(base) = path.strip_suffix('*') else {
+        return vec![path.to_owned()].into_iter();
+    };
+    let mut routes = vec![base.to_owned(), format!("{base}{{*all}}")];
+    if let Some(dir) = base.strip_suffix('/')
+        && !dir.is_empty()
+    {
+        routes.push(dir.to_owned());
+    }
+    routes.into_iter()
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("at line {row}, column {column}: {kind}")]
+/// Describes the location and type of an `_auth` parsing problem.
+pub struct AuthParseError {
+    pub row: usize,
+    /// The column the problem starts at, starting at 1.
+    pub column: usize,
+    #[source]
+    pub kind: AuthParseErrorKind,
+}
+
+impl AuthParseError {
+    const fn new(kind: AuthParseErrorKind, idx: usize, column: usize) -> Self {
+        Self {
+            row: idx + 1,
+            column,
+            kind,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+/// Types of `_auth` parsing errors.
+pub enum AuthParseErrorKind {
+    #[error("You must specify an unindented path before specifying credentials")]
+    NoParseCtx,
+    #[error("You must write users as name:hash")]
+    NoUserColon,
+    #[error("User names can't be empty")]
+    EmptyUser,
+    #[error("Password hashes must be bcrypt ($2y$...) or argon2 ($argon2id$...)")]
+    UnknownHash,
+    #[error("Password hash could not be read")]
+    InvalidHash,
+    #[error("Bearer tokens must be non-empty and valid in a header")]
+    InvalidToken,
+    #[error("A path needs at least one user or token")]
+    NoCredentials,
+    #[error("Conflicts with an earlier path: {0}")]
+    Conflict(matchit::InsertError),
+}
+
+/// A group's credentials, shared by every route its path is expanded to.
+struct AuthRule {
+    rule: Arc<str>,
+    users: Vec<(String, PasswordHashKind)>,
+    tokens: Vec<String>,
+    /// `WWW-Authenticate` values sent with a `401`
+    challenges: Arc<[HeaderValue]>,
+    /// `Authorization` headers already found to be valid
+    verified: Mutex<HashSet<HeaderValue>>,
+}
+
+impl AuthRule {
+    fn new(group: AuthGroup) -> Self {
+        let mut users = Vec::new();
+        let mut tokens = Vec::new();
+        for credential in group.credentials {
+            match credential {
+                Credential::User { name, hash } => users.push((name, hash)),
+                Credential::Token(token) => tokens.push(token),
+            }
+        }
+        let realm = quote(&group.path);
+        let mut challenges = Vec::new();
+        if !users.is_empty() {
+            challenges.push(format!("Basic realm={realm}, charset=\"UTF-8\""));
+        }
+        if !tokens.is_empty() {
+            challenges.push(format!("Bearer realm={realm}"));
+        }
+        Self {
+            rule: group.path.into(),
+            users,
+            tokens,
+            challenges: challenges
+                .into_iter()
+                .filter_map(|challenge| HeaderValue::try_from(challenge).ok())
+                .collect(),
+            verified: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Who `authorization` signs in as, or `None` if it isn't valid for this
+    /// rule. Passwords which haven't been seen before are checked on a
+    /// blocking thread, once one of `verifying`'s permits is free.
+    fn check(self: &Arc<Self>, authorization: &HeaderValue, verifying: &Arc<Semaphore>) -> Check {
+        let Some((scheme, value)) = authorization
+            .to_str()
+            .ok()
+            .and_then(|authorization| authorization.split_once(' '))
+        else {
+            return Check::Done(None);
+        };
+        let value = value.trim();
+        if scheme.eq_ignore_ascii_case("bearer") {
+            let valid = self
+                .tokens
+                .iter()
+                .any(|token| bool::from(token.as_bytes().ct_eq(value.as_bytes())));
+            return Check::Done(valid.then(|| self.authenticated(None)));
+        }
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return Check::Done(None);
+        }
+        let decoded = BASE64
+            .decode(value)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        let Some((name, password)) = decoded.as_deref().and_then(|d| d.split_once(':')) else {
+            return Check::Done(None);
+        };
+        let user = self.users.iter().find(|(user, _)| user == name);
+        let Some((_, hash)) = user.or_else(|| self.users.first()) else {
+            return Check::Done(None);
+        };
+        // an unknown user is still checked against someone's hash, so how long
+        // a `401` takes doesn't give away which users exist
+        let known = user.is_some();
+
+        let verified = self
+            .verified
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(authorization);
+        if known && verified {
+            return Check::Done(Some(self.authenticated(Some(name))));
+        }
+
+        let (rule, verifying) = (self.clone(), verifying.clone());
+        let (hash, password) = (hash.clone(), password.to_owned());
+        let (name, authorization) = (name.to_owned(), authorization.clone());
+        Check::Verifying(Box::pin(async move {
+            let permit = verifying.acquire_owned().await.ok()?;
+            // the permit goes with the hashing, which carries on if the request is dropped
+            let valid = tokio::task::spawn_blocking(move || {
+                let _permit = permit;
+                hash.verify(password.as_bytes())
+            })
+            .await
+            .unwrap_or(false);
+            if !(known && valid) {
+                return None;
+            }
+            let mut verified = rule.verified.lock().unwrap_or_else(PoisonError::into_inner);
+            if verified.len() >= VERIFIED_CACHE_SIZE {
+                verified.clear();
+            }
+            verified.insert(authorization);
+            drop(verified);
+            Some(rule.authenticated(Some(&name)))
+        }))
+    }
+
+    fn authenticated(&self, user: Option<&str>) -> Authenticated {
+        Authenticated {
+            rule: self.rule.clone(),
+            user: user.map(str::to_owned),
+        }
+    }
+}
+
+/// A password check running on a blocking thread, resolving to who signed in.
+pub type Verifying = Pin<Box<dyn Future<Output = Option<Authenticated>> + Send>>;
+
+/// The outcome of [`AuthRule::check`].
+enum Check {
+    Done(Option<Authenticated>),
+    Verifying(Verifying),
+}
+
+/// A semaphore with a permit for each CPU, for checking passwords.
+fn verifying() -> Arc<Semaphore> {
+    let cpus = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    Arc::new(Semaphore::new(cpus))
+}
+
+/// `text` as a quoted string, for a `WWW-Authenticate` parameter.
+fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        if matches!(c, '"' | '\\') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The path matched against groups, the way file services see it.
+///
+/// It's percent-decoded, with empty and `.` segments removed and `..` segments
+/// applied. Otherwise `/st%61ging/` or `/public/../staging/` would get past
+/// `/staging/*`. Other layers which guard routes should match against it too.
+#[must_use]
+pub fn route_path(path: &str) -> String {
+    let decoded = percent_decode_str(path).decode_utf8_lossy();
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => _ = segments.pop(),
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized = String::with_capacity(decoded.len());
+    for segment in segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if decoded.ends_with('/') || normalized.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// Make `headers` say the response may only be cached by the client it's for.
+fn make_private(headers: &mut HeaderMap) {
+    let mut value = String::from("private");
+    let directives = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim);
+    for directive in directives {
+        let name = directive.split('=').next().unwrap_or_default().trim();
+        let shared = ["public", "private", "s-maxage"]
+            .iter()
+            .any(|shared| name.eq_ignore_ascii_case(shared));
+        if !directive.is_empty() && !shared {
+            value.push_str(", ");
+            value.push_str(directive);
+        }
+    }
+    let value =
+        HeaderValue::try_from(value).unwrap_or_else(|_| HeaderValue::from_static("private"));
+    headers.insert(header::CACHE_CONTROL, value);
+}
+
+type AuthRouter = Router<Arc<AuthRule>>;
+
+#[derive(Clone)]
+/// a [`tower::Layer`] to add to a [`tower::ServiceBuilder`] to require credentials.
+///
+/// Clones of this layer, and every service made from it, share the same groups,
+/// so a call to [`Self::reload`] affects all of them. The default layer protects nothing.
+///
+/// They also share a limit of one password check at a time for each CPU, so
+/// guessing passwords can't take up every thread.
+pub struct AuthLayer {
+    groups: Arc<ArcSwap<AuthRouter>>,
+    verifying: Arc<Semaphore>,
+}
+
+impl Default for AuthLayer {
+    fn default() -> Self {
+        Self {
+            groups: Arc::default(),
+            verifying: verifying(),
+        }
+    }
+}
+
+impl Debug for AuthLayer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("AuthLayer").finish_non_exhaustive()
+    }
+}
+
+impl AuthLayer {
+    /// Create a new [`AuthLayer`]. The groups are added to a matchit router internally.
+    /// # Errors
+    /// If two [`AuthGroup`]s are the same, or would illegally overlap
+    /// an error can be returned
+    pub fn new(groups: Vec<AuthGroup>) -> Result<Self, InsertError> {
+        let groups = build_router(groups)?;
+        Ok(Self {
+            groups: Arc::new(ArcSwap::from_pointee(groups)),
+            verifying: verifying(),
+        })
+    }
+
+    /// Replace the groups protected by this layer and all services made from it.
+    /// # Errors
+    /// If the new groups can't be routed. The old groups keep being
+    /// protected in that case.
+    pub fn reload(&self, groups: Vec<AuthGroup>) -> Result<(), InsertError> {
+        let groups = build_router(groups)?;
+        self.groups.store(Arc::new(groups));
+        Ok(())
+    }
+
+    /// The path of the group protecting `path`, as written, if any.
+    #[must_use]
+    pub fn matched(&self, path: &str) -> Option<Arc<str>> {
+        let groups = self.groups.load();
+        let path = route_path(path);
+        let matched = groups.at(&path).ok()?;
+        Some(matched.value.rule.clone())
+    }
+}
+
+/// The rule protecting `req`, going by its own path, then by its [`ServedPath`].
+fn rule_for<B>(groups: &AuthRouter, req: &Request<B>) -> Option<Arc<AuthRule>> {
+    if let Ok(matched) = groups.at(&route_path(req.uri().path())) {
+        return Some(matched.value.clone());
+    }
+    let served = req.extensions().get::<ServedPath>()?;
+    let served = route_path(&served.0);
+    let matched = groups.at(&served).ok()?;
+    Some(matched.value.clone())
+}
+
+fn build_router(groups: Vec<AuthGroup>) -> Result<AuthRouter, InsertError> {
+    let mut router = Router::new();
+    for group in groups {
+        let paths: Vec<String> = routes(&group.path).collect();
+        let rule = Arc::new(AuthRule::new(group));
+        for path in paths {
+            router.insert(path, rule.clone())?;
+        }
+    }
+    Ok(router)
+}
+
+impl<S> Layer<S> for AuthLayer {
+    type Service = Auth<S>;
+
+    fn layer(&self, inner: S) -> Auth<S> {
+        Auth {
+            groups: self.groups.clone(),
+            verifying: self.verifying.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+/// a [`tower::Service`] which requires credentials before calling a wrapped S.
+pub struct Auth<S> {
+    groups: Arc<ArcSwap<AuthRouter>>,
+    verifying: Arc<Semaphore>,
+    inner: S,
+}
+
+impl<ReqBody, S, ResBody> Service<Request<ReqBody>> for Auth<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone,
+    ResBody: Default,
+{
+    type Error = S::Error;
+    type Future = ResponseFuture<S, ReqBody>;
+    type Response = Response<ResBody>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let Some(rule) = rule_for(&self.groups.load(), &req) else {
+            return ResponseFuture::Inner {
+                inner: self.inner.call(req),
+                authenticated: None,
+            };
+        };
+        let unauthorized = Some((
+            rule.challenges.clone(),
+            Unauthenticated {
+                rule: rule.rule.clone(),
+            },
+        ));
+        let check = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .map_or(Check::Done(None), |authorization| {
+                rule.check(authorization, &self.verifying)
+            });
+        match check {
+            Check::Done(None) => ResponseFuture::Unauthorized(unauthorized),
+            Check::Done(authenticated) => ResponseFuture::Inner {
+                inner: self.inner.call(req),
+                authenticated,
+            },
+            Check::Verifying(verifying) => {
+                // the service which was polled ready is called once the password is checked
+                let clone = self.inner.clone();
+                let inner = std::mem::replace(&mut self.inner, clone);
+                ResponseFuture::Verifying {
+                    verifying,
+                    pending: Some(Box::new((inner, req))),
+                    unauthorized,
+                }
+            }
+        }
+    }
+}
+
+/// The `WWW-Authenticate` values and extension a `401` is sent with.
+type Unauthorized = Option<(Arc<[HeaderValue]>, Unauthenticated)>;
+
+#[pin_project::pin_project(project = ResponseFutureProj)]
+/// Future which is either the wrapped service's response, made private, or a
+/// `401`, possibly once a password has been checked.
+pub enum ResponseFuture<S: Service<Request<ReqBody>>, ReqBody> {
+    Inner {
+        #[pin]
+        inner: S::Future,
+        authenticated: Option<Authenticated>,
+    },
+    /// Checking a password, and then calling the ready service with the
+    /// request. Boxed, since few requests need a password checked.
+    Verifying {
+        verifying: Verifying,
+        pending: Option<Box<(S, Request<ReqBody>)>>,
+        unauthorized: Unauthorized,
+    },
+    Unauthorized(Unauthorized),
+}
+
+impl<S, ReqBody, B> Future for ResponseFuture<S, ReqBody>
+where
+    S: Service<Request<ReqBody>, Response = Response<B>>,
+    B: Default,
+{
+    type Output = Result<Response<B>, S::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        loop {
+            match self.as_mut().project() {
+                ResponseFutureProj::Inner {
+                    inner,
+                    authenticated,
+                } => {
+                    let mut response = std::task::ready!(inner.poll(cx))?;
+                    if let Some(authenticated) = authenticated.take() {
+                        make_private(response.headers_mut());
+                        response.extensions_mut().insert(authenticated);
+                    }
+                    return Poll::Ready(Ok(response));
+                }
+                ResponseFutureProj::Verifying {
+                    verifying,
+                    pending,
+                    unauthorized,
+                } => {
+                    let authenticated = std::task::ready!(verifying.as_mut().poll(cx));
+                    let (mut inner, req) =
+                        *pending.take().expect("auth future polled after completion");
+                    let next = if authenticated.is_some() {
+                        Self::Inner {
+                            inner: inner.call(req),
+                            authenticated,
+                        }
+                    } else {
+                        Self::Unauthorized(unauthorized.take())
+                    };
+                    self.set(next);
+                }
+                ResponseFutureProj::Unauthorized(unauthorized) => {
+                    let (challenges, unauthenticated) = unauthorized
+                        .take()
+                        .expect("auth future polled after completion");
+                    let mut response = Response::new(B::default());
+                    *response.status_mut() = StatusCode::UNAUTHORIZED;
+                    let headers = response.headers_mut();
+                    for challenge in challenges.iter() {
+                        headers.append(header::WWW_AUTHENTICATE, challenge.clone());
+                    }
+                    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+                    response.extensions_mut().insert(unauthenticated);
+                    return Poll::Ready(Ok(response));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use argon2::{PasswordHasher, password_hash::SaltString};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn basic(user: &str, password: &str) -> String {
+        format!("Basic {}", BASE64.encode(format!("{user}:{password}")))
+    }
+
+    #[test]
+    fn parse_all_reports_every_error() {
+        let file = "  Bearer orphan\n/a\n  alice\n  bob:plaintext\n  Bearer \n/empty\n/ok\n  Bearer t\n/a\n  Bearer u";
+        let (groups, errors) = parse_all(file);
+        let paths: Vec<_> = groups.iter().map(|g| (g.path.as_str(), g.line)).collect();
+        assert_eq!(paths, [("/ok", 7), ("/a", 9)]);
+
+        let found: Vec<_> = errors.iter().map(|e| (e.row, e.column)).collect();
+        assert_eq!(found, [(1, 3), (2, 1), (3, 3), (4, 3), (5, 3), (6, 1)]);
+        assert!(matches!(errors[2].kind, AuthParseErrorKind::NoUserColon));
+        assert!(matches!(errors[3].kind, AuthParseErrorKind::UnknownHash));
+        assert!(matches!(errors[4].kind, AuthParseErrorKind::InvalidToken));
+        assert!(matches!(errors[5].kind, AuthParseErrorKind::NoCredentials));
+        // `/a` had no valid credentials, so the later `/a` doesn't conflict with it
+        assert!(parse(file).is_err_and(|e| e.row == 1));
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!(route_path("/st%61ging/"), "/staging/");
+        assert_eq!(route_path("//public/./../staging/a"), "/staging/a");
+        assert_eq!(route_path("/.."), "/");
+        assert_eq!(route_path("/"), "/");
+    }
+
+    #[test]
+    fn makes_responses_private() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=60, s-maxage=600"),
+        );
+        make_private(&mut headers);
+        assert_eq!(headers[header::CACHE_CONTROL], "private, max-age=60");
+
+        let mut headers = HeaderMap::new();
+        make_private(&mut headers);
+        assert_eq!(headers[header::CACHE_CONTROL], "private");
+    }
+
+    #[tokio::test]
+    async fn requires_credentials() {
+        let bcrypt = bcrypt::hash("hunter2", 4).unwrap();
+        let salt = SaltString::from_b64("c2FsdHlzYWx0eQ").unwrap();
+        let argon2 = Argon2::default()
+            .hash_password(b"correct horse", &salt)
+            .unwrap()
+            .to_string();
+        let file = format!("/staging/*\n  alice:{bcrypt}\n  bob:{argon2}\n  Bearer s3cret\n");
+        let layer = AuthLayer::new(parse(&file).unwrap()).unwrap();
+        let svc = layer.layer(tower::service_fn(|_: Request<()>| async move {
+            let mut response = Response::new(());
+            response
+                .headers_mut()
+                .insert(header::CACHE_CONTROL, HeaderValue::from_static("public"));
+            Ok::<_, Infallible>(response)
+        }));
+        let get = |path: &'static str, authorization: Option<String>| {
+            let svc = svc.clone();
+            async move {
+                let mut req = Request::builder().uri(path);
+                if let Some(authorization) = authorization {
+                    req = req.header(header::AUTHORIZATION, authorization);
+                }
+                svc.oneshot(req.body(()).unwrap()).await.unwrap()
+            }
+        };
+
+        let response = get("/", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "public");
+
+        let response = get("/staging", None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let challenges: Vec<_> = response
+            .headers()
+            .get_all(header::WWW_AUTHENTICATE)
+            .iter()
+            .collect();
+        assert_eq!(
+            challenges,
+            [
+                "Basic realm=\"/staging/*\", charset=\"UTF-8\"",
+                "Bearer realm=\"/staging/*\""
+            ]
+        );
+        let response = get("/st%61ging/page", Some(basic("alice", "wrong"))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        // checked against alice's hash, which this password matches, but not let in
+        let response = get("/staging/page", Some(basic("mallory", "hunter2"))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        for _ in 0..2 {
+            let response = get("/staging/page", Some(basic("alice", "hunter2"))).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CACHE_CONTROL], "private");
+            let authenticated = response.extensions().get::<Authenticated>().unwrap();
+            assert_eq!(authenticated.user.as_deref(), Some("alice"));
+        }
+        let response = get("/staging/", Some(basic("bob", "correct horse"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = get("/staging/", Some("bearer s3cret".to_owned())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = get("/staging/", Some("Bearer s3cre".to_owned())).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // served from a protected file under another path
+        let mut req = Request::get("/preview").body(()).unwrap();
+        req.extensions_mut()
+            .insert(ServedPath("/staging/preview.html".to_owned()));
+        let response = svc.clone().oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        assert_eq!(layer.matched("/staging/a").as_deref(), Some("/staging/*"));
+        layer.reload(Vec::new()).unwrap();
+        assert_eq!(get("/staging/", None).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn checks_passwords_off_the_runtime() {
+        let bcrypt = bcrypt::hash("hunter2", 10).unwrap();
+        let file = format!("/*\n  alice:{bcrypt}\n  Bearer s3cret\n");
+        let layer = AuthLayer::new(parse(&file).unwrap()).unwrap();
+        let svc = layer.layer(tower::service_fn(|_: Request<()>| async move {
+            Ok::<_, Infallible>(Response::new(()))
+        }));
+        let request = |authorization: String| {
+            Request::get("/")
+                .header(header::AUTHORIZATION, authorization)
+                .body(())
+                .unwrap()
+        };
+
+        let guess = tokio::spawn(svc.clone().oneshot(request(basic("alice", "guess"))));
+        tokio::task::yield_now().await;
+        // this runtime has one thread, which hashing the guess would have held up
+        let response = svc.oneshot(request("Bearer s3cret".to_owned())).await;
+        assert_eq!(response.unwrap().status(), StatusCode::OK);
+        assert!(!guess.is_finished());
+        let response = guess.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
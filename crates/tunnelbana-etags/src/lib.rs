@@ -29,9 +29,11 @@ use std::{
     task::{Context, Poll},
 };
 
+use arc_swap::ArcSwap;
 use bytes::Bytes;
 use http::{HeaderValue, Request, Response, StatusCode};
 use http_body_util::{BodyExt, combinators::UnsyncBoxBody};
+use percent_encoding::percent_decode_str;
 use tag_map::ResourceTagSet;
 use tower::{Layer, Service};
 
@@ -43,17 +45,32 @@ pub use tag_map::{ETagMap, TagMapBuildError};
 
 #[derive(Clone)]
 /// A [`tower::Layer`] that adds an etag to wrapped services.
+///
+/// Clones of this layer, and every service made from it, share the same tags,
+/// so a call to [`Self::reload`] affects all of them.
 pub struct ETagLayer {
-    tags: Arc<ETagMap>,
+    tags: Arc<ArcSwap<ETagMap>>,
 }
 
 impl ETagLayer {
     #[must_use]
     pub fn new(tags: ETagMap) -> Self {
         Self {
-            tags: Arc::new(tags),
+            tags: Arc::new(ArcSwap::from_pointee(tags)),
         }
     }
+
+    /// Replace the tags used by this layer and all services made from it,
+    /// for example after the served files have changed.
+    pub fn reload(&self, tags: ETagMap) {
+        self.tags.store(Arc::new(tags));
+    }
+
+    /// Whether there are tags for the file at `path`, like `/index.html`.
+    #[must_use]
+    pub fn contains(&self, path: &str) -> bool {
+        self.tags.load().contains_key(path)
+    }
 }
 
 impl<S> Layer<S> for ETagLayer {
@@ -70,10 +87,15 @@ impl<S> Layer<S> for ETagLayer {
 #[derive(Clone)]
 /// An implementation of a tower service which adds etags to a service which it wraps.
 pub struct ETag<S> {
-    tags: Arc<ETagMap>,
+    tags: Arc<ArcSwap<ETagMap>>,
     inner: S,
 }
 
+#[derive(Clone, Copy, Debug)]
+/// Added to the extensions of the `304 Not Modified` responses sent
+/// when `If-None-Match` matches a tag.
+pub struct NotModified;
+
 #[pin_project::pin_project(project = PinResponseOpts)]
 /// A future representing possible states of the request.
 pub enum ResponseFuture<F> {
@@ -150,6 +172,7 @@ fn not_modified<E>(etag: HeaderValue) -> http::Response<UnsyncBoxBody<Bytes, E>>
     ));
     response.headers_mut().insert(http::header::ETAG, etag);
     *response.status_mut() = StatusCode::NOT_MODIFIED;
+    response.extensions_mut().insert(NotModified);
     response
 }
 
@@ -162,6 +185,18 @@ where
     res.map(|inner| inner.map(UnsyncBoxBody::new))
 }
 
+/// The key of the file a request for `path` is served from. Tags are keyed
+/// by file path, so the request path is percent-decoded, and a directory's
+/// path is given its `index.html`.
+fn tag_key(path: &str) -> String {
+    let path = percent_decode_str(path).decode_utf8_lossy();
+    if path.ends_with('/') {
+        format!("{path}index.html")
+    } else {
+        path.into_owned()
+    }
+}
+
 impl<ReqBody, F, FResBody, FResBodyError> Service<Request<ReqBody>> for ETag<F>
 where
     F: Service<Request<ReqBody>, Response = Response<FResBody>, Error = Infallible> + Clone,
@@ -178,15 +213,12 @@ where
     }
 
     fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
-        let path = req.uri().path();
-        let path = if path.ends_with('/') {
-            format!("{path}index.html")
-        } else {
-            path.to_string()
-        };
-        if let Some(tags) = self.tags.get(&path) {
+        if let Some(tags) = self.tags.load().get(&tag_key(req.uri().path())) {
+            // a 304 only stands in for a GET or HEAD; any other method
+            // would change the resource rather than read it
+            let reads = matches!(*req.method(), http::Method::GET | http::Method::HEAD);
             match req.headers().get(http::header::IF_NONE_MATCH) {
-                Some(matched) if tags.contains_tag(matched) => {
+                Some(matched) if reads && tags.contains_tag(matched) => {
                     ResponseFuture::NotModified(matched.clone())
                 }
                 _ => ResponseFuture::ChildRespWithETag(self.inner.call(req), tags.clone()),
@@ -196,3 +228,76 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::Full;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn request(if_none_match: Option<&HeaderValue>) -> Request<()> {
+        let mut req = Request::builder().uri("/file.txt");
+        if let Some(tag) = if_none_match {
+            req = req.header(http::header::IF_NONE_MATCH, tag);
+        }
+        req.body(()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reload_replaces_tags() {
+        let dir = std::env::temp_dir().join(format!("tunnelbana-etags-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file.txt"), "first").unwrap();
+
+        let layer = ETagLayer::new(ETagMap::new(&dir).unwrap());
+        let svc = tower::ServiceBuilder::new()
+            .layer(layer.clone())
+            .service_fn(|_: Request<()>| async move {
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("child"))))
+            });
+
+        let resp = svc.clone().oneshot(request(None)).await.unwrap();
+        let first = resp.headers()[http::header::ETAG].clone();
+        let resp = svc.clone().oneshot(request(Some(&first))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        let mut post = request(Some(&first));
+        *post.method_mut() = http::Method::POST;
+        let resp = svc.clone().oneshot(post).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        std::fs::write(dir.join("file.txt"), "second").unwrap();
+        layer.reload(ETagMap::new(&dir).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let resp = svc.clone().oneshot(request(Some(&first))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_ne!(resp.headers()[http::header::ETAG], first);
+    }
+
+    #[test]
+    fn tag_keys_are_file_paths() {
+        assert_eq!(tag_key("/blog/"), "/blog/index.html");
+        assert_eq!(tag_key("/first%20post.html"), "/first post.html");
+        assert_eq!(tag_key("/about.html"), "/about.html");
+    }
+
+    #[test]
+    fn files_in_memory_match_files_on_disk() {
+        let dir = std::env::temp_dir().join(format!("tunnelbana-etags-mem-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "hello").unwrap();
+        std::fs::write(dir.join("a.txt.gz"), "compressed").unwrap();
+        let on_disk = ETagMap::new(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let files = [("/a.txt", &b"hello"[..]), ("/a.txt.gz", &b"compressed"[..])];
+        let in_memory = ETagMap::from_files(files, false).unwrap();
+        let (disk, memory) = (&on_disk["/a.txt"], &in_memory["/a.txt"]);
+        assert_eq!(disk.raw, memory.raw);
+        assert_eq!(disk.gzip, memory.gzip);
+        assert!(memory.brotli.is_none());
+
+        assert!(ETagMap::from_files([("a.txt", &b""[..])], false).is_err());
+    }
+}
@@ -1,6 +1,5 @@
 use std::{
     collections::{HashMap, HashSet},
-    io::ErrorKind as IoErrorKind,
     ops::Deref,
     path::{Path, PathBuf},
     sync::Arc,
@@ -48,7 +47,8 @@ impl ResourceTagSet {
 /// List of resource tags, matched to their compression permutation.
 /// Compressed tags are optional. If they are not present, it is assumed
 /// that the compression will not be sent- and no etag is returned if a wrong
-/// etag is sent.
+/// etag is sent. Maps built with [`ETagMap::with_derived_tags`] fill every
+/// missing compressed tag with one derived from the raw tag.
 pub struct ResourceTags {
     pub raw: HeaderValue,
     pub gzip: Option<HeaderValue>,
@@ -90,6 +90,32 @@ impl ResourceTags {
         insert_if_some(&mut output, self.deflate);
         output
     }
+
+    fn derive_missing(&mut self) -> Result<(), TagMapBuildError> {
+        let raw = &self.raw;
+        for (tag, encoding) in [
+            (&mut self.gzip, "gzip"),
+            (&mut self.zstd, "zstd"),
+            (&mut self.deflate, "deflate"),
+            (&mut self.brotli, "br"),
+        ] {
+            if tag.is_none() {
+                *tag = Some(derived_tag(raw, encoding)?);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Append `-{encoding}` inside the quotes of `raw`.
+fn derived_tag(raw: &HeaderValue, encoding: &str) -> Result<HeaderValue, TagMapBuildError> {
+    let unquoted = raw.as_bytes().strip_suffix(b"\"").unwrap_or(raw.as_bytes());
+    let mut tag = Vec::with_capacity(unquoted.len() + encoding.len() + 2);
+    tag.extend_from_slice(unquoted);
+    tag.push(b'-');
+    tag.extend_from_slice(encoding.as_bytes());
+    tag.push(b'"');
+    Ok(HeaderValue::from_bytes(&tag)?)
 }
 
 impl ETagMap {
@@ -99,53 +125,116 @@ impl ETagMap {
     /// # Errors
     /// This function can error if mmap fails in blake3, or if paths cannot be generated
     pub fn new<P: AsRef<Path>>(base_dir: P) -> Result<Self, TagMapBuildError> {
+        Self::build(base_dir.as_ref(), false)
+    }
+
+    /// Like [`Self::new`], but every encoding without a precompressed file gets
+    /// a tag derived from the raw file's, like `"<hash>-br"`. Use this when
+    /// responses are compressed on the fly, so each variant still has its own
+    /// stable tag.
+    /// # Errors
+    /// This function can error if mmap fails in blake3, or if paths cannot be generated
+    pub fn with_derived_tags<P: AsRef<Path>>(base_dir: P) -> Result<Self, TagMapBuildError> {
+        Self::build(base_dir.as_ref(), true)
+    }
+
+    /// Like [`Self::new`], or [`Self::with_derived_tags`] with `derive_missing`,
+    /// but each file is first passed to `load` with its key and path. If `load`
+    /// returns the file's contents, they are hashed instead of the file on disk,
+    /// so a cache of the directory can be filled in the same pass that hashes it.
+    /// # Errors
+    /// This function can error if `load` does, if mmap fails in blake3, or if
+    /// paths cannot be generated
+    pub fn with_loader<P, F, T>(
+        base_dir: P,
+        derive_missing: bool,
+        mut load: F,
+    ) -> Result<Self, TagMapBuildError>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&str, &Path) -> Result<Option<T>, std::io::Error>,
+        T: AsRef<[u8]>,
+    {
         let base_dir = base_dir.as_ref();
         let files = get_file_list(base_dir)?;
         trace!(?files, count = files.len(), "Hashing files");
 
-        let mut map = HashMap::new();
+        let mut hashes = HashMap::with_capacity(files.len());
         for path in files {
             let relative_path = path
                 .strip_prefix(base_dir)?
                 .to_str()
                 .ok_or(TagMapBuildError::PathNotStr)?;
             let key = format!("/{relative_path}");
+            let hash = match load(&key, &path)? {
+                Some(data) => bytes_header_hash(data.as_ref())?,
+                None => file_header_hash(&path)?,
+            };
+            hashes.insert(key, hash);
+        }
+        Self::from_hashes(&hashes, derive_missing)
+    }
 
-            let tags = get_resource_tags(&path)?;
+    /// Create a new [`ETagMap`] for files which are already in memory, keyed by
+    /// their path, like `/index.html`. Files hash to the same tags as they would
+    /// on disk. With `derive_missing`, this behaves like [`Self::with_derived_tags`].
+    /// # Errors
+    /// This function can error if a path isn't absolute.
+    pub fn from_files<'a, I>(files: I, derive_missing: bool) -> Result<Self, TagMapBuildError>
+    where
+        I: IntoIterator<Item = (&'a str, &'a [u8])>,
+    {
+        let mut hashes = HashMap::new();
+        for (path, data) in files {
+            if !path.starts_with('/') {
+                return Err(TagMapBuildError::PathNotAbsolute(path.to_owned()));
+            }
+            hashes.insert(path.to_owned(), bytes_header_hash(data)?);
+        }
+        Self::from_hashes(&hashes, derive_missing)
+    }
+
+    fn build(base_dir: &Path, derive_missing: bool) -> Result<Self, TagMapBuildError> {
+        Self::with_loader(base_dir, derive_missing, |_, _| Ok(None::<&[u8]>))
+    }
 
-            map.insert(key, Arc::new(tags.into()));
+    /// Group each file's hash with its `.gz`, `.zst`, `.zz` and `.br` siblings' hashes.
+    fn from_hashes(
+        hashes: &HashMap<String, HeaderValue>,
+        derive_missing: bool,
+    ) -> Result<Self, TagMapBuildError> {
+        let mut map = HashMap::with_capacity(hashes.len());
+        for (path, raw) in hashes {
+            let sibling = |ext: &str| hashes.get(&format!("{path}{ext}")).cloned();
+            let mut tags = ResourceTags {
+                raw: raw.clone(),
+                gzip: sibling(".gz"),
+                zstd: sibling(".zst"),
+                deflate: sibling(".zz"),
+                brotli: sibling(".br"),
+            };
+            if derive_missing {
+                tags.derive_missing()?;
+            }
+            map.insert(path.clone(), Arc::new(tags.into()));
         }
         info!(count = map.len(), "Hashed files");
         Ok(Self { map })
     }
 }
 
-fn get_resource_tags(path: &Path) -> Result<ResourceTags, TagMapBuildError> {
-    Ok(ResourceTags {
-        raw: file_header_hash(path, "")?,
-        gzip: file_header_hash_opt(path, ".gz")?,
-        zstd: file_header_hash_opt(path, ".zst")?,
-        deflate: file_header_hash_opt(path, ".zz")?,
-        brotli: file_header_hash_opt(path, ".br")?,
-    })
+fn file_header_hash(path: &Path) -> Result<HeaderValue, TagMapBuildError> {
+    // This is basically just `b3sum` but rust
+    trace!(?path, "Hashing file");
+    let hash = blake3::Hasher::new().update_mmap_rayon(path)?.finalize();
+    hash_header(&hash)
 }
 
-fn file_header_hash_opt(path: &Path, ext: &str) -> Result<Option<HeaderValue>, TagMapBuildError> {
-    // we try to hash all the supported extensions here- so we don't really know if each file has those
-    match file_header_hash(path, ext) {
-        Err(TagMapBuildError::Io(ie)) if matches!(ie.kind(), IoErrorKind::NotFound) => Ok(None),
-        v => v.map(Some),
-    }
+fn bytes_header_hash(data: &[u8]) -> Result<HeaderValue, TagMapBuildError> {
+    hash_header(&blake3::hash(data))
 }
 
-fn file_header_hash(path: &Path, ext: &str) -> Result<HeaderValue, TagMapBuildError> {
-    // Create a pathbuf and push a new textual extension to it
-    let mut path = path.to_path_buf();
-    path.as_mut_os_string().push(ext);
-    // This is basically just `b3sum` but rust
-    trace!(?path, "Hashing file");
-    let hash = blake3::Hasher::new().update_mmap_rayon(&path)?.finalize();
-
+fn hash_header(hash: &blake3::Hash) -> Result<HeaderValue, TagMapBuildError> {
     let hash = hash.to_hex();
     let value = HeaderValue::from_str(&format!("\"{hash}\""))?;
 
@@ -188,4 +277,6 @@ pub enum TagMapBuildError {
     UnknownFileKind,
     #[error("Path was not a valid UTF-8 string")]
     PathNotStr,
+    #[error("Path {0:?} does not start with a `/`")]
+    PathNotAbsolute(String),
 }
@@ -36,6 +36,7 @@ use std::{
     task::{Context, Poll},
 };
 
+use arc_swap::ArcSwap;
 use bytes::Bytes;
 use http::{
     HeaderName, HeaderValue, Request, Response,
@@ -47,6 +48,34 @@ use tower::{Layer, Service};
 
 type BonusHeaders = Arc<[(HeaderName, HeaderValue)]>;
 
+#[derive(Clone, Debug)]
+/// Added to the extensions of every response which had a header group applied.
+pub struct HeadersApplied {
+    /// The route of the matching group, with a trailing `*` in the `_headers`
+    /// file expanded to `{*all}`.
+    pub rule: Arc<str>,
+}
+
+#[derive(Clone, Debug)]
+/// A header group which matches a path, as found by [`HeadersLayer::matched`].
+pub struct HeaderMatch {
+    /// The route of the group, with a trailing `*` in the `_headers` file
+    /// expanded to `{*all}`, as in `/blog/{slug}/{*all}`.
+    pub rule: Arc<str>,
+    /// The value captured by each parameter in the path.
+    pub captures: Vec<(String, String)>,
+    /// The headers which would be added to the response.
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+#[derive(Debug)]
+struct HeaderRule {
+    rule: Arc<str>,
+    headers: BonusHeaders,
+}
+
+type HeaderRouter = Router<HeaderRule>;
+
 #[macro_use]
 extern crate tracing;
 
@@ -54,17 +83,25 @@ extern crate tracing;
 pub struct HeaderGroup {
     pub path: String,
     pub targets: Vec<(HeaderName, HeaderValue)>,
+    /// The line of the `_headers` file this group's path is on, starting at 1.
+    pub line: usize,
 }
 
 /// Parse a list of [`HeaderGroup`]s from a cloudflare-style _headers string.
 /// # Errors
 /// This function errors if you have an orphaned header definition, if you have an invalid header name or value,
-/// or if your name cannot be a matchit path.
+/// if your name cannot be a matchit path, or if two groups conflict. The first error is returned.
 pub fn parse(header_file: &str) -> Result<Vec<HeaderGroup>, HeaderParseError> {
-    if header_file.is_empty() {
-        return Ok(Vec::new());
-    }
-    let mut headers = Vec::new();
+    let (headers, errors) = parse_all(header_file);
+    errors.into_iter().next().map_or(Ok(headers), Err)
+}
+
+/// Like [`parse`], but carries on past errors, returning every group which
+/// could be parsed along with every error in the file.
+#[must_use]
+pub fn parse_all(header_file: &str) -> (Vec<HeaderGroup>, Vec<HeaderParseError>) {
+    let mut groups = Vec::new();
+    let mut errors = Vec::new();
     let mut current_ctx: Option<HeaderGroup> = None;
     for (idx, line) in header_file.lines().enumerate() {
         if line.is_empty() || line.trim().starts_with('#') {
@@ -73,48 +110,70 @@ pub fn parse(header_file: &str) -> Result<Vec<HeaderGroup>, HeaderParseError> {
         }
         if line.starts_with(['\t', ' ']) {
             let Some(ctx) = current_ctx.as_mut() else {
-                return Err(HeaderParseError::new(HeaderParseErrorKind::NoParseCtx, idx));
+                errors.push(HeaderParseError::new(
+                    HeaderParseErrorKind::NoParseCtx,
+                    idx,
+                    indent(line),
+                ));
+                continue;
             };
-            let (name, value) = line
-                .trim()
-                .split_once(':')
-                .ok_or_else(|| HeaderParseError::new(HeaderParseErrorKind::NoHeaderColon, idx))?;
-            let name = match HeaderName::from_bytes(name.trim().as_bytes()) {
-                Ok(v) => v,
-                Err(e) => {
-                    return Err(HeaderParseError::new(
-                        HeaderParseErrorKind::HeaderNameParse(e),
-                        idx,
-                    ));
-                }
-            };
-            let value = match HeaderValue::from_bytes(value.trim().as_bytes()) {
-                Ok(v) => v,
-                Err(e) => {
-                    return Err(HeaderParseError::new(
-                        HeaderParseErrorKind::HeaderValueParse(e),
-                        idx,
-                    ));
-                }
-            };
-
-            ctx.targets.push((name, value));
+            match parse_header(line, idx) {
+                Ok(header) => ctx.targets.push(header),
+                Err(e) => errors.push(e),
+            }
         } else {
             let mut group = Some(HeaderGroup {
                 path: line.trim().to_string(),
                 targets: Vec::new(),
+                line: idx + 1,
             });
             std::mem::swap(&mut current_ctx, &mut group);
             if let Some(group) = group {
-                group_add(&mut headers, group);
+                group_add(&mut groups, group);
             }
         }
     }
     if let Some(group) = current_ctx {
-        group_add(&mut headers, group);
+        group_add(&mut groups, group);
+    }
+
+    // catches groups which conflict with an earlier one, like `HeadersLayer::new` would
+    let mut router = Router::new();
+    let mut headers = Vec::with_capacity(groups.len());
+    for group in groups {
+        match router.insert(group.path.clone(), ()) {
+            Ok(()) => headers.push(group),
+            Err(e) => errors.push(HeaderParseError::new(
+                HeaderParseErrorKind::Conflict(e),
+                group.line - 1,
+                1,
+            )),
+        }
     }
+    errors.sort_by_key(|e| (e.row, e.column));
     info!(?headers, "Got headers");
-    Ok(headers)
+    (headers, errors)
+}
+
+/// Parse an indented `Name: value` line.
+fn parse_header(line: &str, idx: usize) -> Result<(HeaderName, HeaderValue), HeaderParseError> {
+    let name_column = indent(line);
+    let (name, value) = line.trim().split_once(':').ok_or_else(|| {
+        HeaderParseError::new(HeaderParseErrorKind::NoHeaderColon, idx, name_column)
+    })?;
+    let value_column = name_column + name.chars().count() + indent(value);
+    let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|e| {
+        HeaderParseError::new(HeaderParseErrorKind::HeaderNameParse(e), idx, name_column)
+    })?;
+    let value = HeaderValue::from_bytes(value.trim().as_bytes()).map_err(|e| {
+        HeaderParseError::new(HeaderParseErrorKind::HeaderValueParse(e), idx, value_column)
+    })?;
+    Ok((name, value))
+}
+
+/// The column of the first character of `text` which isn't whitespace.
+fn indent(text: &str) -> usize {
+    text.chars().take_while(|c| c.is_whitespace()).count() + 1
 }
 
 fn group_add(headers: &mut Vec<HeaderGroup>, group: HeaderGroup) {
@@ -126,10 +185,12 @@ fn group_add(headers: &mut Vec<HeaderGroup>, group: HeaderGroup) {
         headers.push(HeaderGroup {
             path: base_path.to_owned(),
             targets: group.targets.clone(),
+            line: group.line,
         });
         headers.push(HeaderGroup {
             path: format!("{base_path}{{*all}}"),
             targets: group.targets.clone(),
+            line: group.line,
         });
     } else {
         headers.push(group);
@@ -137,17 +198,23 @@ fn group_add(headers: &mut Vec<HeaderGroup>, group: HeaderGroup) {
 }
 
 #[derive(Debug, thiserror::Error)]
-#[error("at line {row}: {kind}")]
+#[error("at line {row}, column {column}: {kind}")]
 /// Describes the location and type of a header parsing problem.
 pub struct HeaderParseError {
     pub row: usize,
+    /// The column the problem starts at, starting at 1.
+    pub column: usize,
     #[source]
     pub kind: HeaderParseErrorKind,
 }
 
 impl HeaderParseError {
-    const fn new(kind: HeaderParseErrorKind, idx: usize) -> Self {
-        Self { row: idx + 1, kind }
+    const fn new(kind: HeaderParseErrorKind, idx: usize, column: usize) -> Self {
+        Self {
+            row: idx + 1,
+            column,
+            kind,
+        }
     }
 }
 
@@ -163,12 +230,17 @@ pub enum HeaderParseErrorKind {
     NoParseCtx,
     #[error("You must put a colon at the end of the header name")]
     NoHeaderColon,
+    #[error("Conflicts with an earlier path: {0}")]
+    Conflict(matchit::InsertError),
 }
 
 #[derive(Clone)]
 /// a [`tower::Layer`] to add to a [`tower::ServiceBuilder`] to add headers.
+///
+/// Clones of this layer, and every service made from it, share the same headers,
+/// so a call to [`Self::reload`] affects all of them.
 pub struct HeadersLayer {
-    headers: Arc<matchit::Router<BonusHeaders>>,
+    headers: Arc<ArcSwap<HeaderRouter>>,
 }
 
 impl HeadersLayer {
@@ -178,17 +250,52 @@ impl HeadersLayer {
     /// If two [`HeaderGroup`]s are the same, or would illgally overlap
     /// an error can be returned
     pub fn new(header_list: Vec<HeaderGroup>) -> Result<Self, InsertError> {
-        let mut headers = Router::new();
-        for header in header_list {
-            headers.insert(header.path, header.targets.into())?;
-        }
-
-        info!(?headers, "Built auto header map");
-
+        let headers = build_router(header_list)?;
         Ok(Self {
-            headers: Arc::new(headers),
+            headers: Arc::new(ArcSwap::from_pointee(headers)),
+        })
+    }
+
+    /// The group whose headers would be added to `path`, if any.
+    #[must_use]
+    pub fn matched(&self, path: &str) -> Option<HeaderMatch> {
+        let headers = self.headers.load();
+        let matched = headers.at(path).ok()?;
+        Some(HeaderMatch {
+            rule: matched.value.rule.clone(),
+            captures: matched
+                .params
+                .iter()
+                .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                .collect(),
+            headers: matched.value.headers.to_vec(),
         })
     }
+
+    /// Replace the headers served by this layer and all services made from it.
+    /// # Errors
+    /// If the new header groups can't be routed. The old headers keep being
+    /// served in that case.
+    pub fn reload(&self, header_list: Vec<HeaderGroup>) -> Result<(), InsertError> {
+        let headers = build_router(header_list)?;
+        self.headers.store(Arc::new(headers));
+        Ok(())
+    }
+}
+
+fn build_router(header_list: Vec<HeaderGroup>) -> Result<HeaderRouter, InsertError> {
+    let mut headers = Router::new();
+    for header in header_list {
+        let rule = HeaderRule {
+            rule: header.path.as_str().into(),
+            headers: header.targets.into(),
+        };
+        headers.insert(header.path, rule)?;
+    }
+
+    info!(?headers, "Built auto header map");
+
+    Ok(headers)
 }
 
 impl<S> Layer<S> for HeadersLayer {
@@ -205,7 +312,7 @@ impl<S> Layer<S> for HeadersLayer {
 #[derive(Clone)]
 /// a [`tower::Service`] which adds headers to a wrapped S.
 pub struct Headers<S> {
-    headers: Arc<matchit::Router<BonusHeaders>>,
+    headers: Arc<ArcSwap<HeaderRouter>>,
     inner: S,
 }
 
@@ -214,7 +321,7 @@ pub struct Headers<S> {
 pub struct ResponseFuture<F> {
     #[pin]
     src: F,
-    additional_headers: Option<BonusHeaders>,
+    additional_headers: Option<(BonusHeaders, HeadersApplied)>,
 }
 
 impl<F, B, BE> std::future::Future for ResponseFuture<F>
@@ -236,14 +343,15 @@ where
 #[allow(clippy::unnecessary_wraps)]
 fn add_headers<B>(
     res: Result<Response<B>, Infallible>,
-    bonus_headers: Option<BonusHeaders>,
+    bonus_headers: Option<(BonusHeaders, HeadersApplied)>,
 ) -> Result<Response<B>, Infallible> {
     let Ok(mut inner) = res;
-    let resp_headers = inner.headers_mut();
-    if let Some(bonus_headers) = bonus_headers {
+    if let Some((bonus_headers, applied)) = bonus_headers {
+        let resp_headers = inner.headers_mut();
         for (name, value) in bonus_headers.iter() {
             resp_headers.insert(name.clone(), value.clone());
         }
+        inner.extensions_mut().insert(applied);
     }
     Ok(inner)
 }
@@ -265,10 +373,91 @@ where
 
     fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
         let path = req.uri().path();
-        let additional_headers = self.headers.at(path).ok().map(|v| v.value.clone());
+        let additional_headers = self.headers.load().at(path).ok().map(|v| {
+            let applied = HeadersApplied {
+                rule: v.value.rule.clone(),
+            };
+            (v.value.headers.clone(), applied)
+        });
         ResponseFuture {
             src: self.inner.call(req),
             additional_headers,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{Empty, Full};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn request(url: &str) -> Request<Empty<Bytes>> {
+        Request::builder().uri(url).body(Empty::new()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reload_replaces_headers() {
+        let layer = HeadersLayer::new(parse("/page\n  X-Version: first").unwrap()).unwrap();
+        let svc = tower::ServiceBuilder::new()
+            .layer(layer.clone())
+            .service_fn(|_: Request<Empty<Bytes>>| async move {
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("child"))))
+            });
+
+        let resp = svc.clone().oneshot(request("/page")).await.unwrap();
+        assert_eq!(resp.headers()["x-version"], "first");
+        assert_eq!(
+            &*resp.extensions().get::<HeadersApplied>().unwrap().rule,
+            "/page"
+        );
+
+        layer
+            .reload(parse("/page\n  X-Version: second").unwrap())
+            .unwrap();
+        let resp = svc.clone().oneshot(request("/page")).await.unwrap();
+        assert_eq!(resp.headers()["x-version"], "second");
+
+        // `parse` rejects conflicting groups, so build the list from two files
+        let mut duplicate = parse("/other\n  X-A: a").unwrap();
+        duplicate.extend(parse("/other\n  X-B: b").unwrap());
+        assert!(layer.reload(duplicate).is_err());
+        let resp = svc.clone().oneshot(request("/page")).await.unwrap();
+        assert_eq!(resp.headers()["x-version"], "second");
+        assert_eq!(
+            layer.matched("/page").map(|m| m.rule).as_deref(),
+            Some("/page")
+        );
+        assert!(layer.matched("/other").is_none());
+    }
+
+    #[test]
+    fn matched_reports_captures() {
+        let layer = HeadersLayer::new(parse("/blog/{slug}/*\n  X-A: a").unwrap()).unwrap();
+        let matched = layer.matched("/blog/post/img.png").unwrap();
+        assert_eq!(&*matched.rule, "/blog/{slug}/{*all}");
+        assert_eq!(
+            matched.captures,
+            [
+                ("slug".to_owned(), "post".to_owned()),
+                ("all".to_owned(), "img.png".to_owned())
+            ]
+        );
+        assert_eq!(matched.headers[0].1, "a");
+    }
+
+    #[test]
+    fn parse_all_reports_every_error() {
+        let file =
+            "  X-Orphan: a\n/a\n  X-A:  bad\u{7f}\n  no colon\n/ok\n  X-Ok: fine\n/a\n  X-B: b";
+        let (headers, errors) = parse_all(file);
+        let paths: Vec<_> = headers.iter().map(|h| (h.path.as_str(), h.line)).collect();
+        assert_eq!(paths, [("/a", 2), ("/ok", 5)]);
+
+        let found: Vec<_> = errors.iter().map(|e| (e.row, e.column)).collect();
+        assert_eq!(found, [(1, 3), (3, 9), (4, 3), (7, 1)]);
+        assert!(matches!(errors[3].kind, HeaderParseErrorKind::Conflict(_)));
+        assert!(parse(file).is_err_and(|e| e.row == 1));
+    }
+}
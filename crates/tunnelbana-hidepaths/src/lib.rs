@@ -136,6 +136,14 @@ impl HidePathsLayer<DefaultNotFoundService> {
     }
 }
 
+impl<N> HidePathsLayer<N> {
+    /// Whether a request for `path` is sent to the not found service.
+    #[must_use]
+    pub fn is_hidden(&self, path: &str) -> bool {
+        self.hidden.at(path).is_ok()
+    }
+}
+
 impl<S, N> Layer<S> for HidePathsLayer<N>
 where
     N: Clone,
@@ -160,6 +168,10 @@ pub struct HidePath<S, N> {
     inner: S,
 }
 
+#[derive(Clone, Copy, Debug)]
+/// Added to the extensions of every response for a hidden path.
+pub struct Hidden;
+
 #[pin_project::pin_project(project = PinResponseSource)]
 /// Future which always delegates the whole response to either the default service, or
 /// a not-found fallback, and returns the service response unmodified.
@@ -187,7 +199,8 @@ where
             }),
             PinResponseSource::NotFound(s) => s.poll(cx).map(|v| {
                 v.map(|resp| {
-                    let (parts, body) = resp.into_parts();
+                    let (mut parts, body) = resp.into_parts();
+                    parts.extensions.insert(Hidden);
                     Response::from_parts(parts, Either::Right(body))
                 })
             }),
@@ -275,6 +288,8 @@ mod tests {
             .hide("/example.html")
             .build()
             .unwrap();
+        assert!(layer.is_hidden("/example.html"));
+        assert!(!layer.is_hidden("/index.html"));
         let svc = tower::ServiceBuilder::new().layer(layer).service_fn(
             |_: Request<Empty<Bytes>>| async move {
                 Ok::<_, Infallible>(Response::new(http_body_util::Full::new(Bytes::from(body))))
@@ -282,6 +297,7 @@ mod tests {
         );
         let not_found = svc.clone().oneshot(request("/example.html")).await.unwrap();
         assert_eq!(not_found.status(), StatusCode::NOT_FOUND);
+        assert!(not_found.extensions().get::<Hidden>().is_some());
         assert!(
             not_found
                 .body()
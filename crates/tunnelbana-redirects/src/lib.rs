@@ -33,6 +33,7 @@ use std::{
     task::{Context, Poll},
 };
 
+use arc_swap::ArcSwap;
 use bytes::Bytes;
 use http::{HeaderValue, Request, Response, StatusCode, header};
 use http_body_util::{BodyExt, combinators::UnsyncBoxBody};
@@ -50,70 +51,121 @@ pub struct Redirect {
     pub path: String,
     pub target: Interpolation,
     pub code: StatusCode,
+    /// The line of the `_redirects` file this rule is on, starting at 1.
+    pub line: usize,
 }
 
 /// Parse a list of [`Redirect`]s from a cloudflare-style _redirects string.
 /// # Errors
 /// This function errors if your status code is malformed, your target cannot be a header value,
-/// or if your name cannot be a matchit path.
+/// if your name cannot be a matchit path, or if two rules conflict. The first error is returned.
 pub fn parse(redirect_file: &str) -> Result<Vec<Redirect>, RedirectParseError> {
-    if redirect_file.is_empty() {
-        return Ok(Vec::new());
-    }
+    let (redirects, errors) = parse_all(redirect_file);
+    errors.into_iter().next().map_or(Ok(redirects), Err)
+}
+
+/// Like [`parse`], but carries on past errors, returning every rule which
+/// could be parsed along with every error in the file.
+#[must_use]
+pub fn parse_all(redirect_file: &str) -> (Vec<Redirect>, Vec<RedirectParseError>) {
     let mut redirects = Vec::new();
+    let mut errors = Vec::new();
+    // catches rules which conflict with an earlier one, like `RedirectsLayer::new` would
+    let mut router = Router::new();
     for (idx, line) in redirect_file.lines().enumerate() {
         if line.is_empty() || line.starts_with('#') {
             // handle comments
             continue;
         }
-
-        let items = line.split(' ').collect::<Vec<&str>>();
-        info!(line = idx + 1, ?items, "Items for line");
-        if !(2..=3).contains(&items.len()) {
-            return Err(RedirectParseError::new(
-                RedirectParseErrorKind::WrongOptCount(items.len()),
+        let redirect = match parse_line(line, idx) {
+            Ok(redirect) => redirect,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        if let Err(e) = router.insert(redirect.path.clone(), ()) {
+            errors.push(RedirectParseError::new(
+                RedirectParseErrorKind::Conflict(e),
                 idx,
+                1,
             ));
+            continue;
         }
+        redirects.push(redirect);
+    }
+    (redirects, errors)
+}
 
-        let path = items[0].to_string();
-        let target = Interpolation::new(items[1])
-            .map_err(|e| RedirectParseError::new(RedirectParseErrorKind::Interpolation(e), idx))?;
+fn parse_line(line: &str, idx: usize) -> Result<Redirect, RedirectParseError> {
+    // each item, with the column it starts at
+    let mut column = 1;
+    let items: Vec<(usize, &str)> = line
+        .split(' ')
+        .map(|item| {
+            let start = column;
+            column += item.chars().count() + 1;
+            (start, item)
+        })
+        .collect();
+    info!(line = idx + 1, ?items, "Items for line");
+    if !(2..=3).contains(&items.len()) {
+        return Err(RedirectParseError::new(
+            RedirectParseErrorKind::WrongOptCount(items.len()),
+            idx,
+            1,
+        ));
+    }
 
-        test_interpolation(&path, &target, idx)?;
+    let (path_column, path) = items[0];
+    let (target_column, target) = items[1];
+    let target = Interpolation::new(target).map_err(|e| {
+        RedirectParseError::new(RedirectParseErrorKind::Interpolation(e), idx, target_column)
+    })?;
 
-        let code: StatusCode = if let Some(code_str) = items.get(2) {
-            let Ok(code) = code_str.parse() else {
-                return Err(RedirectParseError::new(
-                    RedirectParseErrorKind::StatusCode((*code_str).to_string()),
-                    idx,
-                ));
-            };
-            code
-        } else {
-            StatusCode::TEMPORARY_REDIRECT
+    test_interpolation(path, &target, idx, (path_column, target_column))?;
+
+    let code: StatusCode = if let Some(&(code_column, code_str)) = items.get(2) {
+        let Ok(code) = code_str.parse() else {
+            return Err(RedirectParseError::new(
+                RedirectParseErrorKind::StatusCode(code_str.to_string()),
+                idx,
+                code_column,
+            ));
         };
-        redirects.push(Redirect { path, target, code });
-    }
-    Ok(redirects)
+        code
+    } else {
+        StatusCode::TEMPORARY_REDIRECT
+    };
+    Ok(Redirect {
+        path: path.to_string(),
+        target,
+        code,
+        line: idx + 1,
+    })
 }
 
 fn test_interpolation(
     path: &str,
     target: &Interpolation,
     idx: usize,
+    (path_column, target_column): (usize, usize),
 ) -> Result<(), RedirectParseError> {
     // Show a valid matchit route
     let mut router = matchit::Router::new();
-    router
-        .insert(path, ())
-        .map_err(|e| RedirectParseError::new(RedirectParseErrorKind::Matchit(e), idx))?;
+    router.insert(path, ()).map_err(|e| {
+        RedirectParseError::new(RedirectParseErrorKind::Matchit(e), idx, path_column)
+    })?;
 
     // params returns (key, value)
     let params: HashMap<Cow<str>, Cow<str>> = router
         .at(path)
         .map_err(|_| {
-            RedirectParseError::new(RedirectParseErrorKind::NonSelfMatchingTriggerPath, idx)
+            RedirectParseError::new(
+                RedirectParseErrorKind::NonSelfMatchingTriggerPath,
+                idx,
+                path_column,
+            )
         })?
         .params
         .iter()
@@ -126,12 +178,18 @@ fn test_interpolation(
         RedirectParseError::new(
             RedirectParseErrorKind::InterpKeys(e.into_iter().map(ToOwned::to_owned).collect()),
             idx,
+            target_column,
         )
     })?;
 
     // Prove that the rendered value is a valid header
-    HeaderValue::from_bytes(render.as_bytes())
-        .map_err(|_| RedirectParseError::new(RedirectParseErrorKind::HeaderValue(render), idx))?;
+    HeaderValue::from_bytes(render.as_bytes()).map_err(|_| {
+        RedirectParseError::new(
+            RedirectParseErrorKind::HeaderValue(render),
+            idx,
+            target_column,
+        )
+    })?;
 
     Ok(())
 }
@@ -142,17 +200,23 @@ const fn cowify<'a>(v: (&'a str, &'a str)) -> (Cow<'a, str>, Cow<'a, str>) {
 }
 
 #[derive(Debug, thiserror::Error)]
-#[error("at row {row}: {kind}")]
-/// Error struct for unparsable redirects. Includes line number and type of error.
+#[error("at row {row}, column {column}: {kind}")]
+/// Error struct for unparsable redirects. Includes line and column numbers, and type of error.
 pub struct RedirectParseError {
     pub row: usize,
+    /// The column of the item which couldn't be parsed, starting at 1.
+    pub column: usize,
     #[source]
     pub kind: RedirectParseErrorKind,
 }
 
 impl RedirectParseError {
-    const fn new(kind: RedirectParseErrorKind, idx: usize) -> Self {
-        Self { row: idx + 1, kind }
+    const fn new(kind: RedirectParseErrorKind, idx: usize, column: usize) -> Self {
+        Self {
+            row: idx + 1,
+            column,
+            kind,
+        }
     }
 }
 
@@ -173,12 +237,47 @@ pub enum RedirectParseErrorKind {
     Matchit(matchit::InsertError),
     #[error("This path doesn't match itself, this is a bug")]
     NonSelfMatchingTriggerPath,
+    #[error("Conflicts with an earlier rule: {0}")]
+    Conflict(matchit::InsertError),
+}
+
+#[derive(Clone, Debug)]
+/// Added to the extensions of every response produced by a redirect rule, including
+/// the 500 sent when the rendered target isn't a valid `Location` header.
+pub struct Redirected {
+    /// The trigger path of the rule, as written in the `_redirects` file.
+    pub rule: Arc<str>,
+}
+
+#[derive(Clone, Debug)]
+/// A rule which matches a path, as found by [`RedirectsLayer::matched`].
+pub struct RedirectMatch {
+    /// The trigger path of the rule, as written in the `_redirects` file.
+    pub rule: Arc<str>,
+    /// The value captured by each parameter in the trigger path.
+    pub captures: Vec<(String, String)>,
+    /// The rendered target, or `None` if it isn't a valid `Location`, in
+    /// which case a 500 is sent instead.
+    pub location: Option<HeaderValue>,
+    pub code: StatusCode,
+}
+
+#[derive(Debug)]
+struct RedirectTarget {
+    target: Interpolation,
+    code: StatusCode,
+    rule: Arc<str>,
 }
 
+type RedirectRouter = matchit::Router<RedirectTarget>;
+
 #[derive(Clone)]
 /// a [`tower::Layer`] to add to a [`tower::ServiceBuilder`] to add redirects.
+///
+/// Clones of this layer, and every service made from it, share the same redirects,
+/// so a call to [`Self::reload`] affects all of them.
 pub struct RedirectsLayer {
-    redirects: Arc<matchit::Router<(Interpolation, StatusCode)>>,
+    redirects: Arc<ArcSwap<RedirectRouter>>,
 }
 
 impl RedirectsLayer {
@@ -186,17 +285,60 @@ impl RedirectsLayer {
     /// # Errors
     /// This function can error if you have two redirects for the same path.
     pub fn new(redirect_list: Vec<Redirect>) -> Result<Self, InsertError> {
-        let mut redirects = Router::new();
-        for redirect in redirect_list {
-            redirects.insert(redirect.path, (redirect.target, redirect.code))?;
-        }
-
-        info!(?redirects, "Built redirect list");
-
+        let redirects = build_router(redirect_list)?;
         Ok(Self {
-            redirects: Arc::new(redirects),
+            redirects: Arc::new(ArcSwap::from_pointee(redirects)),
+        })
+    }
+
+    /// The rule which would redirect `path`, if any.
+    #[must_use]
+    pub fn matched(&self, path: &str) -> Option<RedirectMatch> {
+        let redirects = self.redirects.load();
+        let matched = redirects.at(path).ok()?;
+        Some(RedirectMatch {
+            rule: matched.value.rule.clone(),
+            captures: matched
+                .params
+                .iter()
+                .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                .collect(),
+            location: location(matched.value, &matched.params),
+            code: matched.value.code,
         })
     }
+
+    /// Replace the redirects served by this layer and all services made from it.
+    /// # Errors
+    /// This function can error if you have two redirects for the same path.
+    /// The old redirects keep being served in that case.
+    pub fn reload(&self, redirect_list: Vec<Redirect>) -> Result<(), InsertError> {
+        let redirects = build_router(redirect_list)?;
+        self.redirects.store(Arc::new(redirects));
+        Ok(())
+    }
+}
+
+fn build_router(redirect_list: Vec<Redirect>) -> Result<RedirectRouter, InsertError> {
+    let mut redirects = Router::new();
+    for redirect in redirect_list {
+        let target = RedirectTarget {
+            target: redirect.target,
+            code: redirect.code,
+            rule: redirect.path.as_str().into(),
+        };
+        redirects.insert(redirect.path, target)?;
+    }
+
+    info!(?redirects, "Built redirect list");
+
+    Ok(redirects)
+}
+
+/// Render `target` with the captured `params`, if the result is a valid `Location`.
+fn location(target: &RedirectTarget, params: &matchit::Params) -> Option<HeaderValue> {
+    let args: HashMap<Cow<str>, Cow<str>> = params.iter().map(cowify).collect();
+    HeaderValue::from_str(&target.target.render(&args)).ok()
 }
 
 impl<S> Layer<S> for RedirectsLayer {
@@ -213,7 +355,7 @@ impl<S> Layer<S> for RedirectsLayer {
 #[derive(Clone)]
 /// a [`tower::Service`] to add redirects to a wrapped service.
 pub struct Redirects<S> {
-    redirects: Arc<matchit::Router<(Interpolation, StatusCode)>>,
+    redirects: Arc<ArcSwap<RedirectRouter>>,
     inner: S,
 }
 
@@ -222,8 +364,8 @@ pub struct Redirects<S> {
 /// an error if a value in the path capture is not a valid header value.
 pub enum ResponseFuture<F> {
     Child(#[pin] F),
-    Redirect(HeaderValue, StatusCode),
-    InvalidHeaderValue,
+    Redirect(HeaderValue, StatusCode, Redirected),
+    InvalidHeaderValue(Redirected),
 }
 
 impl<F, B, BE> std::future::Future for ResponseFuture<F>
@@ -235,11 +377,13 @@ where
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         match self.project() {
-            PinResponseSource::Redirect(header_value, status) => {
-                Poll::Ready(Ok(redirect_respond(header_value, *status)))
-            }
+            PinResponseSource::Redirect(header_value, status, redirected) => Poll::Ready(Ok(
+                redirect_respond(header_value, *status, redirected.clone()),
+            )),
             PinResponseSource::Child(f) => f.poll(cx).map(unsync_box_body_ify),
-            PinResponseSource::InvalidHeaderValue => Poll::Ready(Ok(invalid_header_respond())),
+            PinResponseSource::InvalidHeaderValue(redirected) => {
+                Poll::Ready(Ok(invalid_header_respond(redirected.clone())))
+            }
         }
     }
 }
@@ -256,6 +400,7 @@ where
 fn redirect_respond<E>(
     value: &HeaderValue,
     code: StatusCode,
+    redirected: Redirected,
 ) -> http::Response<UnsyncBoxBody<Bytes, E>> {
     let mut response = Response::new(UnsyncBoxBody::new(
         http_body_util::Empty::new().map_err(|never| match never {}),
@@ -264,14 +409,16 @@ fn redirect_respond<E>(
         .headers_mut()
         .insert(header::LOCATION, value.clone());
     *response.status_mut() = code;
+    response.extensions_mut().insert(redirected);
     response
 }
 
-fn invalid_header_respond<E>() -> http::Response<UnsyncBoxBody<Bytes, E>> {
+fn invalid_header_respond<E>(redirected: Redirected) -> http::Response<UnsyncBoxBody<Bytes, E>> {
     let mut response = Response::new(UnsyncBoxBody::new(
         http_body_util::Empty::new().map_err(|never| match never {}),
     ));
     *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    response.extensions_mut().insert(redirected);
     response
 }
 
@@ -292,16 +439,90 @@ where
 
     fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
         let path = req.uri().path();
-        if let Ok(location) = self.redirects.at(path) {
-            let args: HashMap<Cow<str>, Cow<str>> = location.params.iter().map(cowify).collect();
-            let src = location.value.0.render(&args);
-            if let Ok(value) = HeaderValue::from_str(&src) {
-                ResponseFuture::Redirect(value, location.value.1)
+        let redirects = self.redirects.load();
+        if let Ok(matched) = redirects.at(path) {
+            let redirected = Redirected {
+                rule: matched.value.rule.clone(),
+            };
+            if let Some(value) = location(matched.value, &matched.params) {
+                ResponseFuture::Redirect(value, matched.value.code, redirected)
             } else {
-                ResponseFuture::InvalidHeaderValue
+                ResponseFuture::InvalidHeaderValue(redirected)
             }
         } else {
             ResponseFuture::Child(self.inner.call(req))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{Empty, Full};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn request(url: &str) -> Request<Empty<Bytes>> {
+        Request::builder().uri(url).body(Empty::new()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reload_replaces_redirects() {
+        let layer = RedirectsLayer::new(parse("/old /first 302").unwrap()).unwrap();
+        let svc = tower::ServiceBuilder::new()
+            .layer(layer.clone())
+            .service_fn(|_: Request<Empty<Bytes>>| async move {
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("child"))))
+            });
+
+        let resp = svc.clone().oneshot(request("/old")).await.unwrap();
+        assert_eq!(resp.headers()[header::LOCATION], "/first");
+        assert_eq!(
+            &*resp.extensions().get::<Redirected>().unwrap().rule,
+            "/old"
+        );
+
+        layer.reload(parse("/old /second 301").unwrap()).unwrap();
+        let resp = svc.clone().oneshot(request("/old")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(resp.headers()[header::LOCATION], "/second");
+
+        // `parse` rejects conflicting rules, so build the list from two files
+        let mut duplicate = parse("/new /a").unwrap();
+        duplicate.extend(parse("/new /b").unwrap());
+        assert!(layer.reload(duplicate).is_err());
+        let resp = svc.clone().oneshot(request("/old")).await.unwrap();
+        assert_eq!(resp.headers()[header::LOCATION], "/second");
+        assert_eq!(
+            layer.matched("/old").map(|m| m.rule).as_deref(),
+            Some("/old")
+        );
+        assert!(layer.matched("/new").is_none());
+    }
+
+    #[test]
+    fn matched_renders_location() {
+        let layer = RedirectsLayer::new(parse("/blog/{slug} /posts/{slug} 301").unwrap()).unwrap();
+        let matched = layer.matched("/blog/hello").unwrap();
+        assert_eq!(&*matched.rule, "/blog/{slug}");
+        assert_eq!(matched.captures, [("slug".to_owned(), "hello".to_owned())]);
+        assert_eq!(matched.location.unwrap(), "/posts/hello");
+        assert_eq!(matched.code, StatusCode::MOVED_PERMANENTLY);
+    }
+
+    #[test]
+    fn parse_all_reports_every_error() {
+        let file = "/a /b abc\n/ok /fine\n/c\n/ok /again\n/d /{missing}";
+        let (redirects, errors) = parse_all(file);
+        assert_eq!(redirects.len(), 1);
+        assert_eq!(redirects[0].line, 2);
+
+        let found: Vec<_> = errors.iter().map(|e| (e.row, e.column)).collect();
+        assert_eq!(found, [(1, 7), (3, 1), (4, 1), (5, 4)]);
+        assert!(matches!(
+            errors[2].kind,
+            RedirectParseErrorKind::Conflict(_)
+        ));
+        assert!(parse(file).is_err_and(|e| e.row == 1));
+    }
+}
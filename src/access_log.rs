@@ -0,0 +1,444 @@
+//! One log line per request, in Combined Log Format or JSON.
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Error as IoError, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        Arc,
+        mpsc::{Receiver, SyncSender, TrySendError},
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use http::{HeaderValue, Method, Request, Response, StatusCode, Version, header};
+use serde::Deserialize;
+use time::{
+    OffsetDateTime,
+    format_description::{BorrowedFormatItem, well_known::Rfc3339},
+    macros::format_description,
+};
+use tower::{Layer, Service};
+
+use crate::{
+    config::AccessLogConfig,
+    listener::PeerAddr,
+    observe::{BodyObserver, ObservedBody, Source},
+    proxy::ClientAddr,
+};
+
+const CLF_TIME_FORMAT: &[BorrowedFormatItem<'static>] = format_description!(
+    "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]"
+);
+
+/// Lines waiting for the writer thread. Past this, lines are dropped rather
+/// than making requests wait for the disk.
+const QUEUE_LEN: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Apache/nginx Combined Log Format, followed by the duration in seconds
+    /// and the source of the response
+    #[default]
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "combined" => Ok(Self::Combined),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "unknown log format `{s}`, expected `combined` or `json`"
+            )),
+        }
+    }
+}
+
+enum Sink {
+    Stdout,
+    File(BufWriter<File>),
+}
+
+impl Sink {
+    fn open_file(path: &Path) -> Result<Self, IoError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::File(BufWriter::new(file)))
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), IoError> {
+        match self {
+            Self::Stdout => std::io::stdout().lock().write_all(line.as_bytes()),
+            Self::File(file) => file.write_all(line.as_bytes()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        match self {
+            Self::Stdout => std::io::stdout().lock().flush(),
+            Self::File(file) => file.flush(),
+        }
+    }
+}
+
+enum Command {
+    Line(String),
+    Reopen(Sink),
+}
+
+struct Writer {
+    format: LogFormat,
+    path: Option<PathBuf>,
+    queue: SyncSender<Command>,
+}
+
+impl Writer {
+    /// Start the thread which owns `sink`, so request threads never block on it.
+    fn spawn(format: LogFormat, path: Option<PathBuf>, sink: Sink) -> Result<Self, IoError> {
+        let (queue, commands) = std::sync::mpsc::sync_channel(QUEUE_LEN);
+        std::thread::Builder::new()
+            .name("tunnelbana-access-log".to_owned())
+            .spawn(move || write_lines(sink, &commands))?;
+        Ok(Self {
+            format,
+            path,
+            queue,
+        })
+    }
+
+    fn write(&self, entry: &Entry, bytes: u64) {
+        let mut line = match self.format {
+            LogFormat::Combined => entry.combined(bytes),
+            LogFormat::Json => entry.json(bytes),
+        };
+        line.push('\n');
+        match self.queue.try_send(Command::Line(line)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("access log is falling behind, dropping a line"),
+            Err(TrySendError::Disconnected(_)) => warn!("access log writer has stopped"),
+        }
+    }
+}
+
+/// Write lines as they arrive, flushing whenever the queue is empty.
+fn write_lines(mut sink: Sink, commands: &Receiver<Command>) {
+    while let Ok(command) = commands.recv() {
+        let mut next = Some(command);
+        while let Some(command) = next {
+            match command {
+                Command::Line(line) => {
+                    if let Err(e) = sink.write_line(&line) {
+                        warn!("failed to write access log: {}", e);
+                    }
+                }
+                Command::Reopen(new_sink) => {
+                    if let Err(e) = sink.flush() {
+                        warn!("failed to flush access log: {}", e);
+                    }
+                    sink = new_sink;
+                }
+            }
+            next = commands.try_recv().ok();
+        }
+        if let Err(e) = sink.flush() {
+            warn!("failed to flush access log: {}", e);
+        }
+    }
+}
+
+#[derive(Clone)]
+/// A [`tower::Layer`] which logs every request once its response body is
+/// finished or dropped.
+///
+/// The client address is taken from the [`ClientAddr`] request extension,
+/// falling back to the [`PeerAddr`] one.
+pub struct AccessLogLayer {
+    writer: Arc<Writer>,
+}
+
+impl AccessLogLayer {
+    /// Start logging to stdout, or appending to the configured file, from a
+    /// thread of its own.
+    /// # Errors
+    /// If the log file can't be opened, or the thread can't be started.
+    pub fn open(config: &AccessLogConfig) -> Result<Self, IoError> {
+        let sink = match &config.path {
+            Some(path) => Sink::open_file(path)?,
+            None => Sink::Stdout,
+        };
+        let writer = Writer::spawn(config.format, config.path.clone(), sink)?;
+        Ok(Self {
+            writer: Arc::new(writer),
+        })
+    }
+
+    /// Reopen the log file, so a rotated file stops being written to.
+    /// # Errors
+    /// If the log file can't be opened. The old file keeps being written to.
+    pub fn reopen(&self) -> Result<(), IoError> {
+        if let Some(path) = &self.writer.path {
+            let sink = Sink::open_file(path)?;
+            // the writer thread only stops once every sender is dropped
+            _ = self.writer.queue.send(Command::Reopen(sink));
+        }
+        Ok(())
+    }
+}
+
+impl<S> Layer<S> for AccessLogLayer {
+    type Service = AccessLog<S>;
+
+    fn layer(&self, inner: S) -> AccessLog<S> {
+        AccessLog {
+            writer: self.writer.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+/// A [`tower::Service`] which logs the requests to the service it wraps.
+pub struct AccessLog<S> {
+    writer: Arc<Writer>,
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for AccessLog<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+{
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+    type Response = Response<ObservedBody<ResBody, PendingEntry>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let request = RequestInfo::new(&req);
+        ResponseFuture {
+            inner: self.inner.call(req),
+            request: Some(request),
+            writer: self.writer.clone(),
+        }
+    }
+}
+
+#[pin_project::pin_project]
+/// Future which attaches the request's log entry to the response body.
+pub struct ResponseFuture<F> {
+    #[pin]
+    inner: F,
+    request: Option<RequestInfo>,
+    writer: Arc<Writer>,
+}
+
+impl<F, B, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    type Output = Result<Response<ObservedBody<B, PendingEntry>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let response = std::task::ready!(this.inner.poll(cx))?;
+        let request = this
+            .request
+            .take()
+            .expect("access log future polled after completion");
+        let entry = PendingEntry {
+            entry: Entry {
+                status: response.status(),
+                source: Source::of(&response),
+                request,
+            },
+            writer: this.writer.clone(),
+        };
+        Poll::Ready(Ok(response.map(|body| ObservedBody::new(body, entry))))
+    }
+}
+
+/// A log entry which is written once the response body has been sent.
+pub struct PendingEntry {
+    entry: Entry,
+    writer: Arc<Writer>,
+}
+
+impl BodyObserver for PendingEntry {
+    fn finished(self, bytes: u64) {
+        self.writer.write(&self.entry, bytes);
+    }
+}
+
+struct RequestInfo {
+    time: OffsetDateTime,
+    start: Instant,
+    peer: Option<PeerAddr>,
+    client: Option<IpAddr>,
+    method: Method,
+    target: String,
+    version: Version,
+    user_agent: Option<HeaderValue>,
+    referer: Option<HeaderValue>,
+}
+
+impl RequestInfo {
+    fn new<B>(req: &Request<B>) -> Self {
+        let target = req
+            .uri()
+            .path_and_query()
+            .map_or_else(|| req.uri().path().to_owned(), ToString::to_string);
+        let peer = req.extensions().get::<PeerAddr>().cloned();
+        let client = req
+            .extensions()
+            .get::<ClientAddr>()
+            .map_or_else(|| peer.as_ref().and_then(PeerAddr::ip), |client| client.0);
+        Self {
+            time: OffsetDateTime::now_utc(),
+            start: Instant::now(),
+            peer,
+            client,
+            method: req.method().clone(),
+            target,
+            version: req.version(),
+            user_agent: req.headers().get(header::USER_AGENT).cloned(),
+            referer: req.headers().get(header::REFERER).cloned(),
+        }
+    }
+}
+
+struct Entry {
+    request: RequestInfo,
+    status: StatusCode,
+    source: Source,
+}
+
+impl Entry {
+    fn combined(&self, bytes: u64) -> String {
+        let req = &self.request;
+        let host = req
+            .client
+            .map_or_else(|| "-".to_owned(), |ip| ip.to_string());
+        let time = req.time.format(CLF_TIME_FORMAT).unwrap_or_default();
+        let bytes = if bytes == 0 {
+            "-".to_owned()
+        } else {
+            bytes.to_string()
+        };
+        format!(
+            "{host} - - [{time}] \"{} {} {:?}\" {} {bytes} \"{}\" \"{}\" {:.3} {}",
+            req.method,
+            escape(req.target.as_bytes()),
+            req.version,
+            self.status.as_u16(),
+            req.referer
+                .as_ref()
+                .map_or_else(|| "-".to_owned(), |v| escape(v.as_bytes())),
+            req.user_agent
+                .as_ref()
+                .map_or_else(|| "-".to_owned(), |v| escape(v.as_bytes())),
+            req.start.elapsed().as_secs_f64(),
+            self.source.as_str(),
+        )
+    }
+
+    fn json(&self, bytes: u64) -> String {
+        let req = &self.request;
+        let header = |value: &Option<HeaderValue>| {
+            value
+                .as_ref()
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+        };
+        serde_json::json!({
+            "time": req.time.format(&Rfc3339).ok(),
+            "peer": req.peer.as_ref().map(ToString::to_string),
+            "client": req.client.map(|ip| ip.to_string()),
+            "method": req.method.as_str(),
+            "target": req.target,
+            "version": format!("{:?}", req.version),
+            "status": self.status.as_u16(),
+            "bytes": bytes,
+            "duration": req.start.elapsed().as_secs_f64(),
+            "user_agent": header(&req.user_agent),
+            "referer": header(&req.referer),
+            "source": self.source.as_str(),
+        })
+        .to_string()
+    }
+}
+
+/// Escape quotes, backslashes and unprintable bytes, like nginx does in its access logs.
+fn escape(value: &[u8]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for &byte in value {
+        match byte {
+            b'"' | b'\\' => {
+                escaped.push('\\');
+                escaped.push(byte.into());
+            }
+            b' '..=b'~' => escaped.push(byte.into()),
+            _ => _ = write!(escaped, "\\x{byte:02X}"),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http_body_util::{BodyExt, Full};
+    use tower::{ServiceBuilder, ServiceExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn logs_after_body() {
+        let path =
+            std::env::temp_dir().join(format!("tunnelbana-access-{}.log", std::process::id()));
+        let config = AccessLogConfig {
+            format: LogFormat::Combined,
+            path: Some(path.clone()),
+        };
+        let svc = ServiceBuilder::new()
+            .layer(AccessLogLayer::open(&config).unwrap())
+            .service_fn(|_: Request<()>| async {
+                Ok::<_, Infallible>(Response::new(Full::new(bytes::Bytes::from("hello"))))
+            });
+        let req = Request::builder()
+            .uri("/index.html?q=1")
+            .header(header::USER_AGENT, "curl/8 \"quoted\"")
+            .extension(PeerAddr::Tcp("192.0.2.1:4000".parse().unwrap()))
+            .body(())
+            .unwrap();
+        let body = svc.oneshot(req).await.unwrap().into_body();
+        body.collect().await.unwrap();
+
+        // lines are written by another thread
+        let mut line = String::new();
+        for _ in 0..100 {
+            line = std::fs::read_to_string(&path).unwrap();
+            if line.ends_with('\n') {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert!(line.starts_with("192.0.2.1 - - ["), "{line}");
+        assert!(
+            line.contains("\"GET /index.html?q=1 HTTP/1.1\" 200 5 \"-\" \"curl/8 \\\"quoted\\\"\""),
+            "{line}"
+        );
+        assert!(line.trim_end().ends_with(" file"), "{line}");
+    }
+}
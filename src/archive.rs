@@ -0,0 +1,145 @@
+//! Reading a site out of a `.tar`, `.tar.zst` or `.zip` archive, so a build
+//! artifact can be served without unpacking it.
+use std::{
+    fs::File,
+    io::{BufReader, Error as IoError, Read},
+    path::Path,
+};
+
+use bytes::Bytes;
+
+use crate::memfs::MemoryFs;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Tar,
+    TarZst,
+    Zip,
+}
+
+impl ArchiveKind {
+    /// The kind of archive at `path`, going by its extension.
+    // the name is lowercased first, and `Path::extension` can't see `.tar.zst`
+    #[allow(clippy::case_sensitive_file_extension_comparisons)]
+    pub fn of(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(Self::TarZst)
+        } else if name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else if name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// Read every regular file in the archive at `path` into memory. Every file
+/// is given the archive's own modification time, so a new deploy invalidates
+/// every response compressed on the fly.
+/// # Errors
+/// If the archive can't be read, or holds a path outside of its root.
+pub fn read(path: &Path, kind: ArchiveKind) -> Result<MemoryFs, ArchiveError> {
+    let file = File::open(path)?;
+    let modified = file.metadata()?.modified().ok();
+    let mut fs = MemoryFs::new(modified);
+    match kind {
+        ArchiveKind::Tar => read_tar(BufReader::new(file), &mut fs)?,
+        ArchiveKind::TarZst => read_tar(zstd::Decoder::new(file)?, &mut fs)?,
+        ArchiveKind::Zip => read_zip(file, &mut fs)?,
+    }
+    info!(archive = ?path, files = fs.len(), bytes = fs.size(), "Read site archive");
+    Ok(fs)
+}
+
+fn read_tar(reader: impl Read, fs: &mut MemoryFs) -> Result<(), ArchiveError> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let kind = entry.header().entry_type();
+        let path = entry.path()?.to_string_lossy().into_owned();
+        if kind.is_dir() {
+            continue;
+        }
+        if !kind.is_file() {
+            warn!(path, kind = ?kind, "Skipping archive entry which isn't a regular file");
+            continue;
+        }
+        let mut data = Vec::with_capacity(usize::try_from(entry.size()).unwrap_or_default());
+        entry.read_to_end(&mut data)?;
+        if !fs.insert(&path, Bytes::from(data)) {
+            return Err(ArchiveError::InvalidPath(path));
+        }
+    }
+    Ok(())
+}
+
+fn read_zip(file: File, fs: &mut MemoryFs) -> Result<(), ArchiveError> {
+    let mut archive = zip::ZipArchive::new(BufReader::new(file))?;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if entry.is_dir() {
+            continue;
+        }
+        let path = entry.name().to_owned();
+        if !entry.is_file() {
+            warn!(path, "Skipping archive entry which isn't a regular file");
+            continue;
+        }
+        let mut data = Vec::with_capacity(usize::try_from(entry.size()).unwrap_or_default());
+        entry.read_to_end(&mut data)?;
+        if !fs.insert(&path, Bytes::from(data)) {
+            return Err(ArchiveError::InvalidPath(path));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("I/O error: {0}")]
+    Io(#[from] IoError),
+    #[error("Invalid zip archive: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("Archive entry {0:?} is outside of the archive root")]
+    InvalidPath(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_tar_zst_and_zip() {
+        let dir = std::env::temp_dir().join(format!("tunnelbana-archive-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        tar.append_data(&mut header, "./docs/index.html", &b"hello"[..])
+            .unwrap();
+        let tar = tar.into_inner().unwrap();
+        let tar_zst = dir.join("site.tar.zst");
+        std::fs::write(&tar_zst, zstd::encode_all(&tar[..], 0).unwrap()).unwrap();
+
+        let zip_path = dir.join("site.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        zip.add_directory("docs/", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.start_file("docs/index.html", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        std::io::Write::write_all(&mut zip, b"hello").unwrap();
+        zip.finish().unwrap();
+
+        for path in [tar_zst, zip_path] {
+            let fs = read(&path, ArchiveKind::of(&path).unwrap()).unwrap();
+            assert_eq!(fs.len(), 1);
+            let index = fs.read_to_string("/docs/index.html").unwrap();
+            assert_eq!(index.as_deref(), Some("hello"));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,224 @@
+//! Picking each response's `Cache-Control`, so files with a fingerprint in
+//! their name, like `app.3f9a2c1b.js`, can be cached forever while everything
+//! else is revalidated.
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use http::{HeaderValue, Request, Response, header};
+use regex::Regex;
+use tower::{Layer, Service};
+
+use crate::config::ImmutableConfig;
+
+#[derive(Clone, Debug)]
+/// Finds content hashes in file names.
+pub enum Fingerprint {
+    /// The file name matches this pattern
+    Pattern(Regex),
+    /// A `.`, `-` or `_` separated part of the file name looks like a hash
+    /// at least this long
+    HashLength(usize),
+}
+
+impl Fingerprint {
+    /// Whether the last segment of `path` has a fingerprint in it.
+    pub fn matches(&self, path: &str) -> bool {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        match self {
+            Self::Pattern(pattern) => pattern.is_match(name),
+            Self::HashLength(min_len) => {
+                let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+                stem.split(['.', '-', '_'])
+                    .any(|part| is_hash(part, *min_len))
+            }
+        }
+    }
+}
+
+/// Whether `part` looks like a hex, base32 or base64 content hash.
+fn is_hash(part: &str, min_len: usize) -> bool {
+    let bytes = part.as_bytes();
+    if bytes.len() < min_len
+        || !bytes.iter().all(u8::is_ascii_alphanumeric)
+        || !bytes.iter().any(u8::is_ascii_digit)
+    {
+        return false;
+    }
+    // lowercase words with a number in them, like `bootstrap4`, aren't hashes,
+    // but lowercase hex is
+    bytes.iter().any(u8::is_ascii_uppercase) || bytes.iter().all(u8::is_ascii_hexdigit)
+}
+
+#[derive(Debug)]
+struct CachePolicy {
+    /// Sent with everything which isn't a fingerprinted file
+    default: HeaderValue,
+    /// Sent with successful responses for fingerprinted files
+    immutable: Option<(HeaderValue, Fingerprint)>,
+}
+
+#[derive(Clone, Debug)]
+/// A [`tower::Layer`] which sets `Cache-Control` on every response which
+/// doesn't already have one.
+pub struct CacheControlLayer {
+    policy: Arc<CachePolicy>,
+}
+
+impl CacheControlLayer {
+    pub fn new(default: HeaderValue, immutable: Option<&ImmutableConfig>) -> Self {
+        let immutable = immutable.map(|config| {
+            let fingerprint = config.pattern.clone().map_or(
+                Fingerprint::HashLength(config.hash_length),
+                Fingerprint::Pattern,
+            );
+            (config.cache_control.clone(), fingerprint)
+        });
+        Self {
+            policy: Arc::new(CachePolicy { default, immutable }),
+        }
+    }
+}
+
+impl<S> Layer<S> for CacheControlLayer {
+    type Service = CacheControl<S>;
+
+    fn layer(&self, inner: S) -> CacheControl<S> {
+        CacheControl {
+            policy: self.policy.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+/// A [`tower::Service`] which sets `Cache-Control` on the responses of the service it wraps.
+pub struct CacheControl<S> {
+    policy: Arc<CachePolicy>,
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for CacheControl<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+{
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+    type Response = Response<ResBody>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let immutable = self
+            .policy
+            .immutable
+            .as_ref()
+            .filter(|(_, fingerprint)| fingerprint.matches(req.uri().path()))
+            .map(|(value, _)| value.clone());
+        ResponseFuture {
+            inner: self.inner.call(req),
+            default: self.policy.default.clone(),
+            immutable,
+        }
+    }
+}
+
+#[pin_project::pin_project]
+/// Future which sets `Cache-Control` once the response is ready.
+pub struct ResponseFuture<F> {
+    #[pin]
+    inner: F,
+    default: HeaderValue,
+    /// Set when the request was for a fingerprinted file
+    immutable: Option<HeaderValue>,
+}
+
+impl<F, B, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    type Output = Result<Response<B>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let mut response = std::task::ready!(this.inner.poll(cx))?;
+        // a 404 for a fingerprinted path might be deployed later, so it isn't immutable
+        let value = match this.immutable.take() {
+            Some(immutable) if response.status().is_success() => immutable,
+            _ => this.default.clone(),
+        };
+        response
+            .headers_mut()
+            .entry(header::CACHE_CONTROL)
+            .or_insert(value);
+        Poll::Ready(Ok(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http::StatusCode;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn detects_fingerprints() {
+        let fingerprint = Fingerprint::HashLength(ImmutableConfig::default().hash_length);
+        for path in [
+            "/assets/app.3f9a2c1b.js",
+            "/app.3f9a2c.js",
+            "/assets/index-BvKDd1Qg.css",
+            "/chunk-ABCD2345.js",
+            "/3f9a2c1b9e.woff2",
+        ] {
+            assert!(fingerprint.matches(path), "{path}");
+        }
+        for path in [
+            "/index.html",
+            "/bootstrap4.min.css",
+            "/app.3f9a2.js",
+            "/3f9a2c1b/index.html",
+        ] {
+            assert!(!fingerprint.matches(path), "{path}");
+        }
+
+        let pattern = Fingerprint::Pattern(Regex::new(r"\.v\d+\.").unwrap());
+        assert!(pattern.matches("/app.v12.js"));
+        assert!(!pattern.matches("/app.3f9a2c1b.js"));
+    }
+
+    #[tokio::test]
+    async fn only_successful_fingerprinted_responses_are_immutable() {
+        let config = ImmutableConfig::default();
+        let layer = CacheControlLayer::new(HeaderValue::from_static("no-cache"), Some(&config));
+        let svc = layer.layer(tower::service_fn(|req: Request<()>| async move {
+            let mut response = Response::new(());
+            if req.uri().path().contains("missing") {
+                *response.status_mut() = StatusCode::NOT_FOUND;
+            }
+            Ok::<_, Infallible>(response)
+        }));
+        let cache_control = |path: &'static str| {
+            let svc = svc.clone();
+            async move {
+                let req = Request::builder().uri(path).body(()).unwrap();
+                let response = svc.oneshot(req).await.unwrap();
+                response.headers()[header::CACHE_CONTROL].clone()
+            }
+        };
+        assert_eq!(
+            cache_control("/app.3f9a2c1b.js").await,
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(cache_control("/index.html").await, "no-cache");
+        assert_eq!(cache_control("/missing.3f9a2c1b.js").await, "no-cache");
+    }
+}
@@ -0,0 +1,535 @@
+//! `tunnelbana check`, which does everything startup does short of listening,
+//! and reports every problem it finds rather than stopping at the first one.
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::{Display, Formatter, Result as FmtResult},
+    path::{Path, PathBuf},
+};
+
+use argh::FromArgs;
+use percent_encoding::percent_decode_str;
+use tunnelbana_headers::HeaderGroup;
+use tunnelbana_redirects::{Redirect, RedirectsLayer};
+
+use crate::{
+    archive::ArchiveKind,
+    config::Config,
+    site::{RESERVED_PATHS, ServeOptions, Site, SiteContents, SiteService},
+    tls::CertStore,
+    vhost::VirtualHosts,
+};
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "check")]
+/// Check the sites, certificates and config for problems without serving
+/// anything, and exit with an error if there are any
+pub struct CheckArgs {
+    /// directory or .tar, .tar.zst or .zip archive to check, overriding `root` in the config file
+    #[argh(positional)]
+    pub directory: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug)]
+struct Problem {
+    severity: Severity,
+    /// The file the problem is in, if it's in one
+    file: Option<PathBuf>,
+    /// The line and column the problem is at, both starting at 1
+    position: Option<(usize, usize)>,
+    message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+            if let Some((line, column)) = self.position {
+                write!(f, "{line}:{column}:")?;
+            }
+            f.write_str(" ")?;
+        }
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{severity}: {}", self.message)
+    }
+}
+
+#[derive(Debug, Default)]
+/// Every problem found by [`check`], in the order they were found.
+pub struct Report {
+    problems: Vec<Problem>,
+}
+
+impl Report {
+    fn push(
+        &mut self,
+        severity: Severity,
+        file: Option<&Path>,
+        position: Option<(usize, usize)>,
+        message: impl Display,
+    ) {
+        self.problems.push(Problem {
+            severity,
+            file: file.map(Path::to_path_buf),
+            position,
+            message: message.to_string(),
+        });
+    }
+
+    fn error(&mut self, file: Option<&Path>, position: Option<(usize, usize)>, msg: impl Display) {
+        self.push(Severity::Error, file, position, msg);
+    }
+
+    fn warning(
+        &mut self,
+        file: Option<&Path>,
+        position: Option<(usize, usize)>,
+        msg: impl Display,
+    ) {
+        self.push(Severity::Warning, file, position, msg);
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.problems
+            .iter()
+            .filter(|problem| problem.severity == severity)
+            .count()
+    }
+
+    pub fn errors(&self) -> usize {
+        self.count(Severity::Error)
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        for problem in &self.problems {
+            writeln!(f, "{problem}")?;
+        }
+        writeln!(
+            f,
+            "{} errors, {} warnings",
+            self.errors(),
+            self.count(Severity::Warning)
+        )
+    }
+}
+
+/// Load every site in `config` the way startup would, along with its TLS
+/// certificates, and report everything wrong with them.
+pub fn check(config: &Config) -> Report {
+    let mut report = Report::default();
+    let options = ServeOptions::from(config);
+    let derived_etags = config.compression.is_some();
+
+    let default = config
+        .root
+        .as_ref()
+        .and_then(|root| check_site(&mut report, root, derived_etags, &options));
+    if config.root.is_none() && config.vhosts.is_empty() {
+        report.error(
+            None,
+            None,
+            "Expected a directory to serve, as an argument, `root` or `[[vhosts]]` in the config file",
+        );
+    }
+
+    let mut vhosts = Some(VirtualHosts::builder());
+    for vhost in &config.vhosts {
+        let options = ServeOptions {
+            spa: vhost.spa.unwrap_or(config.spa),
+            ..options.clone()
+        };
+        let service = check_site(&mut report, &vhost.root, derived_etags, &options);
+        // a site which failed to load can't be routed to, so only check the rest of the hosts
+        vhosts = vhosts.zip(service).and_then(|(vhosts, service)| {
+            vhosts
+                .hosts(vhost.hosts.iter().map(String::as_str), &service)
+                .map_err(|e| report.error(None, None, format!("Invalid vhost config: {e}")))
+                .ok()
+        });
+    }
+    if let (Some(vhosts), None, Some(host)) = (vhosts, default, &config.default_host)
+        && let Err(e) = vhosts.default_host(host)
+    {
+        report.error(None, None, format!("Invalid vhost config: {e}"));
+    }
+
+    check_tls(&mut report, config);
+    report
+}
+
+/// Check the site at `root`, returning its service if it could be built.
+/// Problems are reported against `root` as given, rather than canonicalized.
+fn check_site(
+    report: &mut Report,
+    root: &Path,
+    derived_etags: bool,
+    options: &ServeOptions,
+) -> Option<SiteService> {
+    if !(root.is_dir() || (root.is_file() && ArchiveKind::of(root).is_some())) {
+        report.error(
+            Some(root),
+            None,
+            "Expected the site root to be a directory or a .tar, .tar.zst or .zip archive",
+        );
+        return None;
+    }
+    let path = root;
+    let root = match root.canonicalize() {
+        Ok(root) => root,
+        Err(e) => {
+            report.error(
+                Some(root),
+                None,
+                format!("Could not canonicalize site root: {e}"),
+            );
+            return None;
+        }
+    };
+    // Preloading only changes where files are served from, so there's no
+    // point in holding the whole site in memory here.
+    let contents = match SiteContents::read(&root, derived_etags, None) {
+        Ok(contents) => contents,
+        Err(e) => {
+            report.error(Some(path), None, e);
+            return None;
+        }
+    };
+
+    let errors = report.errors();
+    check_contents(report, path, &contents, options);
+    if report.errors() > errors {
+        return None;
+    }
+
+    let service = Site::from_contents(root, contents, derived_etags)
+        .map_err(|e| e.to_string())
+        .and_then(|site| site.service(options).map_err(|e| e.to_string()));
+    service
+        .map_err(|e| report.error(Some(path), None, format!("Failed to build site: {e}")))
+        .ok()
+}
+
+/// Report every error in the site's `_headers`, `_redirects` and `_auth`, and warn
+/// about rules which look like mistakes given the files in the site.
+fn check_contents(
+    report: &mut Report,
+    root: &Path,
+    contents: &SiteContents,
+    options: &ServeOptions,
+) {
+    let spa = options.spa;
+    let headers_file = root.join("_headers");
+    let (headers, errors) = tunnelbana_headers::parse_all(&contents.headers);
+    for e in errors {
+        report.error(Some(&headers_file), Some((e.row, e.column)), e.kind);
+    }
+    let redirects_file = root.join("_redirects");
+    let (redirects, errors) = tunnelbana_redirects::parse_all(&contents.redirects);
+    for e in errors {
+        report.error(Some(&redirects_file), Some((e.row, e.column)), e.kind);
+    }
+    let auth_file = root.join("_auth");
+    let (_, errors) = tunnelbana_auth::parse_all(&contents.auth);
+    for e in errors {
+        report.error(Some(&auth_file), Some((e.row, e.column)), e.kind);
+    }
+
+    let site = SiteFiles {
+        files: contents.etags.keys().map(String::as_str).collect(),
+        // conflicting rules were left out of `redirects`, so this can't fail
+        redirects: RedirectsLayer::new(redirects.clone()).ok(),
+        clean_urls: options.clean_urls.is_some(),
+    };
+
+    // `/` is listed if every directory is, or `/` is one of the prefixes
+    let root_listed = options.listing.as_ref().is_some_and(|listing| {
+        listing.paths.is_empty()
+            || listing
+                .paths
+                .iter()
+                .any(|path| path.trim_end_matches('/').is_empty())
+    });
+    if !site.files.contains("/index.html") && !root_listed {
+        let message = "There is no index.html, so `/` will be a 404";
+        if spa {
+            report.error(Some(root), None, message);
+        } else {
+            report.warning(Some(root), None, message);
+        }
+    }
+    if !spa && !site.files.contains("/404.html") {
+        report.warning(
+            Some(root),
+            None,
+            "There is no 404.html, so not found responses will be empty",
+        );
+    }
+
+    site.check_shadowed_files(report, &redirects_file, &redirects);
+    site.check_header_paths(report, &headers_file, &headers, spa);
+    if !spa {
+        site.check_redirect_targets(report, &redirects_file, &redirects);
+    }
+}
+
+/// The paths a site serves, for finding rules which look like mistakes.
+struct SiteFiles<'a> {
+    files: HashSet<&'a str>,
+    redirects: Option<RedirectsLayer>,
+    /// Whether `/about` serves `/about.html`
+    clean_urls: bool,
+}
+
+impl SiteFiles<'_> {
+    /// Whether a request for `path` gets a file or a redirect.
+    fn serves(&self, path: &str) -> bool {
+        let path = percent_decode_str(path).decode_utf8_lossy();
+        let index = if path.ends_with('/') {
+            format!("{path}index.html")
+        } else {
+            format!("{path}/index.html")
+        };
+        let html = format!("{}.html", path.trim_end_matches('/'));
+        self.files.contains(path.as_ref())
+            || self.files.contains(index.as_str())
+            || (self.clean_urls && self.files.contains(html.as_str()))
+            || self.redirect_for(&path).is_some()
+    }
+
+    fn redirect_for(&self, path: &str) -> Option<String> {
+        self.redirects
+            .as_ref()
+            .and_then(|redirects| redirects.matched(path))
+            .map(|matched| matched.rule.to_string())
+    }
+
+    /// Warn about redirect rules which match a file, since the file can never be served.
+    fn check_shadowed_files(&self, report: &mut Report, file: &Path, redirects: &[Redirect]) {
+        let lines: HashMap<&str, usize> = redirects
+            .iter()
+            .map(|redirect| (redirect.path.as_str(), redirect.line))
+            .collect();
+        let mut files: Vec<&str> = self.files.iter().copied().collect();
+        files.sort_unstable();
+
+        let mut shadowed: BTreeMap<usize, (String, Vec<&str>)> = BTreeMap::new();
+        for path in files {
+            if RESERVED_PATHS.contains(&path) {
+                continue;
+            }
+            // an index.html is also served at its directory
+            let served_at = [Some(path), path.strip_suffix("index.html")];
+            let Some(rule) = served_at
+                .into_iter()
+                .flatten()
+                .find_map(|at| self.redirect_for(at))
+            else {
+                continue;
+            };
+            let Some(&line) = lines.get(rule.as_str()) else {
+                continue;
+            };
+            shadowed
+                .entry(line)
+                .or_insert_with(|| (rule, Vec::new()))
+                .1
+                .push(path);
+        }
+
+        for (line, (rule, paths)) in shadowed {
+            let message = match paths.as_slice() {
+                [path] => format!("`{rule}` redirects away from the file {path}"),
+                [path, rest @ ..] => format!(
+                    "`{rule}` redirects away from the file {path}, and {} others",
+                    rest.len()
+                ),
+                [] => continue,
+            };
+            report.warning(Some(file), Some((line, 1)), message);
+        }
+    }
+
+    /// Warn about header rules which no request can match. Rules for paths
+    /// which aren't in the site are only checked when `spa` is off, since
+    /// otherwise every path is served.
+    fn check_header_paths(
+        &self,
+        report: &mut Report,
+        file: &Path,
+        headers: &[HeaderGroup],
+        spa: bool,
+    ) {
+        // a wildcard rule becomes two groups on the same line
+        let mut lines: HashMap<usize, usize> = HashMap::new();
+        for group in headers {
+            *lines.entry(group.line).or_default() += 1;
+        }
+        let mut seen = HashSet::new();
+        for group in headers {
+            if !seen.insert(group.line) {
+                continue;
+            }
+            let position = Some((group.line, 1));
+            let path = &group.path;
+            if !path.starts_with('/') {
+                report.warning(
+                    Some(file),
+                    position,
+                    format!("`{path}` can never match, since request paths start with `/`"),
+                );
+            } else if path.contains(['?', '#']) {
+                report.warning(
+                    Some(file),
+                    position,
+                    format!(
+                        "`{path}` can never match, since query strings and fragments aren't part of the path"
+                    ),
+                );
+            } else if !spa
+                && lines.get(&group.line) == Some(&1)
+                && !path.contains('{')
+                && !self.serves(path)
+            {
+                report.warning(
+                    Some(file),
+                    position,
+                    format!("`{path}` doesn't match any file or redirect in the site"),
+                );
+            }
+        }
+    }
+
+    /// Warn about redirects to paths on this site which aren't served.
+    /// Targets which interpolate part of the path can't be checked.
+    fn check_redirect_targets(&self, report: &mut Report, file: &Path, redirects: &[Redirect]) {
+        let no_params: HashMap<Cow<str>, Cow<str>> = HashMap::new();
+        for redirect in redirects {
+            let Ok(target) = redirect.target.try_render(&no_params) else {
+                continue;
+            };
+            if !target.starts_with('/') || target.starts_with("//") {
+                continue;
+            }
+            let path = target.split(['?', '#']).next().unwrap_or_default();
+            if !self.serves(path) {
+                // the target is the second item on the line
+                let column = redirect.path.chars().count() + 2;
+                report.warning(
+                    Some(file),
+                    Some((redirect.line, column)),
+                    format!("`{target}` isn't a file or redirect in the site"),
+                );
+            }
+        }
+    }
+}
+
+/// Check that the configured certificates can be loaded, and that TLS
+/// listeners have some to use.
+fn check_tls(report: &mut Report, config: &Config) {
+    let default_cert = match config.default_cert() {
+        Ok(cert) => cert,
+        Err(e) => {
+            report.error(None, None, format!("Invalid TLS config: {e}"));
+            return;
+        }
+    };
+    let has_certs = default_cert.is_some() || !config.tls.sni.is_empty();
+    let needs_certs = config.listen.iter().any(|listen| listen.tls) || config.http3.is_some();
+    if needs_certs && !has_certs {
+        report.error(
+            None,
+            None,
+            "TLS and HTTP/3 listeners require a default certificate and key, or SNI certificates",
+        );
+    }
+    if has_certs && let Err(e) = CertStore::new(default_cert, config.tls.sni.clone()) {
+        report.error(None, None, format!("Failed to load TLS certificates: {e}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_dir(files: &[(&str, &str)], spa: bool) -> Vec<String> {
+        let root =
+            std::env::temp_dir().join(format!("tunnelbana-check-{}-{spa}", std::process::id()));
+        for (path, contents) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        let config = Config {
+            root: Some(root.clone()),
+            spa,
+            ..Config::default()
+        };
+        let report = check(&config);
+        let prefix = root.display().to_string();
+        std::fs::remove_dir_all(&root).unwrap();
+        report
+            .problems
+            .iter()
+            .map(|problem| {
+                let problem = problem.to_string();
+                let problem = problem.strip_prefix(&prefix).unwrap_or(&problem);
+                problem.trim_start_matches(['/', ':', ' ']).to_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let problems = check_dir(
+            &[
+                ("index.html", "hello"),
+                ("docs/index.html", "docs"),
+                (
+                    "_headers",
+                    "/docs/*\n  X-A: b\n/missing\n  X-A: b\nassets\n  X-A: b\n  Bad\n",
+                ),
+                (
+                    "_redirects",
+                    "/docs/ /gone\n/old /new 999x\n/about /docs/\n",
+                ),
+                ("_auth", "/docs/*\n  alice:hunter2\n"),
+            ],
+            false,
+        );
+        assert_eq!(
+            problems,
+            [
+                "_headers:7:3: error: You must put a colon at the end of the header name",
+                "_redirects:2:11: error: `999x` could not be converted to a status",
+                "_auth:1:1: error: A path needs at least one user or token",
+                "_auth:2:3: error: Password hashes must be bcrypt ($2y$...) or argon2 ($argon2id$...)",
+                "warning: There is no 404.html, so not found responses will be empty",
+                "_redirects:1:1: warning: `/docs/` redirects away from the file /docs/index.html",
+                "_headers:3:1: warning: `/missing` doesn't match any file or redirect in the site",
+                "_headers:5:1: warning: `assets` can never match, since request paths start with `/`",
+                "_redirects:1:8: warning: `/gone` isn't a file or redirect in the site",
+            ]
+        );
+    }
+
+    #[test]
+    fn spa_requires_index() {
+        let problems = check_dir(&[("app.js", "")], true);
+        assert_eq!(
+            problems,
+            ["error: There is no index.html, so `/` will be a 404"]
+        );
+    }
+}
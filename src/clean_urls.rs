@@ -0,0 +1,345 @@
+//! Cloudflare Pages-style clean URLs: `/about.html` is served at `/about`,
+//! and every page has one canonical URL, which the others redirect to.
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use http::{HeaderValue, Request, Response, StatusCode, Uri, header, uri::PathAndQuery};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use tower::{Layer, Service};
+use tunnelbana_etags::ETagLayer;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+/// Whether canonical URLs end with a slash.
+pub enum TrailingSlash {
+    /// Directories get a trailing slash, like `/blog/`, and pages don't, like `/about`
+    #[default]
+    Auto,
+    /// Pages and directories both get a trailing slash
+    Add,
+    /// Neither pages nor directories get a trailing slash
+    Remove,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+/// How a request for a path is handled with clean URLs.
+pub enum Resolution {
+    /// The path is canonical, and is served as it is
+    Serve,
+    /// The path is canonical, and is served from the file at this path
+    Rewrite(String),
+    /// The path isn't canonical, so it's redirected to this one
+    Redirect(String),
+}
+
+#[derive(Clone)]
+/// A [`tower::Layer`] which serves `.html` files without their extension,
+/// and redirects every other URL for a page to its canonical one. Without
+/// a [`TrailingSlash`], every request is passed through unchanged.
+///
+/// Files are found with the site's etags, which cover every file it serves.
+pub struct CleanUrlsLayer {
+    files: ETagLayer,
+    trailing_slash: Option<TrailingSlash>,
+}
+
+impl CleanUrlsLayer {
+    pub const fn new(files: ETagLayer, trailing_slash: Option<TrailingSlash>) -> Self {
+        Self {
+            files,
+            trailing_slash,
+        }
+    }
+
+    /// How a request for `path`, which is percent-encoded, is handled.
+    /// Paths which don't name a page are always served as they are.
+    pub fn resolve(&self, path: &str) -> Resolution {
+        let Some(trailing_slash) = self.trailing_slash else {
+            return Resolution::Serve;
+        };
+        let Some((file, canonical)) = self.page(path, trailing_slash) else {
+            return Resolution::Serve;
+        };
+        if canonical != path {
+            Resolution::Redirect(canonical)
+        } else if file != path {
+            Resolution::Rewrite(file)
+        } else {
+            Resolution::Serve
+        }
+    }
+
+    /// The path of the file a request for `path` is served from, if it isn't
+    /// `path` itself, counting the `index.html` the file service adds to
+    /// directories. Redirected paths aren't served from anything.
+    pub fn served_path(&self, path: &str) -> Option<String> {
+        let file = match self.resolve(path) {
+            Resolution::Serve => path.to_owned(),
+            Resolution::Rewrite(file) => file,
+            Resolution::Redirect(_) => return None,
+        };
+        let file = if file.ends_with('/') {
+            format!("{file}index.html")
+        } else {
+            file
+        };
+        (file != path).then_some(file)
+    }
+
+    /// The file of the page `path` names and the page's canonical path, if
+    /// it names one.
+    fn page(&self, path: &str, trailing_slash: TrailingSlash) -> Option<(String, String)> {
+        let directory = |dir: &str| {
+            if trailing_slash == TrailingSlash::Remove && !dir.is_empty() {
+                dir.to_owned()
+            } else {
+                format!("{dir}/")
+            }
+        };
+        let page = |page: &str| {
+            if trailing_slash == TrailingSlash::Add {
+                format!("{page}/")
+            } else {
+                page.to_owned()
+            }
+        };
+
+        if let Some(dir) = path.strip_suffix("/index.html")
+            && self.exists(path)
+        {
+            return Some((path.to_owned(), directory(dir)));
+        }
+        if let Some(base) = path.strip_suffix(".html")
+            && self.exists(path)
+        {
+            // `/notes` and `/notes.html` can both be files
+            let canonical = if self.exists(base) {
+                path.to_owned()
+            } else {
+                page(base)
+            };
+            return Some((path.to_owned(), canonical));
+        }
+        if let Some(base) = path.strip_suffix('/') {
+            let index = format!("{path}index.html");
+            let html = format!("{base}.html");
+            // the file service finds a directory's index itself
+            if self.exists(&index) {
+                return Some((path.to_owned(), directory(base)));
+            } else if !base.is_empty() && self.exists(&html) {
+                return Some((html, page(base)));
+            }
+            return None;
+        }
+        if self.exists(path) {
+            return None;
+        }
+        let html = format!("{path}.html");
+        let index = format!("{path}/index.html");
+        if self.exists(&html) {
+            Some((html, page(path)))
+        } else if self.exists(&index) {
+            Some((index, directory(path)))
+        } else {
+            None
+        }
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        percent_decode_str(path)
+            .decode_utf8()
+            .is_ok_and(|path| self.files.contains(&path))
+    }
+}
+
+impl<S> Layer<S> for CleanUrlsLayer {
+    type Service = CleanUrls<S>;
+
+    fn layer(&self, inner: S) -> CleanUrls<S> {
+        CleanUrls {
+            layer: self.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+/// A [`tower::Service`] which serves clean URLs from the service it wraps.
+pub struct CleanUrls<S> {
+    layer: CleanUrlsLayer,
+    inner: S,
+}
+
+/// `uri` with its path replaced, keeping the query.
+fn with_path(uri: &Uri, path: &str) -> Option<PathAndQuery> {
+    let Some(query) = uri.query() else {
+        return path.parse().ok();
+    };
+    format!("{path}?{query}").parse().ok()
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for CleanUrls<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    ResBody: Default,
+{
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+    type Response = Response<ResBody>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        match self.layer.resolve(req.uri().path()) {
+            Resolution::Serve => {}
+            Resolution::Rewrite(file) => {
+                // the rest of the stack, etags included, sees the file's own path
+                let mut parts = req.uri().clone().into_parts();
+                parts.path_and_query = with_path(req.uri(), &file);
+                if let Ok(uri) = Uri::from_parts(parts) {
+                    *req.uri_mut() = uri;
+                }
+            }
+            Resolution::Redirect(path) => {
+                let location = with_path(req.uri(), &path)
+                    .and_then(|location| HeaderValue::try_from(location.as_str()).ok());
+                if let Some(location) = location {
+                    return ResponseFuture::Redirect(Some(location));
+                }
+            }
+        }
+        ResponseFuture::Inner(self.inner.call(req))
+    }
+}
+
+#[pin_project::pin_project(project = ResponseFutureProj)]
+/// Future which is either the wrapped service's response, or a redirect.
+pub enum ResponseFuture<F> {
+    Inner(#[pin] F),
+    Redirect(Option<HeaderValue>),
+}
+
+impl<F, B, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+    B: Default,
+{
+    type Output = Result<Response<B>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            ResponseFutureProj::Inner(inner) => inner.poll(cx),
+            ResponseFutureProj::Redirect(location) => {
+                let mut response = Response::new(B::default());
+                *response.status_mut() = StatusCode::PERMANENT_REDIRECT;
+                if let Some(location) = location.take() {
+                    response.headers_mut().insert(header::LOCATION, location);
+                }
+                Poll::Ready(Ok(response))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use tower::ServiceExt;
+    use tunnelbana_etags::ETagMap;
+
+    use super::*;
+
+    fn layer(trailing_slash: TrailingSlash) -> CleanUrlsLayer {
+        let files = [
+            ("/index.html", &b""[..]),
+            ("/about.html", b""),
+            ("/blog/index.html", b""),
+            ("/blog/first post.html", b""),
+            ("/notes", b""),
+            ("/notes.html", b""),
+            ("/style.css", b""),
+        ];
+        let files = ETagLayer::new(ETagMap::from_files(files, false).unwrap());
+        CleanUrlsLayer::new(files, Some(trailing_slash))
+    }
+
+    #[test]
+    fn resolves_pages() {
+        use Resolution::{Redirect, Rewrite, Serve};
+        let redirect = |path: &str| Redirect(path.to_owned());
+        let rewrite = |path: &str| Rewrite(path.to_owned());
+
+        let auto = layer(TrailingSlash::Auto);
+        assert_eq!(auto.resolve("/"), Serve);
+        assert_eq!(auto.resolve("/index.html"), redirect("/"));
+        assert_eq!(auto.resolve("/about"), rewrite("/about.html"));
+        assert_eq!(auto.resolve("/about.html"), redirect("/about"));
+        assert_eq!(auto.resolve("/about/"), redirect("/about"));
+        assert_eq!(auto.resolve("/blog"), redirect("/blog/"));
+        assert_eq!(auto.resolve("/blog/"), Serve);
+        assert_eq!(auto.resolve("/blog/index.html"), redirect("/blog/"));
+        assert_eq!(
+            auto.resolve("/blog/first%20post"),
+            rewrite("/blog/first%20post.html")
+        );
+        assert_eq!(auto.resolve("/notes.html"), Serve);
+        assert_eq!(auto.resolve("/style.css"), Serve);
+        assert_eq!(auto.resolve("/missing"), Serve);
+
+        let add = layer(TrailingSlash::Add);
+        assert_eq!(add.resolve("/about"), redirect("/about/"));
+        assert_eq!(add.resolve("/about.html"), redirect("/about/"));
+        assert_eq!(add.resolve("/about/"), rewrite("/about.html"));
+        assert_eq!(add.resolve("/blog"), redirect("/blog/"));
+
+        let remove = layer(TrailingSlash::Remove);
+        assert_eq!(remove.resolve("/"), Serve);
+        assert_eq!(remove.resolve("/blog/"), redirect("/blog"));
+        assert_eq!(remove.resolve("/blog/index.html"), redirect("/blog"));
+        assert_eq!(remove.resolve("/blog"), rewrite("/blog/index.html"));
+        assert_eq!(remove.resolve("/about/"), redirect("/about"));
+
+        let served = |path: &str| auto.served_path(path);
+        assert_eq!(served("/about").as_deref(), Some("/about.html"));
+        assert_eq!(served("/blog/").as_deref(), Some("/blog/index.html"));
+        assert_eq!(served("/about.html"), None);
+        assert_eq!(served("/style.css"), None);
+
+        let off = CleanUrlsLayer::new(auto.files, None);
+        assert_eq!(off.resolve("/about.html"), Serve);
+        assert_eq!(off.served_path("/about"), None);
+        assert_eq!(off.served_path("/").as_deref(), Some("/index.html"));
+    }
+
+    #[tokio::test]
+    async fn rewrites_and_redirects_requests() {
+        let svc =
+            layer(TrailingSlash::Auto).layer(tower::service_fn(|req: Request<()>| async move {
+                let mut response = Response::new(());
+                response
+                    .headers_mut()
+                    .insert("x-path", req.uri().to_string().try_into().unwrap());
+                Ok::<_, Infallible>(response)
+            }));
+        let get = |path: &'static str| {
+            let svc = svc.clone();
+            async move {
+                let req = Request::builder().uri(path).body(()).unwrap();
+                svc.oneshot(req).await.unwrap()
+            }
+        };
+
+        let response = get("/about?ref=home").await;
+        assert_eq!(response.headers()["x-path"], "/about.html?ref=home");
+        let response = get("/about.html?ref=home").await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[header::LOCATION], "/about?ref=home");
+    }
+}
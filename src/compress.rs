@@ -0,0 +1,498 @@
+//! Compressing responses on the fly when no precompressed sibling exists on
+//! disk, keeping the results in a size-bounded in-memory cache.
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    fmt::{Debug, Formatter},
+    io::{Error as IoError, Write},
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, header};
+use http_body_util::{BodyExt, Full};
+use tower::{Layer, Service};
+
+use crate::{config::CompressionConfig, listing::Listed, site::ResponseBody};
+
+const BROTLI_QUALITY: i32 = 9;
+const ZSTD_LEVEL: i32 = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// Every encoding, most preferred first.
+    pub const ALL: [Self; 4] = [Self::Brotli, Self::Zstd, Self::Gzip, Self::Deflate];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    /// The suffix of a file precompressed with this encoding, as `ServeDir` looks for it.
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Brotli => ".br",
+            Self::Zstd => ".zst",
+            Self::Gzip => ".gz",
+            Self::Deflate => ".zz",
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>, IoError> {
+        match self {
+            Self::Brotli => {
+                let params = brotli::enc::BrotliEncoderParams {
+                    quality: BROTLI_QUALITY,
+                    ..Default::default()
+                };
+                let mut out = Vec::new();
+                brotli::BrotliCompress(&mut &data[..], &mut out, &params)?;
+                Ok(out)
+            }
+            Self::Zstd => zstd::encode_all(data, ZSTD_LEVEL),
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            // HTTP's deflate is zlib, the same as the `.zz` files ServeDir serves
+            Self::Deflate => {
+                let mut encoder =
+                    flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// The encoding the client most wants out of the `available` ones, if any.
+pub fn preferred_encoding(
+    headers: &HeaderMap,
+    available: impl Fn(Encoding) -> bool,
+) -> Option<Encoding> {
+    // qvalues in thousandths, so they can be compared exactly
+    let mut explicit = [None; Encoding::ALL.len()];
+    let mut wildcard = None;
+    let values = headers.get_all(header::ACCEPT_ENCODING).iter();
+    for item in values
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+    {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let q = params
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(Some(1000), parse_qvalue);
+        let Some(q) = q else {
+            continue;
+        };
+        if name == "*" {
+            wildcard = Some(q);
+        } else if let Some(i) = Encoding::ALL
+            .iter()
+            .position(|e| e.name().eq_ignore_ascii_case(name))
+        {
+            explicit[i] = Some(q);
+        }
+    }
+    Encoding::ALL
+        .into_iter()
+        .zip(explicit)
+        .filter_map(|(encoding, q)| Some((encoding, q.or(wildcard)?)))
+        .filter(|(encoding, q)| *q > 0 && available(*encoding))
+        // max_by_key keeps the last maximum, so reverse to prefer earlier encodings
+        .rev()
+        .max_by_key(|(_, q)| *q)
+        .map(|(encoding, _)| encoding)
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn parse_qvalue(q: &str) -> Option<u16> {
+    let q: f32 = q.trim().parse().ok()?;
+    (0.0..=1.0)
+        .contains(&q)
+        .then(|| (q * 1000.0).round() as u16)
+}
+
+/// Whether a `Content-Type` is worth compressing.
+fn compressible(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/javascript"
+                | "application/json"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+                | "image/x-icon"
+                | "font/ttf"
+                | "font/otf"
+        )
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+/// Which compressed body a response can be served from.
+pub struct CacheKey {
+    root: Arc<Path>,
+    path: Box<str>,
+    encoding: Encoding,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+/// Identifies the version of a file a cached body was compressed from.
+struct Validator {
+    last_modified: Option<HeaderValue>,
+    len: u64,
+}
+
+struct CachedBody {
+    validator: Validator,
+    body: Bytes,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, CachedBody>,
+    /// Keys in insertion order, so the oldest is evicted first
+    order: VecDeque<CacheKey>,
+    size: usize,
+}
+
+struct Cache {
+    limit: usize,
+    state: Mutex<CacheState>,
+}
+
+impl Cache {
+    fn get(&self, key: &CacheKey, validator: &Validator) -> Option<Bytes> {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state
+            .entries
+            .get(key)
+            .filter(|cached| cached.validator == *validator)
+            .map(|cached| cached.body.clone())
+    }
+
+    fn insert(&self, key: CacheKey, validator: Validator, body: Bytes) {
+        if body.len() > self.limit {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.size += body.len();
+        let cached = CachedBody { validator, body };
+        match state.entries.insert(key.clone(), cached) {
+            Some(old) => state.size -= old.body.len(),
+            None => state.order.push_back(key),
+        }
+        while state.size > self.limit {
+            let Some(oldest) = state.order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = state.entries.remove(&oldest) {
+                state.size -= evicted.body.len();
+            }
+        }
+        drop(state);
+    }
+}
+
+#[derive(Clone)]
+/// Compression settings and the cache, shared by every site.
+pub struct Compression {
+    min_size: u64,
+    max_size: u64,
+    cache: Arc<Cache>,
+}
+
+impl Debug for Compression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Compression")
+            .field("min_size", &self.min_size)
+            .field("max_size", &self.max_size)
+            .field("cache_size", &self.cache.limit)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Compression {
+    pub fn new(config: &CompressionConfig) -> Self {
+        Self {
+            min_size: config.min_size,
+            max_size: config.max_size,
+            cache: Arc::new(Cache {
+                limit: config.cache_size,
+                state: Mutex::new(CacheState::default()),
+            }),
+        }
+    }
+
+    /// The validator of a response worth compressing, or `None` to send it as it is.
+    fn validator<B>(&self, response: &Response<B>) -> Option<Validator> {
+        let headers = response.headers();
+        // listings change with their directory, so they can't be cached by path
+        if response.status() != StatusCode::OK
+            || response.extensions().get::<Listed>().is_some()
+            || headers.contains_key(header::CONTENT_ENCODING)
+            || headers.contains_key(header::CONTENT_RANGE)
+        {
+            return None;
+        }
+        let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+        let len: u64 = headers
+            .get(header::CONTENT_LENGTH)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()?;
+        (compressible(content_type) && (self.min_size..=self.max_size).contains(&len)).then(|| {
+            Validator {
+                last_modified: headers.get(header::LAST_MODIFIED).cloned(),
+                len,
+            }
+        })
+    }
+
+    async fn compress_response(
+        self,
+        key: CacheKey,
+        response: Response<ResponseBody>,
+    ) -> Response<ResponseBody> {
+        let Some(validator) = self.validator(&response) else {
+            return response;
+        };
+        let (mut parts, body) = response.into_parts();
+        let encoding = key.encoding;
+        let compressed = if let Some(body) = self.cache.get(&key, &validator) {
+            body
+        } else {
+            let raw = match body.collect().await {
+                Ok(collected) => collected.to_bytes(),
+                Err(e) => {
+                    warn!("failed to read response to compress: {}", e);
+                    let mut response = Response::new(full_body(Bytes::new()));
+                    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                    return response;
+                }
+            };
+            let uncompressed = raw.clone();
+            match tokio::task::spawn_blocking(move || encoding.compress(&raw)).await {
+                Ok(Ok(compressed)) => {
+                    let compressed = Bytes::from(compressed);
+                    self.cache.insert(key, validator, compressed.clone());
+                    compressed
+                }
+                Ok(Err(e)) => {
+                    warn!(encoding = encoding.name(), "compression failed: {}", e);
+                    return Response::from_parts(parts, full_body(uncompressed));
+                }
+                Err(e) => {
+                    warn!(encoding = encoding.name(), "compression panicked: {}", e);
+                    return Response::from_parts(parts, full_body(uncompressed));
+                }
+            }
+        };
+        let headers = &mut parts.headers;
+        headers.insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.name()),
+        );
+        headers.insert(header::CONTENT_LENGTH, compressed.len().into());
+        // ranges would be of the uncompressed file
+        headers.remove(header::ACCEPT_RANGES);
+        Response::from_parts(parts, full_body(compressed))
+    }
+}
+
+fn full_body(bytes: Bytes) -> ResponseBody {
+    ResponseBody::new(Full::new(bytes).map_err(|e| match e {}))
+}
+
+#[derive(Clone)]
+/// A [`tower::Layer`] which compresses responses that weren't served precompressed.
+pub struct CompressionLayer {
+    compression: Option<Compression>,
+    root: Arc<Path>,
+}
+
+impl CompressionLayer {
+    /// Compress responses for the site in `root`, or pass them through if
+    /// `compression` is `None`.
+    pub fn new(compression: Option<Compression>, root: &Path) -> Self {
+        Self {
+            compression,
+            root: root.into(),
+        }
+    }
+}
+
+impl<S> Layer<S> for CompressionLayer {
+    type Service = Compress<S>;
+
+    fn layer(&self, inner: S) -> Compress<S> {
+        Compress {
+            compression: self.compression.clone(),
+            root: self.root.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+/// A [`tower::Service`] which compresses responses from the service it wraps.
+pub struct Compress<S> {
+    compression: Option<Compression>,
+    root: Arc<Path>,
+    inner: S,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for Compress<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResponseBody>, Error = Infallible>,
+{
+    type Error = Infallible;
+    type Future = ResponseFuture<S::Future>;
+    type Response = Response<ResponseBody>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let target = self
+            .compression
+            .as_ref()
+            // a HEAD response has no body to compress, so it can't say how long one would be
+            .filter(|_| req.method() == Method::GET)
+            .zip(preferred_encoding(req.headers(), |_| true))
+            .map(|(compression, encoding)| {
+                let path = req.uri().path();
+                let path = if path.ends_with('/') {
+                    format!("{path}index.html")
+                } else {
+                    path.to_string()
+                };
+                let key = CacheKey {
+                    root: self.root.clone(),
+                    path: path.into_boxed_str(),
+                    encoding,
+                };
+                (compression.clone(), key)
+            });
+        ResponseFuture::Inner {
+            inner: self.inner.call(req),
+            target,
+        }
+    }
+}
+
+type CompressFuture = Pin<Box<dyn Future<Output = Response<ResponseBody>> + Send>>;
+
+#[pin_project::pin_project(project = ResponseFutureProj)]
+/// Future which waits for the inner response, then compresses it if it should be.
+pub enum ResponseFuture<F> {
+    /// Waiting for the wrapped service. `target` is where to cache the
+    /// compressed body, if the request accepts a compressed one.
+    Inner {
+        #[pin]
+        inner: F,
+        target: Option<(Compression, CacheKey)>,
+    },
+    /// Reading and compressing the response body.
+    Compressing(CompressFuture),
+}
+
+impl<F> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<ResponseBody>, Infallible>>,
+{
+    type Output = Result<Response<ResponseBody>, Infallible>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let compressing = match self.as_mut().project() {
+            ResponseFutureProj::Inner { inner, target } => {
+                let Ok(response) = std::task::ready!(inner.poll(cx));
+                let Some((compression, key)) = target.take() else {
+                    return Poll::Ready(Ok(response));
+                };
+                Box::pin(compression.compress_response(key, response))
+            }
+            ResponseFutureProj::Compressing(fut) => {
+                return fut.as_mut().poll(cx).map(Ok);
+            }
+        };
+        self.set(Self::Compressing(compressing));
+        self.poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &'static str) -> Option<Encoding> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+        preferred_encoding(&headers, |_| true)
+    }
+
+    #[test]
+    fn negotiates_encoding() {
+        assert_eq!(accept("gzip, deflate, br, zstd"), Some(Encoding::Brotli));
+        assert_eq!(accept("gzip;q=0.5, deflate;q=0.8"), Some(Encoding::Deflate));
+        assert_eq!(accept("*;q=0.1, br;q=0"), Some(Encoding::Zstd));
+        assert_eq!(accept("identity"), None);
+        assert_eq!(accept("GZIP; q=1.0"), Some(Encoding::Gzip));
+        assert_eq!(accept("gzip;q=0"), None);
+    }
+
+    #[test]
+    fn cache_evicts_oldest() {
+        let cache = Cache {
+            limit: 10,
+            state: Mutex::new(CacheState::default()),
+        };
+        let key = |path: &str| CacheKey {
+            root: Path::new("/srv").into(),
+            path: path.into(),
+            encoding: Encoding::Gzip,
+        };
+        let validator = Validator {
+            last_modified: None,
+            len: 100,
+        };
+        cache.insert(key("/a"), validator.clone(), Bytes::from_static(b"aaaaaa"));
+        cache.insert(key("/b"), validator.clone(), Bytes::from_static(b"bbbbbb"));
+        assert!(cache.get(&key("/a"), &validator).is_none());
+        assert!(cache.get(&key("/b"), &validator).is_some());
+
+        let changed = Validator {
+            last_modified: None,
+            len: 101,
+        };
+        assert!(cache.get(&key("/b"), &changed).is_none());
+    }
+}
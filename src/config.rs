@@ -0,0 +1,792 @@
+//! The optional `tunnelbana.toml` configuration file.
+//!
+//! Every key can be overridden by an environment variable named after it,
+//! like `TUNNELBANA_LOG_LEVEL=debug`. Keys in tables are separated by two
+//! underscores, like `TUNNELBANA_TLS__CERT=/etc/tls/cert.pem`. Values are
+//! parsed as TOML when possible, and used as strings otherwise. Variables
+//! which don't name a top-level key, like the `TUNNELBANA_SERVICE_HOST`
+//! Kubernetes sets for a service called `tunnelbana`, are ignored.
+use std::{
+    fmt::Display,
+    io::Error as IoError,
+    net::SocketAddr,
+    num::{NonZeroU32, NonZeroUsize},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use http::HeaderValue;
+use ipnet::IpNet;
+use regex::Regex;
+use serde::{
+    Deserialize, Deserializer,
+    de::{Error as _, Visitor, value::Error as ValueError},
+    forward_to_deserialize_any,
+};
+use toml::{Table, Value};
+use tracing::Level;
+use tunnelbana_auth::{AuthGroup, AuthLayer, AuthParseErrorKind, Credential};
+
+use crate::{
+    access_log::LogFormat,
+    clean_urls::TrailingSlash,
+    compress::Encoding,
+    ip_filter::DeniedStatus,
+    listener::{ListenAddr, ListenSpec},
+    tls::{CertSource, SniCert},
+    vhost::{VhostConfig, normalize_host},
+};
+
+const ENV_PREFIX: &str = "TUNNELBANA_";
+/// Names the config file, so it isn't treated as a config key.
+pub const CONFIG_PATH_VAR: &str = "TUNNELBANA_CONFIG";
+const ENV_TABLE_SEPARATOR: &str = "__";
+
+#[cfg(debug_assertions)]
+const DEFAULT_LOG_LEVEL: Level = Level::TRACE;
+
+#[cfg(not(debug_assertions))]
+const DEFAULT_LOG_LEVEL: Level = Level::INFO;
+
+const DEFAULT_CACHE_CONTROL: &str = "no-transform";
+const DEFAULT_IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+const DEFAULT_FINGERPRINT_HASH_LENGTH: usize = 6;
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_CONNECTIONS: NonZeroUsize = NonZeroUsize::new(10_000).unwrap();
+const DEFAULT_HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_mins(2);
+const DEFAULT_MAX_CONCURRENT_STREAMS: u32 = 100;
+const DEFAULT_MAX_HEADER_SIZE: u32 = 16 * 1024;
+const DEFAULT_COMPRESSION_CACHE_SIZE: usize = 64 * 1024 * 1024;
+const DEFAULT_COMPRESSION_MIN_SIZE: u64 = 1024;
+const DEFAULT_COMPRESSION_MAX_SIZE: u64 = 8 * 1024 * 1024;
+const DEFAULT_PRELOAD_MAX_SIZE: u64 = 256 * 1024 * 1024;
+const DEFAULT_PRELOAD_MAX_FILE_SIZE: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// The whole configuration of the binary. Command line arguments take
+/// precedence over environment variables, which take precedence over the file.
+pub struct Config {
+    /// Directory to serve. With `vhosts`, it is served for unknown hosts
+    pub root: Option<PathBuf>,
+    /// Fall back to `index.html` rather than `404.html`
+    pub spa: bool,
+    /// Sites to serve by hostname, as `[[vhosts]]` tables
+    pub vhosts: Vec<VhostConfig>,
+    /// Host from `vhosts` whose site is served for unknown hosts, instead of `root`
+    pub default_host: Option<String>,
+    /// Addresses to listen on, in the same format as `--listen`
+    pub listen: Vec<ListenSpec>,
+    /// Worker threads to run. Defaults to the number of CPUs
+    pub workers: Option<NonZeroUsize>,
+    /// Give every worker its own runtime and `SO_REUSEPORT` sockets
+    pub reuse_port: bool,
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub log_level: Level,
+    /// How long to wait for connections to finish when shutting down, in seconds
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub shutdown_timeout: Duration,
+    pub connections: ConnectionsConfig,
+    /// Paths to hide, in addition to `/_headers`, `/_redirects` and `/_auth`
+    pub reserved_paths: Vec<String>,
+    /// `Cache-Control` header sent with every response which isn't for a fingerprinted file
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub cache_control: HeaderValue,
+    /// Cache files with a fingerprint in their name forever, if set
+    pub immutable: Option<ImmutableConfig>,
+    /// Serve `.html` files without their extension, if set
+    pub clean_urls: Option<CleanUrlsConfig>,
+    /// List directories without an `index.html`, if set
+    pub listing: Option<ListingConfig>,
+    /// Paths every site requires credentials for, as `[[auth]]` tables
+    #[serde(deserialize_with = "deserialize_auth")]
+    pub auth: AuthLayer,
+    /// Routes only some client addresses may request, if set
+    pub ip_filter: Option<IpFilterConfig>,
+    /// Limit how much each client may request, if set
+    pub rate_limit: Option<RateLimitConfig>,
+    pub precompressed: Precompressed,
+    /// Compress responses without a precompressed sibling, if set
+    pub compression: Option<CompressionConfig>,
+    /// Serve small files from memory, if set
+    pub preload: Option<PreloadConfig>,
+    pub tls: TlsConfig,
+    /// Serve HTTP/3, if set
+    pub http3: Option<Http3Config>,
+    pub proxy: ProxyConfig,
+    /// Log every request, if set
+    pub access_log: Option<AccessLogConfig>,
+    /// Serve Prometheus metrics, if set
+    pub metrics: Option<MetricsConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            root: None,
+            spa: false,
+            vhosts: Vec::new(),
+            default_host: None,
+            listen: Vec::new(),
+            workers: None,
+            reuse_port: false,
+            log_level: DEFAULT_LOG_LEVEL,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            connections: ConnectionsConfig::default(),
+            reserved_paths: Vec::new(),
+            cache_control: HeaderValue::from_static(DEFAULT_CACHE_CONTROL),
+            immutable: None,
+            clean_urls: None,
+            listing: None,
+            auth: AuthLayer::default(),
+            ip_filter: None,
+            rate_limit: None,
+            precompressed: Precompressed::default(),
+            compression: None,
+            preload: None,
+            tls: TlsConfig::default(),
+            http3: None,
+            proxy: ProxyConfig::default(),
+            access_log: None,
+            metrics: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionsConfig {
+    /// Most connections open at once, across every listener
+    pub max: NonZeroUsize,
+    /// How long a client has to send a request's headers, in seconds
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub header_read_timeout: Duration,
+    /// How long a connection may send and receive nothing before it's closed, in seconds
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub idle_timeout: Duration,
+    /// Most requests each HTTP/2 or HTTP/3 connection may have in flight at once
+    pub max_concurrent_streams: u32,
+    /// Most bytes of headers a request may have
+    pub max_header_size: u32,
+}
+
+impl Default for ConnectionsConfig {
+    fn default() -> Self {
+        Self {
+            max: DEFAULT_MAX_CONNECTIONS,
+            header_read_timeout: DEFAULT_HEADER_READ_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_concurrent_streams: DEFAULT_MAX_CONCURRENT_STREAMS,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[allow(clippy::struct_excessive_bools)]
+/// Which precompressed siblings (`.br`, `.gz`, `.zz`, `.zst`) are served.
+pub struct Precompressed {
+    pub br: bool,
+    pub gzip: bool,
+    pub deflate: bool,
+    pub zstd: bool,
+}
+
+impl Precompressed {
+    pub const fn enabled(self, encoding: Encoding) -> bool {
+        match encoding {
+            Encoding::Brotli => self.br,
+            Encoding::Zstd => self.zstd,
+            Encoding::Gzip => self.gzip,
+            Encoding::Deflate => self.deflate,
+        }
+    }
+}
+
+impl Default for Precompressed {
+    fn default() -> Self {
+        Self {
+            br: true,
+            gzip: true,
+            deflate: true,
+            zstd: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImmutableConfig {
+    /// `Cache-Control` header sent with fingerprinted files
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub cache_control: HeaderValue,
+    /// Pattern matched against file names to find fingerprints, instead of `hash_length`
+    #[serde(deserialize_with = "deserialize_some_from_str")]
+    pub pattern: Option<Regex>,
+    /// Shortest part of a file name, between `.`, `-` or `_`, which counts as a hash
+    pub hash_length: usize,
+}
+
+impl Default for ImmutableConfig {
+    fn default() -> Self {
+        Self {
+            cache_control: HeaderValue::from_static(DEFAULT_IMMUTABLE_CACHE_CONTROL),
+            pattern: None,
+            hash_length: DEFAULT_FINGERPRINT_HASH_LENGTH,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CleanUrlsConfig {
+    /// Whether canonical URLs end with a slash: `auto`, `add` or `remove`
+    pub trailing_slash: TrailingSlash,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListingConfig {
+    /// Path prefixes to list directories under, like `/releases`. Every directory if empty
+    pub paths: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpFilterConfig {
+    /// What denied requests get: `not_found` or `forbidden`
+    pub status: DeniedStatus,
+    /// Routes and who may request them, as `[[ip_filter.rules]]` tables
+    pub rules: Vec<IpFilterRule>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IpFilterRule {
+    /// Route pattern, like in `_auth`
+    pub path: String,
+    /// Networks allowed to request the route, as CIDRs. Every network if empty
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// Networks denied, even if they're in `allow`, as CIDRs
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Bytes a second each connection may be sent. Unlimited if unset
+    pub bandwidth: Option<NonZeroU32>,
+    /// Routes and how much each client may request them, as `[[rate_limit.rules]]` tables
+    pub rules: Vec<RateLimitRule>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitRule {
+    /// Route pattern, like in `_auth`
+    pub path: String,
+    /// Requests a second each client may make, on average
+    pub rate: Option<f64>,
+    /// Requests each client may make at once. Defaults to a second's worth
+    pub burst: Option<NonZeroU32>,
+    /// Responses each client may be sent at once
+    pub concurrent: Option<NonZeroU32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[allow(clippy::struct_field_names)]
+pub struct CompressionConfig {
+    /// Most bytes of compressed responses to keep in memory
+    pub cache_size: usize,
+    /// Smallest response to compress, in bytes
+    pub min_size: u64,
+    /// Largest response to compress, in bytes. Larger ones are sent uncompressed
+    pub max_size: u64,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            cache_size: DEFAULT_COMPRESSION_CACHE_SIZE,
+            min_size: DEFAULT_COMPRESSION_MIN_SIZE,
+            max_size: DEFAULT_COMPRESSION_MAX_SIZE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PreloadConfig {
+    /// Most bytes of files to keep in memory, for each site
+    pub max_size: u64,
+    /// Largest file to keep in memory, in bytes. Larger ones are read from disk
+    pub max_file_size: u64,
+}
+
+impl Default for PreloadConfig {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_PRELOAD_MAX_SIZE,
+            max_file_size: DEFAULT_PRELOAD_MAX_FILE_SIZE,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Default certificate chain, used when no SNI certificate matches
+    pub cert: Option<PathBuf>,
+    /// Private key for `cert`
+    pub key: Option<PathBuf>,
+    /// Certificates for specific hostnames, as `[[tls.sni]]` tables
+    pub sni: Vec<SniCert>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Http3Config {
+    /// UDP addresses to accept QUIC connections on, using the `[tls]` certificates
+    pub listen: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Expect a PROXY protocol v1 or v2 header on every connection to the listeners
+    pub protocol: bool,
+    /// Networks whose `Forwarded` and `X-Forwarded-For` headers are believed, as CIDRs
+    pub trusted: Vec<IpNet>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogConfig {
+    pub format: LogFormat,
+    /// File to append to, reopened on SIGHUP. Logs go to stdout if unset
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address to serve `/metrics` on, separate from the site
+    pub listen: ListenAddr,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SniTable {
+    host: String,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl<'de> Deserialize<'de> for SniCert {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let table = SniTable::deserialize(deserializer)?;
+        Ok(Self {
+            host: table.host.to_ascii_lowercase(),
+            source: CertSource {
+                cert: table.cert,
+                key: table.key,
+            },
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AuthTable {
+    /// Route pattern to protect, like in `_auth`
+    path: String,
+    /// Users, as htpasswd-style `name:hash` lines
+    #[serde(default)]
+    users: Vec<String>,
+    /// Static bearer tokens
+    #[serde(default)]
+    tokens: Vec<String>,
+}
+
+fn deserialize_auth<'de, D>(deserializer: D) -> Result<AuthLayer, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let tables = Vec::<AuthTable>::deserialize(deserializer)?;
+    let mut groups = Vec::with_capacity(tables.len());
+    for (idx, table) in tables.into_iter().enumerate() {
+        let credentials = table
+            .users
+            .iter()
+            .map(|user| Credential::user(user))
+            .chain(table.tokens.iter().map(|token| Credential::token(token)))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| D::Error::custom(format!("auth for `{}`: {e}", table.path)))?;
+        if credentials.is_empty() {
+            let e = AuthParseErrorKind::NoCredentials;
+            return Err(D::Error::custom(format!("auth for `{}`: {e}", table.path)));
+        }
+        groups.push(AuthGroup {
+            path: table.path,
+            credentials,
+            line: idx + 1,
+        });
+    }
+    AuthLayer::new(groups).map_err(D::Error::custom)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VhostTable {
+    hosts: Vec<String>,
+    root: PathBuf,
+    spa: Option<bool>,
+}
+
+impl<'de> Deserialize<'de> for VhostConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let table = VhostTable::deserialize(deserializer)?;
+        if table.hosts.is_empty() {
+            return Err(D::Error::custom("a vhost needs at least one host"));
+        }
+        Ok(Self {
+            hosts: table
+                .hosts
+                .iter()
+                .map(|host| normalize_host(host))
+                .collect(),
+            root: table.root,
+            spa: table.spa,
+        })
+    }
+}
+
+impl Config {
+    /// Load the config file at `path`, if any, and apply overrides from the environment.
+    /// # Errors
+    /// If the file can't be read, or it or the environment contain invalid keys or values.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let file = path
+            .map(|path| {
+                std::fs::read_to_string(path)
+                    .map(|text| (path, text))
+                    .map_err(|e| ConfigError::Read(path.to_path_buf(), e))
+            })
+            .transpose()?;
+        let file = file.as_ref().map(|(path, text)| (*path, text.as_str()));
+        Self::from_sources(file, std::env::vars())
+    }
+
+    fn from_sources(
+        file: Option<(&Path, &str)>,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let mut table = match file {
+            Some((path, text)) => {
+                // Deserializing straight from the text keeps line and column information in errors
+                toml::from_str::<Self>(text)
+                    .map_err(|e| ConfigError::File(path.to_path_buf(), e))?;
+                text.parse()
+                    .map_err(|e| ConfigError::File(path.to_path_buf(), e))?
+            }
+            None => Table::new(),
+        };
+        apply_env_overrides(&mut table, vars)?;
+        Self::deserialize(table).map_err(ConfigError::Env)
+    }
+
+    /// The TLS certificate used when no SNI certificate matches, if one is configured.
+    /// # Errors
+    /// If only one of the certificate and key are set.
+    pub fn default_cert(&self) -> Result<Option<CertSource>, ConfigError> {
+        match (&self.tls.cert, &self.tls.key) {
+            (Some(cert), Some(key)) => Ok(Some(CertSource {
+                cert: cert.clone(),
+                key: key.clone(),
+            })),
+            (None, None) => Ok(None),
+            _ => Err(ConfigError::IncompleteCert),
+        }
+    }
+}
+
+fn apply_env_overrides(
+    table: &mut Table,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<(), ConfigError> {
+    let keys = top_level_keys();
+    for (name, raw) in vars {
+        if name == CONFIG_PATH_VAR {
+            continue;
+        }
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let key = key.to_ascii_lowercase();
+        let mut path: Vec<&str> = key.split(ENV_TABLE_SEPARATOR).collect();
+        if !keys.contains(&path[0]) {
+            continue;
+        }
+        let Some(last) = path.pop() else {
+            continue;
+        };
+
+        let mut current = &mut *table;
+        for segment in path {
+            let entry = current
+                .entry(segment)
+                .or_insert_with(|| Value::Table(Table::new()));
+            let Value::Table(next) = entry else {
+                return Err(ConfigError::EnvNotTable(name));
+            };
+            current = next;
+        }
+
+        let value = raw.parse().unwrap_or(Value::String(raw));
+        current.insert(last.to_owned(), value);
+    }
+    Ok(())
+}
+
+/// The keys [`Config`] accepts, as listed by its derived `Deserialize`.
+fn top_level_keys() -> &'static [&'static str] {
+    let mut keys: &'static [&'static str] = &[];
+    _ = Config::deserialize(FieldNames(&mut keys));
+    keys
+}
+
+/// A deserializer which only records the field names of the struct asked for.
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de> Deserializer<'de> for FieldNames<'_> {
+    type Error = ValueError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(ValueError::custom("expected a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(ValueError::custom("only field names are read"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+fn deserialize_from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = String::deserialize(deserializer)?;
+    value.parse().map_err(D::Error::custom)
+}
+
+fn deserialize_some_from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    deserialize_from_str(deserializer).map(Some)
+}
+
+fn deserialize_seconds<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let seconds = f64::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(seconds).map_err(D::Error::custom)
+}
+
+impl<'de> Deserialize<'de> for ListenSpec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_from_str(deserializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_from_str(deserializer)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("could not read config file {0:?}: {1}")]
+    Read(PathBuf, IoError),
+    #[error("invalid config file {0:?}: {1}")]
+    File(PathBuf, toml::de::Error),
+    #[error("invalid config from environment variables: {0}")]
+    Env(toml::de::Error),
+    #[error("{0} sets a value inside a key which isn't a table")]
+    EnvNotTable(String),
+    #[error("the TLS certificate and key must be set together")]
+    IncompleteCert,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = r#"
+root = "/var/www/html"
+listen = ["[::]:80", "tls:[::]:443"]
+cache_control = "public, max-age=60"
+
+[connections]
+max = 2048
+idle_timeout = 75
+
+[immutable]
+pattern = '\.[0-9a-f]{6}\.'
+
+[clean_urls]
+trailing_slash = "remove"
+
+[listing]
+paths = ["/releases"]
+
+[[auth]]
+path = "/staging/*"
+users = ["alice:$2b$05$t81wjJH/ASZeb5OCiBhCp.6edItZqFwzqSlfQl466momz0gCT5lmq"]
+tokens = ["6f1a1d2c0e9b"]
+
+[ip_filter]
+status = "forbidden"
+
+[[ip_filter.rules]]
+path = "/admin/*"
+allow = ["10.8.0.0/16"]
+
+[rate_limit]
+bandwidth = 1048576
+
+[[rate_limit.rules]]
+path = "/releases/*"
+rate = 0.5
+concurrent = 2
+
+[precompressed]
+deflate = false
+
+[[tls.sni]]
+host = "Example.org"
+cert = "/etc/tls/example.org.pem"
+key = "/etc/tls/example.org.key"
+
+[http3]
+listen = ["[::]:443"]
+
+[proxy]
+trusted = ["10.0.0.0/8", "2001:db8::/32"]
+
+[[vhosts]]
+hosts = ["example.org", "WWW.example.org."]
+root = "/srv/example.org"
+spa = true
+"#;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn file_and_env() {
+        let env = vars(&[
+            ("TUNNELBANA_LOG_LEVEL", "warn"),
+            ("TUNNELBANA_SHUTDOWN_TIMEOUT", "1.5"),
+            ("TUNNELBANA_PRECOMPRESSED__BR", "false"),
+            ("TUNNELBANA_CONFIG", "/etc/tunnelbana.toml"),
+            ("HOME", "/root"),
+        ]);
+        let config =
+            Config::from_sources(Some((Path::new("tunnelbana.toml"), EXAMPLE)), env).unwrap();
+        assert_eq!(config.root, Some(PathBuf::from("/var/www/html")));
+        assert_eq!(config.listen.len(), 2);
+        assert!(config.listen[1].tls);
+        assert_eq!(config.cache_control, "public, max-age=60");
+        let immutable = config.immutable.unwrap();
+        assert!(immutable.pattern.unwrap().is_match("app.3f9a2c.js"));
+        assert_eq!(
+            immutable.cache_control,
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(
+            config.clean_urls.unwrap().trailing_slash,
+            TrailingSlash::Remove
+        );
+        assert_eq!(config.listing.unwrap().paths, ["/releases"]);
+        assert_eq!(
+            config.auth.matched("/staging").as_deref(),
+            Some("/staging/*")
+        );
+        let ip_filter = config.ip_filter.unwrap();
+        assert_eq!(ip_filter.status, DeniedStatus::Forbidden);
+        assert_eq!(ip_filter.rules[0].allow.len(), 1);
+        let rate_limit = config.rate_limit.unwrap();
+        assert_eq!(rate_limit.bandwidth, NonZeroU32::new(1_048_576));
+        assert_eq!(rate_limit.rules[0].rate, Some(0.5));
+        assert_eq!(rate_limit.rules[0].burst, None);
+        assert_eq!(config.log_level, Level::WARN);
+        assert_eq!(config.shutdown_timeout, Duration::from_millis(1500));
+        assert_eq!(config.connections.max.get(), 2048);
+        assert_eq!(config.connections.idle_timeout, Duration::from_secs(75));
+        assert_eq!(
+            config.connections.header_read_timeout,
+            DEFAULT_HEADER_READ_TIMEOUT
+        );
+        assert!(!config.precompressed.br);
+        assert!(!config.precompressed.deflate);
+        assert!(config.precompressed.gzip);
+        assert_eq!(config.tls.sni[0].host, "example.org");
+        assert_eq!(config.http3.unwrap().listen[0].port(), 443);
+        assert_eq!(config.proxy.trusted.len(), 2);
+        assert!(!config.proxy.protocol);
+        assert_eq!(config.vhosts[0].hosts, ["example.org", "www.example.org"]);
+        assert_eq!(config.vhosts[0].spa, Some(true));
+    }
+
+    #[test]
+    fn unknown_keys_have_locations() {
+        let file = "spa = true\n\n[tls]\ncertificate = \"/etc/cert.pem\"\n";
+        let err = Config::from_sources(Some((Path::new("tunnelbana.toml"), file)), Vec::new())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("line 4, column 1"), "{err}");
+        assert!(err.contains("certificate"), "{err}");
+
+        let env = vars(&[("TUNNELBANA_SPA__ENABLED", "true")]);
+        assert!(Config::from_sources(None, env).is_err());
+    }
+
+    #[test]
+    fn ignores_unrelated_env() {
+        let env = vars(&[
+            ("TUNNELBANA_SERVICE_HOST", "10.0.0.1"),
+            ("TUNNELBANA_PORT", "tcp://10.0.0.1:80"),
+            ("TUNNELBANA_PORT_80_TCP_ADDR", "10.0.0.1"),
+            ("TUNNELBANA_SPA", "true"),
+        ]);
+        let config = Config::from_sources(None, env).unwrap();
+        assert!(config.spa);
+    }
+}
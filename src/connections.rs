@@ -0,0 +1,340 @@
+//! Limits on client connections, so slow or numerous clients can't tie up
+//! the server: how many may be open, how long they may be idle, and how much
+//! they may send in request headers.
+use std::{
+    convert::Infallible,
+    future::{Ready, ready},
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::future::Either;
+use http::{Request, Response, StatusCode};
+use hyper_util::{
+    rt::{TokioExecutor, TokioTimer},
+    server::conn::auto::Builder as ConnBuilder,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{Instant, Sleep},
+};
+use tower::{Layer, Service};
+
+use crate::{
+    config::ConnectionsConfig,
+    listener::Connection,
+    memfs::empty_response,
+    site::{RequestBody, ResponseBody},
+};
+
+/// The smallest `max_header_size` hyper accepts, as it's the size of its read buffer.
+const MIN_HEADER_SIZE: u32 = 8192;
+
+#[derive(Clone)]
+/// The limits every connection is served with. Clones share their count of open connections.
+pub struct ConnectionLimits {
+    open: Arc<Semaphore>,
+    max: usize,
+    header_read_timeout: Duration,
+    idle_timeout: Duration,
+    max_concurrent_streams: u32,
+    max_header_size: u32,
+}
+
+impl ConnectionLimits {
+    /// # Errors
+    /// If `max_header_size` is too small for hyper.
+    pub fn new(config: &ConnectionsConfig) -> Result<Self, ConnectionLimitsError> {
+        if config.max_header_size < MIN_HEADER_SIZE {
+            return Err(ConnectionLimitsError::HeaderSize(config.max_header_size));
+        }
+        let max = config.max.get().min(Semaphore::MAX_PERMITS);
+        Ok(Self {
+            open: Arc::new(Semaphore::new(max)),
+            max,
+            header_read_timeout: config.header_read_timeout,
+            idle_timeout: config.idle_timeout,
+            max_concurrent_streams: config.max_concurrent_streams,
+            max_header_size: config.max_header_size,
+        })
+    }
+
+    /// Count a connection as open until the returned permit is dropped, or
+    /// return `None` if as many are open as may be.
+    pub fn open(&self) -> Option<OwnedSemaphorePermit> {
+        self.open.clone().try_acquire_owned().ok()
+    }
+
+    /// The most connections which may be open at once.
+    pub const fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Apply the header and stream limits to `builder`.
+    pub fn configure(&self, builder: &mut ConnBuilder<TokioExecutor>) {
+        // an idle HTTP/1 connection is waiting for the headers of its next
+        // request, so this also closes it once it's been idle for as long
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .header_read_timeout(self.header_read_timeout)
+            .max_buf_size(self.max_header_size as usize);
+        builder
+            .http2()
+            .timer(TokioTimer::new())
+            .max_concurrent_streams(self.max_concurrent_streams)
+            .max_header_list_size(self.max_header_size);
+    }
+
+    /// Whether `req` has more bytes of header names and values, and request
+    /// target, than may be sent.
+    pub fn headers_too_large<B>(&self, req: &Request<B>) -> bool {
+        let target = req
+            .uri()
+            .path_and_query()
+            .map_or(0, |target| target.as_str().len());
+        let headers: usize = req
+            .headers()
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        target + headers > self.max_header_size as usize
+    }
+
+    /// The layer which refuses requests with too many bytes of headers.
+    pub fn header_size_layer(&self) -> HeaderSizeLayer {
+        HeaderSizeLayer {
+            limits: self.clone(),
+        }
+    }
+
+    /// Close `stream` once it's been idle for too long.
+    pub fn idle_timeout(&self, stream: Connection) -> Connection {
+        Box::pin(IdleTimeout::new(stream, self.idle_timeout))
+    }
+
+    /// Apply the idle and stream limits to QUIC connections.
+    pub fn configure_quic(&self, config: &mut quinn::ServerConfig) {
+        let mut transport = quinn::TransportConfig::default();
+        transport
+            .max_concurrent_bidi_streams(self.max_concurrent_streams.into())
+            .max_idle_timeout(self.idle_timeout.try_into().ok());
+        config.transport_config(Arc::new(transport));
+    }
+
+    /// The most bytes of request headers an HTTP/3 client may send.
+    pub fn max_field_section_size(&self) -> u64 {
+        self.max_header_size.into()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectionLimitsError {
+    #[error("max_header_size is {0} bytes, but must be at least {MIN_HEADER_SIZE}")]
+    HeaderSize(u32),
+}
+
+#[derive(Clone, Copy, Debug)]
+/// Response extension marking a `431` for a request with too many bytes of headers.
+pub struct HeadersTooLarge;
+
+#[derive(Clone)]
+/// A [`tower::Layer`] which answers requests with more bytes of headers than
+/// may be sent with a `431`. hyper only stops reading headers once its buffer
+/// is full, which depends on how the client's bytes arrive, so this is what
+/// holds every request to the limit.
+pub struct HeaderSizeLayer {
+    limits: ConnectionLimits,
+}
+
+impl<S> Layer<S> for HeaderSizeLayer {
+    type Service = HeaderSize<S>;
+
+    fn layer(&self, inner: S) -> HeaderSize<S> {
+        HeaderSize {
+            limits: self.limits.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+/// A [`tower::Service`] which only passes requests with few enough bytes of
+/// headers to the service it wraps.
+pub struct HeaderSize<S> {
+    limits: ConnectionLimits,
+    inner: S,
+}
+
+impl<S> Service<Request<RequestBody>> for HeaderSize<S>
+where
+    S: Service<Request<RequestBody>, Response = Response<ResponseBody>, Error = Infallible>,
+{
+    type Error = Infallible;
+    type Future = Either<Ready<Result<Response<ResponseBody>, Infallible>>, S::Future>;
+    type Response = Response<ResponseBody>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<RequestBody>) -> Self::Future {
+        if !self.limits.headers_too_large(&req) {
+            return Either::Right(self.inner.call(req));
+        }
+        let mut response = empty_response(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        response.extensions_mut().insert(HeadersTooLarge);
+        Either::Left(ready(Ok(response)))
+    }
+}
+
+/// A connection which fails with [`IoErrorKind::TimedOut`] once nothing has
+/// been sent or received on it for a while, whether it's waiting for a
+/// request or a stalled client isn't reading its response.
+pub struct IdleTimeout<S> {
+    inner: S,
+    timeout: Duration,
+    active: Instant,
+    /// Woken at the earliest the connection could have timed out, and pushed
+    /// back when it has been active since, rather than on every read and write
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl<S> IdleTimeout<S> {
+    pub fn new(inner: S, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            active: Instant::now(),
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+        }
+    }
+
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Result<(), IoError> {
+        while self.sleep.as_mut().poll(cx).is_ready() {
+            let deadline = self.active + self.timeout;
+            if deadline <= Instant::now() {
+                return Err(IoError::new(
+                    IoErrorKind::TimedOut,
+                    "connection was idle for too long",
+                ));
+            }
+            self.sleep.as_mut().reset(deadline);
+        }
+        Ok(())
+    }
+
+    /// Note activity if `poll` is ready, or check for a timeout if it isn't.
+    fn track<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<Result<T, IoError>>,
+    ) -> Poll<Result<T, IoError>> {
+        if poll.is_ready() {
+            self.active = Instant::now();
+            return poll;
+        }
+        self.poll_idle(cx)?;
+        Poll::Pending
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeout<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), IoError>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.track(cx, poll)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, IoError>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.track(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.track(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn caps_open_connections() {
+        let config = ConnectionsConfig {
+            max: NonZeroUsize::new(2).unwrap(),
+            ..ConnectionsConfig::default()
+        };
+        let limits = ConnectionLimits::new(&config).unwrap();
+        let worker = limits.clone();
+        let first = limits.open().unwrap();
+        let second = worker.open().unwrap();
+        assert!(limits.open().is_none());
+        drop(first);
+        assert!(limits.open().is_some());
+        drop(second);
+
+        let tiny = ConnectionsConfig {
+            max_header_size: 1024,
+            ..ConnectionsConfig::default()
+        };
+        assert!(ConnectionLimits::new(&tiny).is_err());
+    }
+
+    #[test]
+    fn measures_headers() {
+        let limits = ConnectionLimits::new(&ConnectionsConfig::default()).unwrap();
+        let req = |cookie: usize| {
+            Request::get("/?q=1")
+                .header("cookie", "a".repeat(cookie))
+                .body(())
+                .unwrap()
+        };
+        // 5 bytes of target, 6 of name
+        assert!(!limits.headers_too_large(&req(16 * 1024 - 11)));
+        assert!(limits.headers_too_large(&req(16 * 1024 - 10)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_idle_connections() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut server = IdleTimeout::new(server, Duration::from_secs(10));
+        let mut buf = [0; 5];
+
+        tokio::time::sleep(Duration::from_secs(8)).await;
+        client.write_all(b"hello").await.unwrap();
+        server.read_exact(&mut buf).await.unwrap();
+        // the deadline moved back when the connection was active
+        tokio::time::sleep(Duration::from_secs(8)).await;
+        server.write_all(b"world").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+
+        let start = Instant::now();
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+    }
+}
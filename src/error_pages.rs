@@ -0,0 +1,329 @@
+//! Custom pages for error responses, like `404.html`, found in the requested
+//! directory or the nearest one above it, so a sub-site can have its own.
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use http::{HeaderValue, Method, Request, Response, StatusCode, header};
+use http_body_util::{BodyExt, Empty};
+use percent_encoding::percent_decode_str;
+use tower::{Layer, Service, ServiceExt, util::Oneshot};
+use tunnelbana_etags::ETagLayer;
+
+use crate::site::{RequestBody, ResponseBody, SiteService};
+
+/// Statuses which get a page, named after the status, like `410.html`.
+pub const STATUSES: [StatusCode; 4] = [
+    StatusCode::FORBIDDEN,
+    StatusCode::NOT_FOUND,
+    StatusCode::GONE,
+    StatusCode::INTERNAL_SERVER_ERROR,
+];
+
+/// The page for `status` nearest to `path`, which is percent-encoded, like
+/// `/docs/404.html` for `/docs/missing` if there is one, and `/404.html` if
+/// there isn't. Files are found with the site's etags, which cover every file.
+pub fn nearest(files: &ETagLayer, status: StatusCode, path: &str) -> Option<String> {
+    if !STATUSES.contains(&status) {
+        return None;
+    }
+    let name = format!("{}.html", status.as_u16());
+    let (mut dir, _) = path.rsplit_once('/')?;
+    loop {
+        let page = format!("{dir}/{name}");
+        let exists = percent_decode_str(&page)
+            .decode_utf8()
+            .is_ok_and(|page| files.contains(&page));
+        if exists {
+            return Some(page);
+        }
+        (dir, _) = dir.rsplit_once('/')?;
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Added to the extensions of every response given an error page.
+pub struct ErrorPage {
+    /// The page's path, like `/docs/404.html`
+    pub page: String,
+}
+
+#[derive(Clone)]
+/// A [`tower::Layer`] which gives error responses without a body of their
+/// own the site's page for their status, keeping their status and headers.
+pub struct ErrorPagesLayer {
+    files: ETagLayer,
+    /// Serves the pages, and sends something other than a success when there's no file
+    pages: SiteService,
+}
+
+impl ErrorPagesLayer {
+    pub const fn new(files: ETagLayer, pages: SiteService) -> Self {
+        Self { files, pages }
+    }
+}
+
+impl<S> Layer<S> for ErrorPagesLayer {
+    type Service = ErrorPages<S>;
+
+    fn layer(&self, inner: S) -> ErrorPages<S> {
+        ErrorPages {
+            layer: self.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+/// A [`tower::Service`] which adds error pages to the responses of the service it wraps.
+pub struct ErrorPages<S> {
+    layer: ErrorPagesLayer,
+    inner: S,
+}
+
+/// The parts of a request which are needed to serve it an error page.
+pub struct PageRequest {
+    path: String,
+    head: bool,
+    accept_encoding: Option<HeaderValue>,
+}
+
+impl<S> Service<Request<RequestBody>> for ErrorPages<S>
+where
+    S: Service<Request<RequestBody>, Response = Response<ResponseBody>, Error = Infallible>,
+{
+    type Error = Infallible;
+    type Future = ResponseFuture<S::Future>;
+    type Response = Response<ResponseBody>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<RequestBody>) -> Self::Future {
+        let request = PageRequest {
+            path: req.uri().path().to_owned(),
+            head: req.method() == Method::HEAD,
+            accept_encoding: req.headers().get(header::ACCEPT_ENCODING).cloned(),
+        };
+        ResponseFuture::Inner {
+            inner: self.inner.call(req),
+            request: Some((self.layer.clone(), request)),
+        }
+    }
+}
+
+#[pin_project::pin_project(project = ResponseFutureProj)]
+/// Future which waits for the inner response, then gives it an error page if it should have one.
+pub enum ResponseFuture<F> {
+    Inner {
+        #[pin]
+        inner: F,
+        request: Option<(ErrorPagesLayer, PageRequest)>,
+    },
+    /// Serving the error page at `page` for `response`. Boxed, since few
+    /// responses need a page.
+    Page {
+        served: Pin<Box<Oneshot<SiteService, Request<RequestBody>>>>,
+        page: Option<(String, Response<ResponseBody>)>,
+    },
+}
+
+impl<F> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<ResponseBody>, Infallible>>,
+{
+    type Output = Result<Response<ResponseBody>, Infallible>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let next = match self.as_mut().project() {
+            ResponseFutureProj::Inner { inner, request } => {
+                let Ok(response) = std::task::ready!(inner.poll(cx));
+                let Some((layer, request)) = request.take() else {
+                    return Poll::Ready(Ok(response));
+                };
+                // a response with a body, like a file, already says what went wrong
+                if response.headers().contains_key(header::CONTENT_TYPE) {
+                    return Poll::Ready(Ok(response));
+                }
+                let Some(page) = nearest(&layer.files, response.status(), &request.path) else {
+                    return Poll::Ready(Ok(response));
+                };
+                let Some(page_request) = page_request(&page, request) else {
+                    return Poll::Ready(Ok(response));
+                };
+                Self::Page {
+                    served: Box::pin(layer.pages.oneshot(page_request)),
+                    page: Some((page, response)),
+                }
+            }
+            ResponseFutureProj::Page { served, page } => {
+                let Ok(served) = std::task::ready!(served.as_mut().poll(cx));
+                let (page, response) = page.take().expect("error page polled after completion");
+                return Poll::Ready(Ok(with_page(served, page, response)));
+            }
+        };
+        self.set(next);
+        self.poll(cx)
+    }
+}
+
+/// The request for the page at `page`, made for `request`.
+fn page_request(page: &str, request: PageRequest) -> Option<Request<RequestBody>> {
+    // the page is always sent whole, so conditional headers aren't passed on
+    let method = if request.head {
+        Method::HEAD
+    } else {
+        Method::GET
+    };
+    let mut page_request = Request::builder().method(method).uri(page);
+    if let Some(accept_encoding) = request.accept_encoding {
+        page_request = page_request.header(header::ACCEPT_ENCODING, accept_encoding);
+    }
+    page_request
+        .body(RequestBody::new(Empty::new().map_err(|e| match e {})))
+        .ok()
+}
+
+/// `response` with the body of `served`, the page at `page`, or unchanged
+/// if the page couldn't be served.
+fn with_page(
+    served: Response<ResponseBody>,
+    page: String,
+    mut response: Response<ResponseBody>,
+) -> Response<ResponseBody> {
+    if !served.status().is_success() {
+        return response;
+    }
+
+    let (parts, body) = served.into_parts();
+    let headers = response.headers_mut();
+    for name in [
+        header::CONTENT_TYPE,
+        header::CONTENT_LENGTH,
+        header::CONTENT_ENCODING,
+    ] {
+        match parts.headers.get(&name) {
+            Some(value) => _ = headers.insert(name, value.clone()),
+            None => _ = headers.remove(name),
+        }
+    }
+    let varies = headers
+        .get_all(header::VARY)
+        .iter()
+        .any(|value| value.as_bytes().eq_ignore_ascii_case(b"accept-encoding"));
+    if !varies {
+        headers.append(
+            header::VARY,
+            HeaderValue::from_name(header::ACCEPT_ENCODING),
+        );
+    }
+    *response.body_mut() = body;
+    response.extensions_mut().insert(ErrorPage { page });
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use tunnelbana_etags::ETagMap;
+
+    use super::*;
+    use crate::memfs::empty_response;
+
+    fn files() -> ETagLayer {
+        let files = [
+            ("/404.html", &b""[..]),
+            ("/500.html", b""),
+            ("/docs/404.html", b""),
+            ("/my docs/410.html", b""),
+        ];
+        ETagLayer::new(ETagMap::from_files(files, false).unwrap())
+    }
+
+    #[test]
+    fn finds_nearest_page() {
+        let files = files();
+        let nearest = |status, path| nearest(&files, status, path);
+        assert_eq!(
+            nearest(StatusCode::NOT_FOUND, "/docs/guide/missing").as_deref(),
+            Some("/docs/404.html")
+        );
+        assert_eq!(
+            nearest(StatusCode::NOT_FOUND, "/docs/").as_deref(),
+            Some("/docs/404.html")
+        );
+        assert_eq!(
+            nearest(StatusCode::NOT_FOUND, "/docs").as_deref(),
+            Some("/404.html")
+        );
+        assert_eq!(
+            nearest(StatusCode::GONE, "/my%20docs/old").as_deref(),
+            Some("/my%20docs/410.html")
+        );
+        assert_eq!(nearest(StatusCode::GONE, "/old"), None);
+        assert_eq!(nearest(StatusCode::FORBIDDEN, "/docs/secret"), None);
+        assert_eq!(nearest(StatusCode::BAD_REQUEST, "/docs/missing"), None);
+    }
+
+    #[tokio::test]
+    async fn fills_in_empty_error_responses() {
+        let pages = SiteService::new(tower::service_fn(|req: Request<RequestBody>| async move {
+            let mut response = Response::new(ResponseBody::new(
+                Full::new(Bytes::from(req.uri().to_string())).map_err(|e| match e {}),
+            ));
+            response
+                .headers_mut()
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html"));
+            Ok::<_, Infallible>(response)
+        }));
+        let svc = ErrorPagesLayer::new(files(), pages).layer(tower::service_fn(
+            |req: Request<RequestBody>| async move {
+                let status = match req.uri().path() {
+                    "/broken" => StatusCode::INTERNAL_SERVER_ERROR,
+                    "/forbidden" => StatusCode::FORBIDDEN,
+                    _ => StatusCode::NOT_FOUND,
+                };
+                let mut response = empty_response(status);
+                response
+                    .headers_mut()
+                    .insert("x-original", HeaderValue::from_static("yes"));
+                Ok::<_, Infallible>(response)
+            },
+        ));
+        let get = |path: &'static str| {
+            let svc = svc.clone();
+            async move {
+                let req = Request::get(path)
+                    .body(RequestBody::new(Empty::new().map_err(|e| match e {})))
+                    .unwrap();
+                let response = svc.oneshot(req).await.unwrap();
+                let status = response.status();
+                let original = response.headers().contains_key("x-original");
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                (status, original, body)
+            }
+        };
+
+        assert_eq!(
+            get("/docs/missing").await,
+            (StatusCode::NOT_FOUND, true, Bytes::from("/docs/404.html"))
+        );
+        assert_eq!(
+            get("/broken").await,
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                true,
+                Bytes::from("/500.html")
+            )
+        );
+        assert_eq!(
+            get("/forbidden").await,
+            (StatusCode::FORBIDDEN, true, Bytes::new())
+        );
+    }
+}
@@ -0,0 +1,458 @@
+//! `tunnelbana explain`, which sends one request through a site's real
+//! service and shows how each layer handled it.
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult, Write},
+    net::IpAddr,
+};
+
+use argh::FromArgs;
+use http::{HeaderName, HeaderValue, Method, Request, Response, header};
+use http_body_util::{BodyExt, Empty};
+use percent_encoding::percent_decode_str;
+use tower::ServiceExt;
+use tunnelbana_auth::{Authenticated, Unauthenticated};
+use tunnelbana_hidepaths::Hidden;
+use tunnelbana_redirects::Redirected;
+
+use crate::{
+    clean_urls::Resolution,
+    compress::Encoding,
+    error_pages::ErrorPage,
+    ip_filter::Denied,
+    listing::Listed,
+    proxy::ClientAddr,
+    site::{RequestBody, ResponseBody, ServeOptions, Site, SiteService},
+};
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "explain")]
+/// Show how a request would be handled, step by step, without serving anything
+pub struct ExplainArgs {
+    /// path to request, such as `/blog/post?page=2`
+    #[argh(positional)]
+    pub path: String,
+
+    /// request header, as `NAME: VALUE`, such as `Accept-Encoding: br`. May be repeated
+    #[argh(option)]
+    pub header: Vec<String>,
+
+    /// request method. Defaults to GET
+    #[argh(option, default = "Method::GET")]
+    pub method: Method,
+
+    /// address the request comes from, for `[ip_filter]` rules. Defaults to none
+    #[argh(option)]
+    pub client: Option<IpAddr>,
+}
+
+impl ExplainArgs {
+    /// The request these arguments describe.
+    /// # Errors
+    /// If a header isn't written as `NAME: VALUE`, or the path isn't a valid URI.
+    pub fn request(&self) -> Result<Request<RequestBody>, ExplainError> {
+        let mut request = Request::builder()
+            .method(self.method.clone())
+            .uri(&self.path);
+        for line in &self.header {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| ExplainError::Header(line.clone()))?;
+            let name = HeaderName::try_from(name.trim())
+                .map_err(|_| ExplainError::Header(line.clone()))?;
+            let value = HeaderValue::try_from(value.trim())
+                .map_err(|_| ExplainError::Header(line.clone()))?;
+            request = request.header(name, value);
+        }
+        let body = RequestBody::new(Empty::new().map_err(|e| match e {}));
+        let mut request = request.body(body)?;
+        request.extensions_mut().insert(ClientAddr(self.client));
+        Ok(request)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExplainError {
+    #[error("`{0}` should be written as NAME: VALUE")]
+    Header(String),
+    #[error("{0}")]
+    Request(#[from] http::Error),
+}
+
+#[derive(Debug, Default)]
+/// What happened to a request at each step, one line per step.
+pub struct Explanation {
+    lines: Vec<String>,
+}
+
+impl Explanation {
+    fn line(&mut self, line: impl Into<String>) {
+        self.lines.push(line.into());
+    }
+}
+
+impl Display for Explanation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        for line in &self.lines {
+            writeln!(f, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Send `request` through `service`, and explain the response using `site`,
+/// the site the request's host is routed to, if any.
+pub async fn explain(
+    site: Option<(&Site, &ServeOptions)>,
+    service: SiteService,
+    request: Request<RequestBody>,
+) -> Explanation {
+    let mut explanation = Explanation::default();
+    explanation.line(format!("{} {}", request.method(), request.uri()));
+    let path = request.uri().path().to_owned();
+    let method = request.method().clone();
+    let Ok(response) = service.oneshot(request).await;
+
+    if let Some((site, options)) = site {
+        explanation.line(format!("site: {}", site.root().display()));
+        explain_site(&mut explanation, site, options, &method, &path, &response);
+        if let Some(error_page) = response.extensions().get::<ErrorPage>() {
+            explanation.line(format!("error page: {}", error_page.page));
+        }
+    } else {
+        explanation.line("site: none is served for this host");
+    }
+
+    explanation.line(format!("response: {}", response.status()));
+    for (name, value) in response.headers() {
+        explanation.line(format!(
+            "  {name}: {}",
+            String::from_utf8_lossy(value.as_bytes())
+        ));
+    }
+    explanation
+}
+
+fn explain_site(
+    explanation: &mut Explanation,
+    site: &Site,
+    options: &ServeOptions,
+    method: &Method,
+    path: &str,
+    response: &Response<ResponseBody>,
+) {
+    if !explain_access(explanation, response) {
+        return;
+    }
+
+    explain_headers(explanation, site, path);
+    if !explain_method(explanation, method) {
+        return;
+    }
+
+    match site.redirects().matched(path) {
+        Some(matched) => {
+            let target = matched.location.map_or_else(
+                || "a 500, since the target isn't a valid `Location`".to_owned(),
+                |location| {
+                    format!(
+                        "a {} to {}",
+                        matched.code.as_u16(),
+                        String::from_utf8_lossy(location.as_bytes())
+                    )
+                },
+            );
+            explanation.line(format!(
+                "_redirects: `{}` matches{}, sending {target}",
+                matched.rule,
+                captures(&matched.captures)
+            ));
+        }
+        None => explanation.line("_redirects: no rule matches"),
+    }
+    if response.extensions().get::<Redirected>().is_some() {
+        return;
+    }
+
+    let path = match site.clean_urls(options).resolve(path) {
+        Resolution::Serve => path.to_owned(),
+        Resolution::Rewrite(file) => {
+            explanation.line(format!("clean URLs: served from {file}"));
+            file
+        }
+        Resolution::Redirect(canonical) => {
+            explanation.line(format!(
+                "clean URLs: not canonical, so it redirects to {canonical}"
+            ));
+            return;
+        }
+    };
+    let path = path.as_str();
+
+    if response.extensions().get::<Hidden>().is_some() {
+        explanation.line("hidden: yes, so it's served as if it weren't there");
+        return;
+    }
+    explanation.line("hidden: no");
+
+    if response.extensions().get::<Listed>().is_some() {
+        explanation.line("file: none, so the directory is listed");
+        return;
+    }
+
+    let file = match file_for(site, path) {
+        FileMatch::File(key) => key,
+        FileMatch::Directory(key) => {
+            explanation.line(format!(
+                "file: {key} is a directory, so it redirects to {key}/"
+            ));
+            return;
+        }
+        FileMatch::None if options.spa => {
+            explanation.line("file: none, so /index.html is served instead");
+            return;
+        }
+        FileMatch::None => {
+            explanation.line("file: none");
+            return;
+        }
+    };
+    let encoding = response
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .and_then(|value| Encoding::ALL.into_iter().find(|e| value == e.name()));
+    let Some(encoding) = encoding else {
+        explanation.line(format!("file: {file}"));
+        return;
+    };
+    let sibling = format!("{file}{}", encoding.extension());
+    if options.precompressed.enabled(encoding) && site.has_file(&sibling) {
+        explanation.line(format!("file: {file}, precompressed as {sibling}"));
+    } else {
+        explanation.line(format!(
+            "file: {file}, compressed on the fly with {}",
+            encoding.name()
+        ));
+    }
+}
+
+/// Explain whether the IP filter denied the request, and which auth group
+/// protected it, if any, returning whether the request got past both.
+fn explain_access(explanation: &mut Explanation, response: &Response<ResponseBody>) -> bool {
+    if let Some(denied) = response.extensions().get::<Denied>() {
+        explanation.line(format!(
+            "ip filter: `{}` doesn't allow this client, so it's denied",
+            denied.route
+        ));
+        return false;
+    }
+    if let Some(unauthenticated) = response.extensions().get::<Unauthenticated>() {
+        explanation.line(format!(
+            "auth: `{}` matches, and the request has no valid credentials",
+            unauthenticated.rule
+        ));
+        return false;
+    }
+    match response.extensions().get::<Authenticated>() {
+        Some(Authenticated {
+            rule,
+            user: Some(user),
+        }) => explanation.line(format!("auth: `{rule}` matches, signed in as {user}")),
+        Some(Authenticated { rule, user: None }) => {
+            explanation.line(format!("auth: `{rule}` matches, with a bearer token"));
+        }
+        None => explanation.line("auth: no group matches"),
+    }
+    true
+}
+
+/// Explain which `_headers` group matches `path`, and what it sets.
+fn explain_headers(explanation: &mut Explanation, site: &Site, path: &str) {
+    let Some(matched) = site.headers().matched(path) else {
+        explanation.line("_headers: no group matches");
+        return;
+    };
+    explanation.line(format!(
+        "_headers: `{}` matches{}",
+        matched.rule,
+        captures(&matched.captures)
+    ));
+    for (name, value) in &matched.headers {
+        explanation.line(format!(
+            "  {name}: {}",
+            String::from_utf8_lossy(value.as_bytes())
+        ));
+    }
+}
+
+/// Explain how the methods layer handled `method`, returning whether it was
+/// passed on to be served.
+fn explain_method(explanation: &mut Explanation, method: &Method) -> bool {
+    match *method {
+        Method::GET => true,
+        Method::HEAD => {
+            explanation.line("method: HEAD, so it's served as a GET without the body");
+            true
+        }
+        Method::OPTIONS => {
+            explanation.line("method: OPTIONS, so it's answered with the allowed methods");
+            false
+        }
+        _ => {
+            explanation.line(format!("method: {method} isn't allowed, so it gets a 405"));
+            false
+        }
+    }
+}
+
+/// `, capturing a = b, c = d`, or nothing if there are no captures.
+fn captures(captures: &[(String, String)]) -> String {
+    let mut out = String::new();
+    for (i, (key, value)) in captures.iter().enumerate() {
+        let sep = if i == 0 { ", capturing" } else { "," };
+        let _ = write!(out, "{sep} {key} = {value}");
+    }
+    out
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum FileMatch {
+    /// The file at this key is served
+    File(String),
+    /// This key is a directory, which is redirected to with a trailing slash
+    Directory(String),
+    None,
+}
+
+/// Which file the site's file service picks for `path`, the way `ServeDir` does.
+fn file_for(site: &Site, path: &str) -> FileMatch {
+    let Ok(key) = percent_decode_str(path).decode_utf8() else {
+        return FileMatch::None;
+    };
+    if key.split('/').any(|segment| segment == "..") {
+        return FileMatch::None;
+    }
+    let index = format!("{}/index.html", key.trim_end_matches('/'));
+    if key.ends_with('/') {
+        if site.has_file(&index) {
+            return FileMatch::File(index);
+        }
+    } else if site.has_file(&key) {
+        return FileMatch::File(key.into_owned());
+    } else if site.has_file(&index) {
+        return FileMatch::Directory(key.into_owned());
+    }
+    FileMatch::None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clean_urls::TrailingSlash;
+
+    #[tokio::test]
+    async fn explains_each_layer() {
+        let root = std::env::temp_dir().join(format!("tunnelbana-explain-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("blog/post")).unwrap();
+        std::fs::write(root.join("blog/post/index.html"), "post").unwrap();
+        std::fs::write(root.join("blog/post/index.html.br"), "br").unwrap();
+        std::fs::write(root.join("_headers"), "/blog/*\n  X-Blog: yes\n").unwrap();
+        std::fs::write(root.join("_redirects"), "/old/{slug} /blog/{slug}/ 301\n").unwrap();
+        std::fs::write(root.join("_auth"), "/drafts/*\n  Bearer s3cret\n").unwrap();
+        let site = Site::load(root.clone(), false, None).unwrap();
+        let options = ServeOptions::from(&crate::config::Config::default());
+        let service = site.service(&options).unwrap();
+
+        let args = ExplainArgs {
+            path: "/blog/post/".to_owned(),
+            header: vec!["Accept-Encoding: br".to_owned()],
+            method: Method::GET,
+            client: None,
+        };
+        let explanation = explain(
+            Some((&site, &options)),
+            service.clone(),
+            args.request().unwrap(),
+        )
+        .await;
+        let text = explanation.to_string();
+        assert!(
+            text.contains(
+                "_headers: `/blog/{*all}` matches, capturing all = post/\n  x-blog: yes\n"
+            )
+        );
+        assert!(text.contains("_redirects: no rule matches\nhidden: no\n"));
+        assert!(
+            text.contains(
+                "file: /blog/post/index.html, precompressed as /blog/post/index.html.br\n"
+            )
+        );
+        assert!(text.contains("response: 200 OK\n"));
+
+        let args = ExplainArgs {
+            path: "/drafts/".to_owned(),
+            header: Vec::new(),
+            method: Method::GET,
+            client: None,
+        };
+        let explanation = explain(
+            Some((&site, &options)),
+            service.clone(),
+            args.request().unwrap(),
+        )
+        .await;
+        assert!(explanation.to_string().contains(
+            "auth: `/drafts/*` matches, and the request has no valid credentials\nresponse: 401"
+        ));
+
+        let args = ExplainArgs {
+            path: "/blog/post/".to_owned(),
+            header: Vec::new(),
+            method: Method::DELETE,
+            client: None,
+        };
+        let explanation = explain(
+            Some((&site, &options)),
+            service.clone(),
+            args.request().unwrap(),
+        )
+        .await;
+        let text = explanation.to_string();
+        assert!(text.contains(
+            "method: DELETE isn't allowed, so it gets a 405\nresponse: 405 Method Not Allowed\n"
+        ));
+        // `_headers` still apply to a 405
+        let (_, response) = text.split_once("response:").unwrap();
+        assert!(response.contains("  allow: GET, HEAD, OPTIONS\n"));
+        assert!(response.contains("  x-blog: yes\n"));
+
+        let args = ExplainArgs {
+            path: "/old/post".to_owned(),
+            header: Vec::new(),
+            method: Method::GET,
+            client: None,
+        };
+        let explanation = explain(Some((&site, &options)), service, args.request().unwrap()).await;
+        assert!(explanation.to_string().contains(
+            "_redirects: `/old/{slug}` matches, capturing slug = post, sending a 301 to /blog/post/\nresponse: 301"
+        ));
+
+        let options = ServeOptions {
+            clean_urls: Some(TrailingSlash::Auto),
+            ..options
+        };
+        let service = site.service(&options).unwrap();
+        let args = ExplainArgs {
+            path: "/blog/post/index.html".to_owned(),
+            header: Vec::new(),
+            method: Method::GET,
+            client: None,
+        };
+        let explanation = explain(Some((&site, &options)), service, args.request().unwrap()).await;
+        assert!(
+            explanation.to_string().contains(
+                "clean URLs: not canonical, so it redirects to /blog/post/\nresponse: 308"
+            )
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+//! Serving HTTP/3 over QUIC, with the same service as the TCP listeners.
+use std::{io::Error as IoError, net::SocketAddr, pin::pin, sync::Arc};
+
+use bytes::Bytes;
+use futures_util::future::Either;
+use h3::server::RequestResolver;
+use http::HeaderValue;
+use http_body_util::{BodyExt, Empty};
+use quinn::{
+    Endpoint,
+    crypto::rustls::{NoInitialCipherSuite, QuicServerConfig},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tower::{BoxError, Service};
+
+use crate::{
+    connections::ConnectionLimits,
+    listener::PeerAddr,
+    metrics::Metrics,
+    server::Server,
+    site::{RequestBody, SiteService},
+    tls::CertStore,
+};
+
+/// How long browsers may remember that HTTP/3 is available, in seconds.
+const ALT_SVC_MAX_AGE: u32 = 86400;
+
+/// Build the QUIC endpoint config, with certificates from `certs` and the
+/// idle and stream limits from `limits`.
+/// # Errors
+/// If the crypto provider doesn't support TLS 1.3 or QUIC's initial cipher suite.
+pub fn server_config(
+    certs: &Arc<CertStore>,
+    limits: &ConnectionLimits,
+) -> Result<quinn::ServerConfig, QuicConfigError> {
+    let crypto = QuicServerConfig::try_from(certs.quic_config()?)?;
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    limits.configure_quic(&mut config);
+    Ok(config)
+}
+
+/// Bind a QUIC endpoint to `addr`.
+///
+/// This must be called from inside the runtime which will serve it.
+/// # Errors
+/// If the UDP socket can't be bound.
+pub fn bind(addr: SocketAddr, config: quinn::ServerConfig) -> Result<Endpoint, IoError> {
+    Endpoint::server(config, addr)
+}
+
+/// The `Alt-Svc` header which tells clients of the TCP listeners that
+/// HTTP/3 is served on each of `addrs`, or `None` if there are none.
+pub fn alt_svc(addrs: &[SocketAddr]) -> Option<HeaderValue> {
+    let mut ports: Vec<u16> = addrs.iter().map(SocketAddr::port).collect();
+    ports.sort_unstable();
+    ports.dedup();
+    let value = ports
+        .iter()
+        .map(|port| format!("h3=\":{port}\"; ma={ALT_SVC_MAX_AGE}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    HeaderValue::try_from(value)
+        .ok()
+        .filter(|_| !ports.is_empty())
+}
+
+/// Serve connections from `endpoint` until `shutdown` is cancelled, then ask
+/// clients to finish their requests and wait up to the server's shutdown
+/// timeout for them.
+pub async fn serve(server: Server, endpoint: Endpoint, shutdown: CancellationToken) {
+    let tasks = TaskTracker::new();
+    let mut cancelled = pin!(shutdown.cancelled());
+    loop {
+        let next_conn = pin!(endpoint.accept());
+        let incoming = match futures_util::future::select(next_conn, cancelled.as_mut()).await {
+            Either::Left((Some(incoming), _)) => incoming,
+            Either::Left((None, _)) => {
+                error!("HTTP/3 endpoint closed");
+                break;
+            }
+            Either::Right(_) => break,
+        };
+        let peer = PeerAddr::Quic(incoming.remote_address());
+        let Some(permit) = server.limits.open() else {
+            warn!(
+                "refusing QUIC connection from {}: {} are already open",
+                peer,
+                server.limits.max()
+            );
+            if let Some(metrics) = &server.metrics {
+                metrics.connection_refused();
+            }
+            incoming.refuse();
+            continue;
+        };
+        info!("incoming QUIC connection accepted: {}", peer);
+        let service = server.service.clone();
+        let metrics = server.metrics.clone();
+        let shutdown = shutdown.clone();
+        let max_field_section_size = server.limits.max_field_section_size();
+        tasks.spawn(async move {
+            let _permit = permit;
+            let _connection = metrics.as_ref().map(Metrics::connection_opened);
+            match incoming.await {
+                Ok(conn) => {
+                    let peer = peer.clone();
+                    serve_connection(conn, service, peer, max_field_section_size, shutdown).await;
+                }
+                Err(err) => debug!("QUIC handshake with {} failed: {}", peer, err),
+            }
+            debug!("QUIC connection dropped: {}", peer);
+        });
+    }
+
+    // refuse new connections, but keep serving the open ones
+    endpoint.set_server_config(None);
+    tasks.close();
+    let timeout = server.shutdown_timeout;
+    if tokio::time::timeout(timeout, tasks.wait()).await.is_ok() {
+        info!("HTTP/3 gracefully shutdown!");
+    } else {
+        error!(
+            ?timeout,
+            "Timed out waiting for HTTP/3 graceful shutdown, aborting..."
+        );
+    }
+    endpoint.close(0u32.into(), b"shutting down");
+}
+
+/// Serve requests on `conn` until the client closes it, or `shutdown` is
+/// cancelled and the requests in flight have finished.
+async fn serve_connection(
+    conn: quinn::Connection,
+    service: SiteService,
+    peer: PeerAddr,
+    max_field_section_size: u64,
+    shutdown: CancellationToken,
+) {
+    let conn = h3::server::builder()
+        .max_field_section_size(max_field_section_size)
+        .build(h3_quinn::Connection::new(conn))
+        .await;
+    let mut conn = match conn {
+        Ok(conn) => conn,
+        Err(err) => {
+            debug!("HTTP/3 setup with {} failed: {}", peer, err);
+            return;
+        }
+    };
+    let mut cancelled = pin!(shutdown.cancelled());
+    let mut shutting_down = false;
+    loop {
+        let accepted = if shutting_down {
+            Some(conn.accept().await)
+        } else {
+            match futures_util::future::select(pin!(conn.accept()), cancelled.as_mut()).await {
+                Either::Left((accepted, _)) => Some(accepted),
+                Either::Right(_) => None,
+            }
+        };
+        let resolver = match accepted {
+            Some(Ok(Some(resolver))) => resolver,
+            // the client has closed the connection, or shutdown has finished
+            Some(Ok(None)) => break,
+            Some(Err(err)) => {
+                if !err.is_h3_no_error() {
+                    debug!("HTTP/3 connection error from {}: {}", peer, err);
+                }
+                break;
+            }
+            None => {
+                // GOAWAY, after which `accept` returns the requests in flight, then `None`
+                shutting_down = true;
+                if let Err(err) = conn.shutdown(0).await {
+                    debug!("HTTP/3 shutdown with {} failed: {}", peer, err);
+                    break;
+                }
+                continue;
+            }
+        };
+        let service = service.clone();
+        let peer = peer.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_request(resolver, service, peer.clone()).await {
+                debug!("HTTP/3 request from {} failed: {}", peer, err);
+            }
+        });
+    }
+}
+
+async fn serve_request(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    mut service: SiteService,
+    peer: PeerAddr,
+) -> Result<(), BoxError> {
+    let (req, mut stream) = resolver.resolve_request().await?;
+    // static files never need the request body, so it isn't read
+    let mut req = req.map(|()| RequestBody::new(Empty::new().map_err(|e| match e {})));
+    req.extensions_mut().insert(peer);
+
+    let Ok(()) = std::future::poll_fn(|cx| service.poll_ready(cx)).await;
+    let Ok(response) = service.call(req).await;
+    let (parts, mut body) = response.into_parts();
+    stream
+        .send_response(http::Response::from_parts(parts, ()))
+        .await?;
+    while let Some(frame) = body.frame().await {
+        match frame?.into_data() {
+            Ok(data) => stream.send_data(data).await?,
+            Err(frame) => {
+                if let Ok(trailers) = frame.into_trailers() {
+                    stream.send_trailers(trailers).await?;
+                }
+            }
+        }
+    }
+    stream.finish().await?;
+    Ok(())
+}
+
+#[derive(Debug, thiserror::Error)]
+/// Errors from building the QUIC endpoint config.
+pub enum QuicConfigError {
+    #[error("invalid TLS config for QUIC: {0}")]
+    Tls(#[from] rustls::Error),
+    #[error("{0}")]
+    CipherSuite(#[from] NoInitialCipherSuite),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advertises_each_port_once() {
+        let addrs: Vec<SocketAddr> = vec![
+            "[::]:443".parse().unwrap(),
+            "0.0.0.0:443".parse().unwrap(),
+            "127.0.0.1:8443".parse().unwrap(),
+        ];
+        assert_eq!(
+            alt_svc(&addrs).unwrap(),
+            "h3=\":443\"; ma=86400, h3=\":8443\"; ma=86400"
+        );
+        assert!(alt_svc(&[]).is_none());
+    }
+}
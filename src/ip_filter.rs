@@ -0,0 +1,347 @@
+//! Allowing or denying requests for routes by the client's address, like
+//! keeping `/admin` to an office VPN, or a whole site to one network while
+//! it's under embargo.
+use std::{
+    convert::Infallible,
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use http::{Request, Response, StatusCode};
+use ipnet::IpNet;
+use matchit::{InsertError, Router};
+use serde::Deserialize;
+use tower::{Layer, Service, ServiceExt, util::Oneshot};
+use tunnelbana_auth::ServedPath;
+
+use crate::{
+    memfs::empty_response,
+    proxy::ClientAddr,
+    site::{RequestBody, ResponseBody, SiteService},
+};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+/// The status denied requests get.
+pub enum DeniedStatus {
+    /// As if the route didn't exist
+    #[default]
+    NotFound,
+    Forbidden,
+}
+
+impl DeniedStatus {
+    pub const fn status(self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+/// Which clients may request a route.
+pub struct IpRule {
+    /// Only these networks are allowed, unless it's empty
+    pub allow: Vec<IpNet>,
+    /// These networks are denied, even if they're also allowed
+    pub deny: Vec<IpNet>,
+}
+
+impl IpRule {
+    /// Whether `client` may request the route. A client without an address,
+    /// like one on a unix socket, is only allowed if every network is.
+    pub fn allows(&self, client: Option<IpAddr>) -> bool {
+        let Some(client) = client else {
+            return self.allow.is_empty();
+        };
+        // IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6
+        let client = client.to_canonical();
+        let contains = |nets: &[IpNet]| nets.iter().any(|net| net.contains(&client));
+        !contains(&self.deny) && (self.allow.is_empty() || contains(&self.allow))
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Added to the extensions of every response for a request which was denied.
+pub struct Denied {
+    /// The route which denied it, as passed to [`IpFilterLayerBuilder::rule`]
+    pub route: Arc<str>,
+}
+
+struct Rule {
+    route: Arc<str>,
+    rule: IpRule,
+}
+
+/// Build a [`matchit::Router`] of routes which only some clients may request.
+///
+/// The denied service defaults to an empty 404, and can be replaced with
+/// [`Self::with_denied_service`].
+pub struct IpFilterLayerBuilder {
+    rules: Router<Arc<Rule>>,
+    denied: SiteService,
+    errors: Vec<(String, InsertError)>,
+}
+
+impl IpFilterLayerBuilder {
+    /// Use a different service for denied requests than an empty 404.
+    #[must_use]
+    pub fn with_denied_service(self, denied: SiteService) -> Self {
+        Self { denied, ..self }
+    }
+
+    /// Only let clients `rule` allows request `route`, which is a [`matchit`]
+    /// route. Like in `_auth`, a trailing `*` also covers the path above it.
+    #[must_use]
+    pub fn rule(mut self, route: impl Into<String>, rule: IpRule) -> Self {
+        let route = route.into();
+        let value = Arc::new(Rule {
+            route: route.as_str().into(),
+            rule,
+        });
+        for path in tunnelbana_auth::routes(&route) {
+            if let Err(err) = self.rules.insert(path, value.clone()) {
+                self.errors.push((route.clone(), err));
+            }
+        }
+        self
+    }
+
+    /// Convenience method for calling [`Self::rule`] in a loop.
+    #[must_use]
+    pub fn rules<R: Into<String>>(mut self, rules: impl IntoIterator<Item = (R, IpRule)>) -> Self {
+        for (route, rule) in rules {
+            self = self.rule(route, rule);
+        }
+        self
+    }
+
+    /// Build this [`IpFilterLayer`].
+    /// # Errors
+    /// If a route couldn't be inserted into the router. Only the first error is returned.
+    pub fn build(self) -> Result<IpFilterLayer, IpFilterError> {
+        if let Some((route, err)) = self.errors.into_iter().next() {
+            return Err(IpFilterError(route, err));
+        }
+        Ok(IpFilterLayer {
+            rules: Arc::new(self.rules),
+            denied: self.denied,
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Could not filter `{0}`: {1}")]
+pub struct IpFilterError(String, InsertError);
+
+#[derive(Clone)]
+/// A [`tower::Layer`] which sends requests for a route from clients its rule
+/// doesn't allow to the denied service. Clients are identified by the
+/// [`ClientAddr`] extension, so trusted proxies are looked through, and
+/// routes are matched against [`tunnelbana_auth::route_path`] and the
+/// [`ServedPath`], like `_auth`.
+pub struct IpFilterLayer {
+    rules: Arc<Router<Arc<Rule>>>,
+    denied: SiteService,
+}
+
+impl IpFilterLayer {
+    pub fn builder() -> IpFilterLayerBuilder {
+        IpFilterLayerBuilder {
+            rules: Router::new(),
+            denied: SiteService::new(tower::service_fn(|_| {
+                std::future::ready(Ok(empty_response(StatusCode::NOT_FOUND)))
+            })),
+            errors: Vec::new(),
+        }
+    }
+
+    /// The route whose rule doesn't allow `client` to request `path`, if any.
+    pub fn denies(&self, path: &str, client: Option<IpAddr>) -> Option<Arc<str>> {
+        let path = tunnelbana_auth::route_path(path);
+        let matched = self.rules.at(&path).ok()?;
+        let Rule { route, rule } = matched.value.as_ref();
+        (!rule.allows(client)).then(|| route.clone())
+    }
+}
+
+impl<S> Layer<S> for IpFilterLayer {
+    type Service = IpFilter<S>;
+
+    fn layer(&self, inner: S) -> IpFilter<S> {
+        IpFilter {
+            layer: self.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+/// A [`tower::Service`] which only passes allowed requests to the service it wraps.
+pub struct IpFilter<S> {
+    layer: IpFilterLayer,
+    inner: S,
+}
+
+impl<S> Service<Request<RequestBody>> for IpFilter<S>
+where
+    S: Service<Request<RequestBody>, Response = Response<ResponseBody>, Error = Infallible>,
+{
+    type Error = Infallible;
+    type Future = ResponseFuture<S::Future>;
+    type Response = Response<ResponseBody>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<RequestBody>) -> Self::Future {
+        let client = req
+            .extensions()
+            .get::<ClientAddr>()
+            .and_then(|client| client.0);
+        // the file a request is served from is guarded as well as its own path
+        let served = req.extensions().get::<ServedPath>();
+        let route = self
+            .layer
+            .denies(req.uri().path(), client)
+            .or_else(|| served.and_then(|ServedPath(served)| self.layer.denies(served, client)));
+        let Some(route) = route else {
+            return ResponseFuture::Inner(self.inner.call(req));
+        };
+        debug!(path = req.uri().path(), ?client, %route, "Denied request");
+        ResponseFuture::Denied {
+            denied: Box::pin(self.layer.denied.clone().oneshot(req)),
+            route: Some(route),
+        }
+    }
+}
+
+#[pin_project::pin_project(project = ResponseFutureProj)]
+/// Future which is either the wrapped service's response, or the denied service's.
+pub enum ResponseFuture<F> {
+    Inner(#[pin] F),
+    /// Boxed, since few requests are denied.
+    Denied {
+        denied: Pin<Box<Oneshot<SiteService, Request<RequestBody>>>>,
+        route: Option<Arc<str>>,
+    },
+}
+
+impl<F> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<ResponseBody>, Infallible>>,
+{
+    type Output = Result<Response<ResponseBody>, Infallible>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            ResponseFutureProj::Inner(inner) => inner.poll(cx),
+            ResponseFutureProj::Denied { denied, route } => {
+                let Ok(mut response) = std::task::ready!(denied.as_mut().poll(cx));
+                if let Some(route) = route.take() {
+                    response.extensions_mut().insert(Denied { route });
+                }
+                Poll::Ready(Ok(response))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{BodyExt, Empty};
+
+    use super::*;
+
+    fn nets(nets: &[&str]) -> Vec<IpNet> {
+        nets.iter().map(|net| net.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn rules_allow_and_deny() {
+        let vpn = IpRule {
+            allow: nets(&["10.8.0.0/16"]),
+            deny: nets(&["10.8.9.0/24"]),
+        };
+        assert!(vpn.allows(Some("10.8.1.2".parse().unwrap())));
+        assert!(vpn.allows(Some("::ffff:10.8.1.2".parse().unwrap())));
+        assert!(!vpn.allows(Some("10.8.9.2".parse().unwrap())));
+        assert!(!vpn.allows(Some("192.0.2.1".parse().unwrap())));
+        assert!(!vpn.allows(None));
+
+        let blocklist = IpRule {
+            allow: Vec::new(),
+            deny: nets(&["2001:db8::/32"]),
+        };
+        assert!(!blocklist.allows(Some("2001:db8::1".parse().unwrap())));
+        assert!(blocklist.allows(Some("192.0.2.1".parse().unwrap())));
+        assert!(blocklist.allows(None));
+    }
+
+    #[tokio::test]
+    async fn denies_requests() {
+        let forbidden = SiteService::new(tower::service_fn(|_| {
+            std::future::ready(Ok(empty_response(StatusCode::FORBIDDEN)))
+        }));
+        let rule = IpRule {
+            allow: nets(&["10.8.0.0/16"]),
+            deny: Vec::new(),
+        };
+        let layer = IpFilterLayer::builder()
+            .rule("/admin/*", rule)
+            .with_denied_service(forbidden)
+            .build()
+            .unwrap();
+        let svc = layer.layer(tower::service_fn(|_| {
+            std::future::ready(Ok(empty_response(StatusCode::OK)))
+        }));
+        let get = |path: &'static str, client: &'static str| {
+            let svc = svc.clone();
+            async move {
+                let mut req = Request::get(path)
+                    .body(RequestBody::new(Empty::new().map_err(|e| match e {})))
+                    .unwrap();
+                req.extensions_mut()
+                    .insert(ClientAddr(Some(client.parse().unwrap())));
+                svc.oneshot(req).await.unwrap()
+            }
+        };
+
+        assert_eq!(get("/admin/", "10.8.0.1").await.status(), StatusCode::OK);
+        assert_eq!(
+            get("/admin", "192.0.2.1").await.status(),
+            StatusCode::FORBIDDEN
+        );
+        let response = get("/admin/users", "192.0.2.1").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.extensions().get::<Denied>().map(|d| &*d.route),
+            Some("/admin/*")
+        );
+        let response = get("/%61dmin//users", "192.0.2.1").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(get("/about", "192.0.2.1").await.status(), StatusCode::OK);
+
+        // served from a file the rule covers, under another path
+        let mut req = Request::get("/team")
+            .body(RequestBody::new(Empty::new().map_err(|e| match e {})))
+            .unwrap();
+        req.extensions_mut()
+            .insert(ClientAddr(Some("192.0.2.1".parse().unwrap())));
+        req.extensions_mut()
+            .insert(ServedPath("/admin/team.html".to_owned()));
+        let response = svc.clone().oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let duplicate = IpFilterLayer::builder()
+            .rule("/a", IpRule::default())
+            .rule("/a", IpRule::default())
+            .build();
+        assert!(duplicate.is_err());
+    }
+}
@@ -0,0 +1,528 @@
+//! Listening socket configuration and the accept half of the server.
+use std::{
+    fmt::{Display, Formatter},
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{os::fd::OwnedFd, path::PathBuf};
+
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpSocket},
+    sync::mpsc::Sender,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::proxy;
+
+#[cfg(unix)]
+const UNIX_PREFIX: &str = "unix:";
+const TLS_PREFIX: &str = "tls:";
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// The same backlog `TcpListener::bind` uses.
+const LISTEN_BACKLOG: u32 = 1024;
+
+/// Anything which can carry a connection to hyper.
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T> Stream for T where T: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+/// A type-erased accepted connection.
+pub type Connection = Pin<Box<dyn Stream>>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// An address to listen on, as passed to `--listen`.
+///
+/// TCP addresses are written as `127.0.0.1:8080` or `[::]:80`,
+/// unix domain sockets as `unix:/run/tunnelbana.sock`.
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            if path.is_empty() {
+                return Err(format!("`{s}` is missing a socket path"));
+            }
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        s.parse()
+            .map(Self::Tcp)
+            .map_err(|e| format!("`{s}` is not a valid listen address: {e}"))
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A listener as passed to `--listen`: an address, optionally prefixed
+/// with `tls:` to terminate TLS on it, like `tls:[::]:443`.
+pub struct ListenSpec {
+    pub addr: ListenAddr,
+    pub tls: bool,
+}
+
+impl FromStr for ListenSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(TLS_PREFIX) {
+            Some(addr) => Ok(Self {
+                addr: addr.parse()?,
+                tls: true,
+            }),
+            None => Ok(Self {
+                addr: s.parse()?,
+                tls: false,
+            }),
+        }
+    }
+}
+
+impl Display for ListenSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.tls {
+            f.write_str(TLS_PREFIX)?;
+        }
+        self.addr.fmt(f)
+    }
+}
+
+/// Where a listener comes from: an address to bind, or an already-bound
+/// socket, such as one passed by systemd or shared between workers.
+pub enum ListenerSource {
+    Bind(ListenSpec),
+    #[cfg(unix)]
+    Inherited {
+        fd: OwnedFd,
+        tls: bool,
+    },
+}
+
+impl ListenerSource {
+    pub const fn tls(&self) -> bool {
+        match self {
+            Self::Bind(spec) => spec.tls,
+            #[cfg(unix)]
+            Self::Inherited { tls, .. } => *tls,
+        }
+    }
+
+    /// Prepare this source to be opened once per worker. Unix sockets can't share
+    /// a path with `SO_REUSEPORT`, so they are bound once here, and every worker
+    /// accepts from the same socket.
+    /// # Errors
+    /// If a unix socket can't be bound.
+    pub fn into_shared(self) -> Result<Self, IoError> {
+        match self {
+            #[cfg(unix)]
+            Self::Bind(ListenSpec {
+                addr: ListenAddr::Unix(path),
+                tls,
+            }) => {
+                remove_stale_socket(&path)?;
+                let listener = std::os::unix::net::UnixListener::bind(&path)?;
+                Ok(Self::Inherited {
+                    fd: listener.into(),
+                    tls,
+                })
+            }
+            other => Ok(other),
+        }
+    }
+
+    /// Another source for the same address or socket, for another worker.
+    /// # Errors
+    /// If a socket can't be duplicated.
+    pub fn try_clone(&self) -> Result<Self, IoError> {
+        match self {
+            Self::Bind(spec) => Ok(Self::Bind(spec.clone())),
+            #[cfg(unix)]
+            Self::Inherited { fd, tls } => Ok(Self::Inherited {
+                fd: fd.try_clone()?,
+                tls: *tls,
+            }),
+        }
+    }
+}
+
+impl Display for ListenerSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bind(spec) => spec.fmt(f),
+            #[cfg(unix)]
+            Self::Inherited { fd, .. } => write!(f, "inherited socket {fd:?}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// The remote end of an accepted connection.
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Quic(SocketAddr),
+    #[cfg(unix)]
+    Unix,
+}
+
+impl PeerAddr {
+    /// The client's IP address, unless it connected over a unix socket.
+    pub const fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Tcp(addr) | Self::Quic(addr) => Some(addr.ip()),
+            #[cfg(unix)]
+            Self::Unix => None,
+        }
+    }
+}
+
+impl Display for PeerAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) | Self::Quic(addr) => addr.fmt(f),
+            #[cfg(unix)]
+            Self::Unix => f.write_str("unix socket"),
+        }
+    }
+}
+
+/// A bound listener, which hands its connections to the server.
+pub struct Listener {
+    socket: Socket,
+    tls: Option<TlsAcceptor>,
+    proxy_protocol: bool,
+}
+
+impl Listener {
+    /// Bind or adopt a listener. If `tls` is set, connections will have TLS
+    /// terminated before they are passed to hyper. With `reuse_port`, TCP
+    /// sockets are bound with `SO_REUSEPORT`, so other workers can bind the
+    /// same address.
+    ///
+    /// This must be called from inside the runtime which will accept connections.
+    /// # Errors
+    /// If the socket can't be bound, or an inherited descriptor isn't a
+    /// TCP or unix stream socket.
+    pub fn open(
+        source: ListenerSource,
+        tls: Option<TlsAcceptor>,
+        reuse_port: bool,
+    ) -> Result<Self, IoError> {
+        let socket = match source {
+            ListenerSource::Bind(spec) => Socket::bind(&spec.addr, reuse_port)?,
+            #[cfg(unix)]
+            ListenerSource::Inherited { fd, .. } => Socket::from_fd(fd)?,
+        };
+        Ok(Self {
+            socket,
+            tls,
+            proxy_protocol: false,
+        })
+    }
+
+    /// Expect every connection to start with a PROXY protocol header, and
+    /// use the client address from it as the peer.
+    #[must_use]
+    pub const fn with_proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    /// Accept connections forever, handing them to `conns`. Returns
+    /// once the receiving half has been dropped.
+    pub async fn accept_into(self, conns: Sender<Accepted>) {
+        loop {
+            match self.socket.accept().await {
+                Ok((stream, peer)) => {
+                    let accepted = Accepted {
+                        stream,
+                        peer,
+                        tls: self.tls.clone(),
+                        proxy_protocol: self.proxy_protocol,
+                    };
+                    if conns.send(accepted).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => warn!("accept error: {}", e),
+            }
+        }
+    }
+
+    /// The address this listener is bound to, for logging.
+    pub fn local_addr(&self) -> Result<ListenSpec, IoError> {
+        Ok(ListenSpec {
+            addr: self.socket.local_addr()?,
+            tls: self.tls.is_some(),
+        })
+    }
+}
+
+/// A connection which has been accepted, but not yet handshaken.
+pub struct Accepted {
+    /// The socket's remote end, which may be a proxy
+    pub peer: PeerAddr,
+    stream: Connection,
+    tls: Option<TlsAcceptor>,
+    proxy_protocol: bool,
+}
+
+impl Accepted {
+    /// Finish setting up this connection, reading its PROXY protocol header
+    /// and performing the TLS handshake if the listener which accepted it
+    /// has them enabled. Returns the stream and the client's address.
+    /// # Errors
+    /// If the PROXY header is invalid, or the TLS handshake fails, or either times out.
+    pub async fn into_stream(mut self) -> Result<(Connection, PeerAddr), IoError> {
+        let mut peer = self.peer;
+        if self.proxy_protocol {
+            let header = proxy::read_header(&mut self.stream);
+            match tokio::time::timeout(PROXY_HEADER_TIMEOUT, header).await {
+                Ok(Ok(Some(client))) => peer = PeerAddr::Tcp(client),
+                Ok(Ok(None)) => {}
+                Ok(Err(e)) => return Err(e),
+                Err(elapsed) => return Err(IoError::new(IoErrorKind::TimedOut, elapsed)),
+            }
+        }
+        let Some(tls) = self.tls else {
+            return Ok((self.stream, peer));
+        };
+        let handshake = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(self.stream));
+        match handshake.await {
+            Ok(stream) => Ok((Box::pin(stream?), peer)),
+            Err(elapsed) => Err(IoError::new(IoErrorKind::TimedOut, elapsed)),
+        }
+    }
+}
+
+/// A bound socket which can accept connections.
+enum Socket {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Socket {
+    /// Bind a new socket. Stale unix sockets left behind by a previous
+    /// process are removed before binding.
+    fn bind(addr: &ListenAddr, reuse_port: bool) -> Result<Self, IoError> {
+        match addr {
+            ListenAddr::Tcp(addr) => {
+                let socket = if addr.is_ipv4() {
+                    TcpSocket::new_v4()?
+                } else {
+                    TcpSocket::new_v6()?
+                };
+                #[cfg(unix)]
+                socket.set_reuseaddr(true)?;
+                if reuse_port {
+                    set_reuse_port(&socket)?;
+                }
+                socket.bind(*addr)?;
+                socket.listen(LISTEN_BACKLOG).map(Self::Tcp)
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                UnixListener::bind(path).map(Self::Unix)
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn from_fd(fd: OwnedFd) -> Result<Self, IoError> {
+        let socket = socket2::Socket::from(fd);
+        if socket.r#type()? != socket2::Type::STREAM {
+            return Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                "inherited socket is not a stream socket",
+            ));
+        }
+        let local_addr = socket.local_addr()?;
+        socket.set_nonblocking(true)?;
+        if local_addr.as_socket().is_some() {
+            TcpListener::from_std(socket.into()).map(Self::Tcp)
+        } else if local_addr.is_unix() {
+            UnixListener::from_std(socket.into()).map(Self::Unix)
+        } else {
+            Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                "inherited socket is neither TCP nor a unix socket",
+            ))
+        }
+    }
+
+    async fn accept(&self) -> Result<(Connection, PeerAddr), IoError> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::pin(stream), PeerAddr::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::pin(stream), PeerAddr::Unix))
+            }
+        }
+    }
+
+    fn local_addr(&self) -> Result<ListenAddr, IoError> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                let path = addr.as_pathname().unwrap_or_else(|| "unnamed".as_ref());
+                Ok(ListenAddr::Unix(path.to_path_buf()))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> Result<(), IoError> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(IoError::new(
+            IoErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(e) if e.kind() == IoErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+))]
+fn set_reuse_port(socket: &TcpSocket) -> Result<(), IoError> {
+    socket.set_reuseport(true)
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+)))]
+fn set_reuse_port(_socket: &TcpSocket) -> Result<(), IoError> {
+    Err(IoError::new(
+        IoErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_listen_addrs() {
+        assert_eq!(
+            "[::]:80".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Tcp("[::]:80".parse().unwrap())
+        );
+        assert_eq!(
+            "127.0.0.1:9000".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Tcp("127.0.0.1:9000".parse().unwrap())
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            "unix:/run/tunnelbana.sock".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Unix(PathBuf::from("/run/tunnelbana.sock"))
+        );
+        assert!("unix:".parse::<ListenAddr>().is_err());
+        assert!("localhost".parse::<ListenAddr>().is_err());
+    }
+
+    #[test]
+    fn parse_listen_specs() {
+        let spec: ListenSpec = "tls:[::]:443".parse().unwrap();
+        assert!(spec.tls);
+        assert_eq!(spec.addr, ListenAddr::Tcp("[::]:443".parse().unwrap()));
+        let spec: ListenSpec = "[::]:80".parse().unwrap();
+        assert!(!spec.tls);
+        assert_eq!(spec.to_string(), "[::]:80");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn adopt_inherited_sockets() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        let listener = Listener::open(inherited(tcp), None, false).unwrap();
+        assert_eq!(
+            listener.local_addr().unwrap().addr,
+            ListenAddr::Tcp(tcp_addr)
+        );
+        let (accepted, client) = tokio::join!(
+            listener.socket.accept(),
+            tokio::net::TcpStream::connect(tcp_addr)
+        );
+        let (_, peer) = accepted.unwrap();
+        assert!(
+            matches!(peer, PeerAddr::Tcp(addr) if addr == client.unwrap().local_addr().unwrap())
+        );
+
+        let dir = std::env::temp_dir().join(format!("tunnelbana-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("inherited.sock");
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let listener = Listener::open(inherited(unix), None, false).unwrap();
+        let (accepted, client) = tokio::join!(
+            listener.socket.accept(),
+            tokio::net::UnixStream::connect(&path)
+        );
+        client.unwrap();
+        assert!(matches!(accepted.unwrap().1, PeerAddr::Unix));
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(Listener::open(inherited(udp), None, false).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reuse_port_workers() {
+        let first = Listener::open(bind("127.0.0.1:0"), None, true).unwrap();
+        let addr = first.local_addr().unwrap().addr.to_string();
+        let second = Listener::open(bind(&addr), None, true).unwrap();
+        assert_eq!(second.local_addr().unwrap().addr.to_string(), addr);
+        assert!(Listener::open(bind(&addr), None, false).is_err());
+    }
+
+    #[cfg(unix)]
+    fn bind(addr: &str) -> ListenerSource {
+        ListenerSource::Bind(addr.parse().unwrap())
+    }
+
+    #[cfg(unix)]
+    fn inherited(fd: impl Into<OwnedFd>) -> ListenerSource {
+        ListenerSource::Inherited {
+            fd: fd.into(),
+            tls: false,
+        }
+    }
+}
@@ -0,0 +1,573 @@
+//! Generated index pages for directories without an `index.html`, as HTML,
+//! or as JSON for requests which `Accept: application/json`.
+use std::{
+    cmp::Ordering,
+    collections::HashSet,
+    convert::Infallible,
+    fmt::Write,
+    future::Future,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use futures_util::future::Either;
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, header};
+use http_body_util::{BodyExt, Empty, Full};
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
+use time::{
+    OffsetDateTime,
+    format_description::{BorrowedFormatItem, well_known::Rfc3339},
+    macros::format_description,
+};
+use tower::{Layer, Service};
+use tunnelbana_etags::ETagLayer;
+use tunnelbana_hidepaths::HidePathsLayer;
+
+use crate::{
+    compress::Encoding,
+    config::ListingConfig,
+    memfs::{MemoryFs, empty_response},
+    site::{RequestBody, ResponseBody, SiteService},
+};
+
+const MODIFIED_FORMAT: &[BorrowedFormatItem<'static>] =
+    format_description!("[year]-[month]-[day] [hour]:[minute]");
+
+/// Characters escaped in a link to an entry, which is a single path segment.
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'\'')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A file or directory in a listing.
+pub struct Entry {
+    pub name: String,
+    pub dir: bool,
+    /// In bytes, and 0 for directories
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+#[derive(Clone, Copy, Debug)]
+/// Added to the extensions of every generated directory listing.
+pub struct Listed;
+
+#[derive(Clone)]
+/// Where the files to list are.
+pub enum ListingSource {
+    Disk(Arc<Path>),
+    /// An archive, read into memory
+    Memory(Arc<ArcSwap<MemoryFs>>),
+}
+
+impl ListingSource {
+    /// Whether `dir`, a decoded path like `/docs/`, is a directory.
+    fn is_dir(&self, dir: &str) -> bool {
+        match self {
+            Self::Disk(root) => root.join(dir.trim_start_matches('/')).is_dir(),
+            Self::Memory(files) => files.load().is_dir(dir),
+        }
+    }
+
+    /// The entries of `dir`, or `None` if it can't be read.
+    async fn entries(&self, dir: &str) -> Option<Vec<Entry>> {
+        let root = match self {
+            Self::Disk(root) => root,
+            Self::Memory(files) => return files.load().list(dir),
+        };
+        let mut read_dir = tokio::fs::read_dir(root.join(dir.trim_start_matches('/')))
+            .await
+            .ok()?;
+        let mut entries = Vec::new();
+        while let Ok(Some(entry)) = read_dir.next_entry().await {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            // follow symlinks, like the file service does
+            let Ok(metadata) = tokio::fs::metadata(entry.path()).await else {
+                continue;
+            };
+            if !metadata.is_dir() && !metadata.is_file() {
+                continue;
+            }
+            entries.push(Entry {
+                name,
+                dir: metadata.is_dir(),
+                size: if metadata.is_dir() { 0 } else { metadata.len() },
+                modified: metadata.modified().ok(),
+            });
+        }
+        Some(entries)
+    }
+}
+
+struct Listing {
+    /// Decoded path prefixes to list directories under, without trailing slashes
+    prefixes: Vec<String>,
+    source: ListingSource,
+    /// The site's etags, which are used to find `index.html` files
+    files: ETagLayer,
+    hidden: HidePathsLayer<SiteService>,
+}
+
+impl Listing {
+    /// The decoded directory to list for `req`, if it should get a listing.
+    fn dir_for<B>(&self, req: &Request<B>) -> Option<String> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return None;
+        }
+        let path = req.uri().path();
+        if !path.ends_with('/') {
+            return None;
+        }
+        let dir = percent_decode_str(path).decode_utf8().ok()?;
+        if dir
+            .split('/')
+            .any(|segment| segment == ".." || segment.contains('\\'))
+        {
+            return None;
+        }
+        let listed = self.prefixes.is_empty()
+            || self.prefixes.iter().any(|prefix| {
+                dir.strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.starts_with('/'))
+            });
+        if !listed || self.files.contains(&format!("{dir}index.html")) || !self.source.is_dir(&dir)
+        {
+            return None;
+        }
+        Some(dir.into_owned())
+    }
+
+    /// The entries of `dir` which are served, leaving out hidden paths and
+    /// precompressed siblings. `path` is the directory's path as requested.
+    async fn entries(&self, dir: &str, path: &str) -> Option<Vec<Entry>> {
+        let mut entries = self.source.entries(dir).await?;
+        let files: HashSet<String> = entries
+            .iter()
+            .filter(|entry| !entry.dir)
+            .map(|entry| entry.name.clone())
+            .collect();
+        entries.retain(|entry| {
+            let sibling = Encoding::ALL.iter().any(|encoding| {
+                entry
+                    .name
+                    .strip_suffix(encoding.extension())
+                    .is_some_and(|base| files.contains(base))
+            });
+            let entry_path = format!("{path}{}", utf8_percent_encode(&entry.name, SEGMENT));
+            // a directory is hidden along with what it serves
+            let hidden = self.hidden.is_hidden(&entry_path)
+                || (entry.dir
+                    && (self.hidden.is_hidden(&format!("{entry_path}/"))
+                        || self.hidden.is_hidden(&format!("{entry_path}/index.html"))));
+            !sibling && !hidden
+        });
+        Some(entries)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Name => "name",
+            Self::Size => "size",
+            Self::Modified => "modified",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// How entries are sorted, from `?sort=name|size|modified&order=asc|desc`.
+/// Directories always come first.
+struct Sort {
+    key: SortKey,
+    descending: bool,
+}
+
+impl Sort {
+    fn from_query(query: Option<&str>) -> Self {
+        let mut sort = Self {
+            key: SortKey::Name,
+            descending: false,
+        };
+        for pair in query.unwrap_or_default().split('&') {
+            match pair.split_once('=') {
+                Some(("sort", "name")) => sort.key = SortKey::Name,
+                Some(("sort", "size")) => sort.key = SortKey::Size,
+                Some(("sort", "modified")) => sort.key = SortKey::Modified,
+                Some(("order", "asc")) => sort.descending = false,
+                Some(("order", "desc")) => sort.descending = true,
+                _ => {}
+            }
+        }
+        sort
+    }
+
+    fn apply(self, entries: &mut [Entry]) {
+        entries.sort_by(|a, b| {
+            let ordering = match self.key {
+                SortKey::Name => Ordering::Equal,
+                SortKey::Size => a.size.cmp(&b.size),
+                SortKey::Modified => a.modified.cmp(&b.modified),
+            }
+            .then_with(|| a.name.cmp(&b.name));
+            let ordering = if self.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            };
+            b.dir.cmp(&a.dir).then(ordering)
+        });
+    }
+
+    /// The query for a column's header link, which reverses the order if
+    /// the entries are already sorted by that column.
+    fn link(self, key: SortKey) -> String {
+        let order = if self.key == key && !self.descending {
+            "desc"
+        } else {
+            "asc"
+        };
+        format!("?sort={}&amp;order={order}", key.name())
+    }
+}
+
+fn escape_html(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// `size` in bytes, written like `1.5 MiB`.
+fn human_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if size < 1024 {
+        return format!("{size} B");
+    }
+    let mut unit = 1024;
+    let mut name = UNITS[0];
+    for next in &UNITS[1..] {
+        if size / unit < 1024 {
+            break;
+        }
+        unit *= 1024;
+        name = next;
+    }
+    format!("{}.{} {name}", size / unit, size % unit * 10 / unit)
+}
+
+fn render_html(dir: &str, entries: &[Entry], sort: Sort) -> String {
+    let title = escape_html(dir);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width\">\n\
+         <title>Index of {title}</title>\n</head>\n<body>\n<h1>Index of {title}</h1>\n\
+         <table>\n<thead><tr><th><a href=\"{}\">Name</a></th><th><a href=\"{}\">Size</a></th>\
+         <th><a href=\"{}\">Modified</a></th></tr></thead>\n<tbody>\n",
+        sort.link(SortKey::Name),
+        sort.link(SortKey::Size),
+        sort.link(SortKey::Modified),
+    );
+    if dir != "/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let slash = if entry.dir { "/" } else { "" };
+        let size = if entry.dir {
+            "-".to_owned()
+        } else {
+            human_size(entry.size)
+        };
+        let modified = entry
+            .modified
+            .and_then(|modified| OffsetDateTime::from(modified).format(MODIFIED_FORMAT).ok())
+            .unwrap_or_default();
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}{slash}\">{}{slash}</a></td><td>{size}</td><td>{modified}</td></tr>",
+            utf8_percent_encode(&entry.name, SEGMENT),
+            escape_html(&entry.name),
+        );
+    }
+    html.push_str("</tbody>\n</table>\n</body>\n</html>\n");
+    html
+}
+
+fn render_json(dir: &str, entries: &[Entry]) -> String {
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            serde_json::json!({
+                "name": entry.name,
+                "type": if entry.dir { "directory" } else { "file" },
+                "size": entry.size,
+                "modified": entry
+                    .modified
+                    .and_then(|modified| OffsetDateTime::from(modified).format(&Rfc3339).ok()),
+            })
+        })
+        .collect();
+    serde_json::json!({ "path": dir, "entries": entries }).to_string()
+}
+
+fn wants_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains("application/json"))
+}
+
+/// The parts of a request which are needed to list a directory for it.
+struct ListingRequest {
+    dir: String,
+    path: String,
+    sort: Sort,
+    json: bool,
+    head: bool,
+}
+
+async fn respond(listing: Arc<Listing>, request: ListingRequest) -> Response<ResponseBody> {
+    let Some(mut entries) = listing.entries(&request.dir, &request.path).await else {
+        return empty_response(StatusCode::NOT_FOUND);
+    };
+    request.sort.apply(&mut entries);
+    let (body, content_type) = if request.json {
+        (render_json(&request.dir, &entries), "application/json")
+    } else {
+        (
+            render_html(&request.dir, &entries, request.sort),
+            "text/html; charset=utf-8",
+        )
+    };
+    let len = body.len();
+    let mut response = Response::new(if request.head {
+        ResponseBody::new(Empty::new().map_err(|e| match e {}))
+    } else {
+        ResponseBody::new(Full::new(Bytes::from(body)).map_err(|e| match e {}))
+    });
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(header::CONTENT_LENGTH, len.into());
+    headers.append(header::VARY, HeaderValue::from_name(header::ACCEPT));
+    response.extensions_mut().insert(Listed);
+    response
+}
+
+#[derive(Clone)]
+/// A [`tower::Layer`] which lists directories without an `index.html`
+/// instead of passing them to the service it wraps. Without a
+/// [`ListingConfig`], every request is passed through.
+pub struct ListingLayer {
+    listing: Option<Arc<Listing>>,
+}
+
+impl ListingLayer {
+    /// `hidden` is checked for every entry, so hidden files aren't listed.
+    pub fn new(
+        config: Option<&ListingConfig>,
+        source: ListingSource,
+        files: ETagLayer,
+        hidden: HidePathsLayer<SiteService>,
+    ) -> Self {
+        let listing = config.map(|config| {
+            let prefixes = config
+                .paths
+                .iter()
+                .map(|prefix| prefix.trim_end_matches('/').to_owned())
+                .collect();
+            Arc::new(Listing {
+                prefixes,
+                source,
+                files,
+                hidden,
+            })
+        });
+        Self { listing }
+    }
+}
+
+impl<S> Layer<S> for ListingLayer {
+    type Service = Listings<S>;
+
+    fn layer(&self, inner: S) -> Listings<S> {
+        Listings {
+            listing: self.listing.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+/// A [`tower::Service`] which lists directories the service it wraps has no index for.
+pub struct Listings<S> {
+    listing: Option<Arc<Listing>>,
+    inner: S,
+}
+
+type ListingFuture =
+    Pin<Box<dyn Future<Output = Result<Response<ResponseBody>, Infallible>> + Send>>;
+
+impl<S> Service<Request<RequestBody>> for Listings<S>
+where
+    S: Service<Request<RequestBody>, Response = Response<ResponseBody>, Error = Infallible>,
+{
+    type Error = Infallible;
+    type Future = Either<S::Future, ListingFuture>;
+    type Response = Response<ResponseBody>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<RequestBody>) -> Self::Future {
+        let listed = self
+            .listing
+            .as_ref()
+            .and_then(|listing| Some((listing.clone(), listing.dir_for(&req)?)));
+        let Some((listing, dir)) = listed else {
+            return Either::Left(self.inner.call(req));
+        };
+        let request = ListingRequest {
+            dir,
+            path: req.uri().path().to_owned(),
+            sort: Sort::from_query(req.uri().query()),
+            json: wants_json(req.headers()),
+            head: req.method() == Method::HEAD,
+        };
+        Either::Right(Box::pin(async move { Ok(respond(listing, request).await) }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, dir: bool, size: u64, modified: u64) -> Entry {
+        Entry {
+            name: name.to_owned(),
+            dir,
+            size,
+            modified: Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(modified)),
+        }
+    }
+
+    #[test]
+    fn sorts_entries() {
+        let mut entries = vec![
+            entry("b.txt", false, 10, 3),
+            entry("docs", true, 0, 1),
+            entry("a.txt", false, 30, 2),
+        ];
+        let names = |entries: &[Entry]| {
+            entries
+                .iter()
+                .map(|entry| entry.name.as_str())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        Sort::from_query(None).apply(&mut entries);
+        assert_eq!(names(&entries), "docs a.txt b.txt");
+        Sort::from_query(Some("sort=size&order=desc")).apply(&mut entries);
+        assert_eq!(names(&entries), "docs a.txt b.txt");
+        Sort::from_query(Some("sort=modified")).apply(&mut entries);
+        assert_eq!(names(&entries), "docs a.txt b.txt");
+        Sort::from_query(Some("sort=modified&order=desc")).apply(&mut entries);
+        assert_eq!(names(&entries), "docs b.txt a.txt");
+        Sort::from_query(Some("order=desc")).apply(&mut entries);
+        assert_eq!(names(&entries), "docs b.txt a.txt");
+    }
+
+    #[test]
+    fn renders_sizes() {
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+
+    #[tokio::test]
+    async fn lists_served_files() {
+        let mut fs = MemoryFs::new(None);
+        for path in [
+            "/_headers",
+            "/files/tool.tar.gz",
+            "/files/app.js",
+            "/files/app.js.br",
+            "/files/<b>.txt",
+            "/files/secret/key.txt",
+            "/files/nested/index.html",
+            "/site/index.html",
+        ] {
+            fs.insert(path, Bytes::from_static(b"data"));
+        }
+        let files = ETagLayer::new(fs.etags(false).unwrap());
+        let hidden = HidePathsLayer::builder()
+            .hide_all(["/_headers", "/files/secret/{*rest}"])
+            .with_not_found_service(SiteService::new(tower::service_fn(|_| {
+                std::future::ready(Ok(empty_response(StatusCode::NOT_FOUND)))
+            })))
+            .build()
+            .unwrap();
+        let source = ListingSource::Memory(Arc::new(ArcSwap::from_pointee(fs)));
+        let config = ListingConfig::default();
+        let layer = ListingLayer::new(Some(&config), source, files, hidden);
+        let listing = layer.listing.unwrap();
+
+        let request = |path: &str| Request::get(path).body(()).unwrap();
+        assert_eq!(listing.dir_for(&request("/site/")), None);
+        assert_eq!(listing.dir_for(&request("/missing/")), None);
+        assert_eq!(listing.dir_for(&request("/files")), None);
+        assert_eq!(
+            listing.dir_for(&request("/files/")).as_deref(),
+            Some("/files/")
+        );
+
+        let mut entries = listing.entries("/files/", "/files/").await.unwrap();
+        Sort::from_query(None).apply(&mut entries);
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["nested", "<b>.txt", "app.js", "tool.tar.gz"]);
+        let root = listing.entries("/", "/").await.unwrap();
+        assert!(root.iter().all(|entry| entry.name != "_headers"));
+
+        let html = render_html("/files/", &entries, Sort::from_query(None));
+        assert!(html.contains("<a href=\"nested/\">nested/</a>"));
+        assert!(html.contains("<a href=\"%3Cb%3E.txt\">&lt;b&gt;.txt</a>"));
+        let json: serde_json::Value =
+            serde_json::from_str(&render_json("/files/", &entries)).unwrap();
+        assert_eq!(json["entries"][3]["name"], "tool.tar.gz");
+        assert_eq!(json["entries"][3]["size"], 4);
+        assert_eq!(json["entries"][0]["type"], "directory");
+    }
+}
@@ -4,56 +4,247 @@
 //! tunnelbana is a binary which uses the [tunnelbana project](https://github.com/randomairborne/tunnelbana)
 //! to build a static file server.
 use std::{
+    convert::Infallible,
     io::{Error as IoError, ErrorKind as IoErrorKind},
+    net::SocketAddr,
+    num::NonZeroUsize,
     path::{Path, PathBuf},
-    pin::pin,
     process::{ExitCode, Termination},
-    time::Duration,
+    sync::Arc,
 };
 
-use futures_util::future::Either;
-use http::{HeaderValue, StatusCode};
-use hyper_util::{
-    rt::{TokioExecutor, TokioIo},
-    server::{conn::auto::Builder as ConnBuilder, graceful::GracefulShutdown},
-    service::TowerToHyperService,
-};
-use tokio::{net::TcpListener, runtime::Builder as RuntimeBuilder};
-use tokio_util::task::TaskTracker;
-use tower::ServiceBuilder;
-use tower_http::{
-    services::{ServeDir, ServeFile},
-    set_header::SetResponseHeaderLayer,
-    set_status::SetStatusLayer,
-};
-use tracing::Level;
-use tunnelbana_etags::{ETagLayer, ETagMap};
-use tunnelbana_headers::HeadersLayer;
-use tunnelbana_redirects::RedirectsLayer;
+use access_log::{AccessLogLayer, LogFormat};
+use archive::ArchiveKind;
+use bytes::Bytes;
+use check::CheckArgs;
+use config::{Config, Http3Config, MetricsConfig, PreloadConfig};
+use connections::ConnectionLimits;
+use explain::ExplainArgs;
+use http::{Request, Response};
+use http_body_util::BodyExt;
+use ipnet::IpNet;
+use listener::{ListenAddr, ListenSpec, Listener, ListenerSource};
+use metrics::{Metrics, MetricsLayer};
+use proxy::ClientAddrLayer;
+use rate_limit::RateLimitLayer;
+use server::Server;
+use site::{RequestBody, ResponseBody, ServeOptions, Site, SiteService};
+use tls::{CertStore, SniCert};
+use tokio::runtime::{Builder as RuntimeBuilder, Runtime};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tower::{BoxError, Layer, Service, ServiceBuilder, util::BoxCloneSyncService};
+use vhost::{VhostConfig, VirtualHosts};
 
 #[macro_use]
 extern crate tracing;
 
-const RESERVED_PATHS: [&str; 2] = ["/_headers", "/_redirects"];
-
-#[cfg(debug_assertions)]
-const LOG_LEVEL: Level = Level::TRACE;
-
-#[cfg(not(debug_assertions))]
-const LOG_LEVEL: Level = Level::INFO;
+mod access_log;
+mod archive;
+mod cache_control;
+mod check;
+mod clean_urls;
+mod compress;
+mod config;
+mod connections;
+mod error_pages;
+mod explain;
+mod http3;
+mod ip_filter;
+mod listener;
+mod listing;
+mod memfs;
+mod methods;
+mod metrics;
+mod observe;
+mod proxy;
+mod rate_limit;
+mod server;
+mod site;
+#[cfg(unix)]
+mod systemd;
+mod tls;
+mod vhost;
+
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:8080";
 
 use argh::FromArgs;
 
 #[derive(FromArgs)]
+#[allow(clippy::struct_excessive_bools)]
 /// Serve a directory
 struct Args {
+    /// tunnelbana.toml file to read settings from. Defaults to `$TUNNELBANA_CONFIG`
+    #[argh(option)]
+    config: Option<PathBuf>,
+
     /// fall back to index.html rather than 404.html
     #[argh(switch)]
     spa: bool,
 
-    /// directory to serve
+    /// serve a directory for specific hostnames, as `HOST[,HOST...]=DIR`. May be repeated
+    #[argh(option)]
+    vhost: Vec<VhostConfig>,
+
+    /// vhost whose site is served to unknown hosts. Without this or a directory,
+    /// unknown hosts get a 421
+    #[argh(option)]
+    default_host: Option<String>,
+
+    /// address to listen on, such as `[::]:80` or `unix:/run/tunnelbana.sock`.
+    /// Prefix with `tls:` to serve HTTPS. May be repeated. Defaults to 0.0.0.0:8080
+    #[argh(option)]
+    listen: Vec<ListenSpec>,
+
+    /// PEM certificate chain to use on `tls:` listeners when no --tls-sni certificate matches
+    #[argh(option)]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[argh(option)]
+    tls_key: Option<PathBuf>,
+
+    /// certificate for a specific hostname, as `HOST=CERT,KEY`. May be repeated
+    #[argh(option)]
+    tls_sni: Vec<SniCert>,
+
+    /// UDP address to serve HTTP/3 on, such as `[::]:443`, using the TLS
+    /// certificates. May be repeated
+    #[argh(option)]
+    http3_listen: Vec<SocketAddr>,
+
+    /// expect a PROXY protocol v1 or v2 header at the start of every connection
+    #[argh(switch)]
+    proxy_protocol: bool,
+
+    /// network whose `Forwarded` and `X-Forwarded-For` headers are trusted,
+    /// such as `10.0.0.0/8`. May be repeated
+    #[argh(option)]
+    trusted_proxy: Vec<IpNet>,
+
+    /// log every request to this file, or to stdout if `-`
+    #[argh(option)]
+    access_log: Option<PathBuf>,
+
+    /// access log format, `combined` (the default) or `json`
+    #[argh(option)]
+    access_log_format: Option<LogFormat>,
+
+    /// compress responses which have no precompressed file on the fly
+    #[argh(switch)]
+    compress: bool,
+
+    /// serve files from memory, reading them all at startup and on reload
+    #[argh(switch)]
+    preload: bool,
+
+    /// let files with a hash in their name, like `app.3f9a2c1b.js`, be cached forever
+    #[argh(switch)]
+    immutable: bool,
+
+    /// serve `/about.html` at `/about`, redirecting other URLs for a page to it
+    #[argh(switch)]
+    clean_urls: bool,
+
+    /// list the files in directories which have no index.html
+    #[argh(switch)]
+    listing: bool,
+
+    /// number of worker threads. Defaults to the number of CPUs
+    #[argh(option)]
+    workers: Option<NonZeroUsize>,
+
+    /// run each worker with its own runtime and its own `SO_REUSEPORT` sockets,
+    /// rather than sharing one multi-threaded runtime
+    #[argh(switch)]
+    reuse_port: bool,
+
+    /// serve Prometheus metrics at /metrics on this address
+    #[argh(option)]
+    metrics_listen: Option<ListenAddr>,
+
+    /// directory or .tar, .tar.zst or .zip archive to serve, overriding `root` in the config file
     #[argh(positional)]
-    directory: PathBuf,
+    directory: Option<PathBuf>,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Check(CheckArgs),
+    Explain(ExplainArgs),
+}
+
+impl Command {
+    /// The site given to the command, overriding `root` in the config file.
+    const fn directory(&self) -> Option<&PathBuf> {
+        match self {
+            Self::Check(check) => check.directory.as_ref(),
+            Self::Explain(_) => None,
+        }
+    }
+}
+
+impl Args {
+    /// Override the settings from the config file and environment with
+    /// the ones given on the command line.
+    fn apply_to(self, config: &mut Config) {
+        config.spa |= self.spa;
+        config.reuse_port |= self.reuse_port;
+        if self.compress {
+            config.compression.get_or_insert_default();
+        }
+        if self.preload {
+            config.preload.get_or_insert_default();
+        }
+        if self.immutable {
+            config.immutable.get_or_insert_default();
+        }
+        if self.clean_urls {
+            config.clean_urls.get_or_insert_default();
+        }
+        if self.listing {
+            config.listing.get_or_insert_default();
+        }
+        if self.workers.is_some() {
+            config.workers = self.workers;
+        }
+        if self.directory.is_some() {
+            config.root = self.directory;
+        }
+        config.vhosts.extend(self.vhost);
+        if self.default_host.is_some() {
+            config.default_host = self.default_host;
+        }
+        if !self.listen.is_empty() {
+            config.listen = self.listen;
+        }
+        if self.tls_cert.is_some() || self.tls_key.is_some() {
+            config.tls.cert = self.tls_cert;
+            config.tls.key = self.tls_key;
+        }
+        config.tls.sni.extend(self.tls_sni);
+        if !self.http3_listen.is_empty() {
+            config.http3 = Some(Http3Config {
+                listen: self.http3_listen,
+            });
+        }
+        config.proxy.protocol |= self.proxy_protocol;
+        config.proxy.trusted.extend(self.trusted_proxy);
+        if let Some(path) = self.access_log {
+            let access_log = config.access_log.get_or_insert_default();
+            access_log.path = (path.as_os_str() != "-").then_some(path);
+        }
+        if let Some(format) = self.access_log_format {
+            config.access_log.get_or_insert_default().format = format;
+        }
+        if let Some(listen) = self.metrics_listen {
+            config.metrics = Some(MetricsConfig { listen });
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -99,182 +290,504 @@ impl Termination for Error {
     }
 }
 
-const CACHE_CONTROL_TEXT: &str = "no-transform";
-static CACHE_CONTRL_VALUE: HeaderValue = HeaderValue::from_static(CACHE_CONTROL_TEXT);
+#[derive(Debug)]
+struct BindError(String, IoError);
 
-#[allow(clippy::too_many_lines)]
-fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt().with_max_level(LOG_LEVEL).init();
-    let args: Args = argh::from_env();
-    let location = Path::new(&args.directory);
-    if !location.is_dir() {
-        return Err(e!("Expected argument 1 to be a directory"));
+impl std::fmt::Display for BindError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "could not listen on {}: {}", self.0, self.1)
     }
-    let location = location
-        .canonicalize()
-        .map_err(|e| e!("Could not canonicalize directory", e))?;
+}
 
-    let headers = read_with_default_if_nonexistent(location.join("_headers"))
-        .map_err(|e| e!("Failed to read _headers", e))?;
-    let headers =
-        tunnelbana_headers::parse(&headers).map_err(|e| e!("Failed to parse _headers", e))?;
+impl std::error::Error for BindError {}
 
-    let redirects = read_with_default_if_nonexistent(location.join("_redirects"))
-        .map_err(|e| e!("Failed to read _redirects", e))?;
-    let redirects =
-        tunnelbana_redirects::parse(&redirects).map_err(|e| e!("Failed to parse _redirects", e))?;
+#[derive(Debug)]
+struct SiteRootError(PathBuf, Box<dyn std::error::Error>);
 
-    let etags = ETagMap::new(&location).map_err(|e| e!("Failed to generate etags", e))?;
+impl std::fmt::Display for SiteRootError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.0.display(), self.1)
+    }
+}
+
+impl std::error::Error for SiteRootError {}
 
-    let redirect_mw =
-        RedirectsLayer::new(redirects).map_err(|e| e!("Failed to build redirects router", e))?;
-    let header_add_mw =
-        HeadersLayer::new(headers).map_err(|e| e!("Failed to build headers router", e))?;
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => e.report(),
+    }
+}
 
-    let etag_mw = ETagLayer::new(etags);
+/// Wrap `service` in `layer` if there is one, keeping it type-erased.
+fn with_layer<L, B>(service: SiteService, layer: Option<L>) -> SiteService
+where
+    L: Layer<SiteService>,
+    L::Service: Service<Request<RequestBody>, Response = Response<B>, Error = Infallible>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    <L::Service as Service<Request<RequestBody>>>::Future: Send + 'static,
+    B: http_body::Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    let Some(layer) = layer else {
+        return service;
+    };
+    let service = ServiceBuilder::new()
+        .map_response(|res: Response<B>| {
+            res.map(|body| ResponseBody::new(body.map_err(Into::into)))
+        })
+        .layer(layer)
+        .service(service);
+    BoxCloneSyncService::new(service)
+}
 
-    let (not_found_path, not_found_status_layer) = if args.spa {
-        ("index.html", None)
+#[allow(clippy::too_many_lines)]
+fn run() -> Result<(), Error> {
+    let mut args: Args = argh::from_env();
+    let command = args.command.take();
+    let config_path = args
+        .config
+        .clone()
+        .or_else(|| std::env::var_os(config::CONFIG_PATH_VAR).map(PathBuf::from));
+    let mut config =
+        Config::load(config_path.as_deref()).map_err(|e| e!("Failed to load config", e))?;
+    args.apply_to(&mut config);
+    if let Some(directory) = command.as_ref().and_then(Command::directory) {
+        config.root = Some(directory.clone());
+    }
+
+    // a command's output says what it found, so only log what it can't say
+    let log_level = if command.is_some() {
+        config.log_level.min(tracing::Level::WARN)
     } else {
-        ("404.html", Some(SetStatusLayer::new(StatusCode::NOT_FOUND)))
+        config.log_level
     };
+    tracing_subscriber::fmt().with_max_level(log_level).init();
 
-    let not_found_svc = ServeFile::new(location.join(not_found_path))
-        .precompressed_br()
-        .precompressed_deflate()
-        .precompressed_gzip()
-        .precompressed_zstd();
-    let not_found_svc = ServiceBuilder::new()
-        .option_layer(not_found_status_layer)
-        .service(not_found_svc);
-    let serve_dir = ServeDir::new(location)
-        .append_index_html_on_directories(true)
-        .precompressed_br()
-        .precompressed_deflate()
-        .precompressed_gzip()
-        .precompressed_zstd()
-        .fallback(not_found_svc.clone());
-
-    let hide_special_files = tunnelbana_hidepaths::HidePathsLayer::builder()
-        .hide_all(RESERVED_PATHS)
-        .with_not_found_service(not_found_svc)
-        .build()
-        .map_err(|e| e!("Failed to build path hide layer", e))?;
+    if config.root.is_some() && config.default_host.is_some() {
+        return Err(e!("Only one of `root` and `default_host` can be set"));
+    }
 
-    let set_vary = SetResponseHeaderLayer::appending(
-        http::header::VARY,
-        HeaderValue::from_name(http::header::ACCEPT_ENCODING),
-    );
+    match command {
+        Some(Command::Check(_)) => {
+            let report = check::check(&config);
+            print!("{report}");
+            if report.errors() > 0 {
+                return Err(e!("Found problems while checking the config"));
+            }
+            return Ok(());
+        }
+        Some(Command::Explain(explain)) => return explain_request(&config, &explain),
+        None => {}
+    }
 
-    let set_cache_control =
-        SetResponseHeaderLayer::appending(http::header::CACHE_CONTROL, CACHE_CONTRL_VALUE.clone());
+    // This has to happen before any threads are started, like the ones used for hashing
+    #[cfg(unix)]
+    let inherited_sockets = systemd::inherited_sockets()
+        .map_err(|e| e!("Failed to read sockets passed by systemd", e))?;
+
+    let (sites, service) = load_sites(&config)?;
+
+    let access_log = config
+        .access_log
+        .as_ref()
+        .map(AccessLogLayer::open)
+        .transpose()
+        .map_err(|e| e!("Failed to open access log", e))?;
+    let rate_limit = config
+        .rate_limit
+        .as_ref()
+        .map(RateLimitLayer::from_config)
+        .transpose()
+        .map_err(|e| e!("Invalid rate limit config", e))?;
+    let limits = ConnectionLimits::new(&config.connections)
+        .map_err(|e| e!("Invalid connections config", e))?;
+    let metrics = config.metrics.as_ref().map(|_| Arc::new(Metrics::new()));
+    // limited and oversized requests are still logged and counted
+    let service = with_layer(service, rate_limit);
+    let service = with_layer(service, Some(limits.header_size_layer()));
+    let service = with_layer(service, access_log.clone());
+    let service = with_layer(service, metrics.clone().map(MetricsLayer::new));
+    let client_addr = ClientAddrLayer::new(config.proxy.trusted.clone());
+    let service = with_layer(service, Some(client_addr));
+
+    let workers = config.workers.map_or_else(
+        || std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        NonZeroUsize::get,
+    );
+    // With SO_REUSEPORT, each worker has its own runtime, and this one only
+    // runs background tasks like reloading.
+    let rt = if config.reuse_port {
+        RuntimeBuilder::new_current_thread()
+    } else {
+        let mut builder = RuntimeBuilder::new_multi_thread();
+        builder.worker_threads(workers);
+        builder
+    }
+    .enable_all()
+    .thread_name("tunnelbana-worker")
+    .build()
+    .map_err(|e| e!("Invalid runtime config", e))?;
+
+    #[allow(unused_mut)]
+    let mut sources: Vec<ListenerSource> = Vec::new();
+    #[cfg(unix)]
+    sources.extend(inherited_sockets.into_iter().map(ListenerSource::from));
+    if sources.is_empty() {
+        if config.listen.is_empty() {
+            let default = DEFAULT_LISTEN_ADDR
+                .parse()
+                .map_err(|_| e!("Default listen address is invalid"))?;
+            sources.push(ListenerSource::Bind(default));
+        } else {
+            sources.extend(config.listen.drain(..).map(ListenerSource::Bind));
+        }
+    } else if !config.listen.is_empty() {
+        warn!("Using sockets passed by systemd, ignoring configured listen addresses");
+    }
 
-    let service = ServiceBuilder::new()
-        .layer(header_add_mw)
-        .layer(redirect_mw)
-        .layer(etag_mw)
-        .layer(hide_special_files)
-        .layer(set_vary)
-        .layer(set_cache_control)
-        .service(serve_dir);
+    let default_cert = config
+        .default_cert()
+        .map_err(|e| e!("Invalid TLS config", e))?;
+    let sni_certs = std::mem::take(&mut config.tls.sni);
+    let http3_addrs = config
+        .http3
+        .take()
+        .map(|http3| http3.listen)
+        .unwrap_or_default();
+    let tls_listeners = sources.iter().any(ListenerSource::tls);
+    let mut cert_store = None;
+    let tls_acceptor = if tls_listeners || !http3_addrs.is_empty() {
+        if default_cert.is_none() && sni_certs.is_empty() {
+            return Err(e!(
+                "TLS and HTTP/3 listeners require a default certificate and key, or SNI certificates"
+            ));
+        }
+        let certs = CertStore::new(default_cert, sni_certs)
+            .map_err(|e| e!("Failed to load TLS certificates", e))?;
+        let certs = Arc::new(certs);
+        let acceptor = tls_listeners
+            .then(|| certs.acceptor())
+            .transpose()
+            .map_err(|e| e!("Failed to build TLS config", e))?;
+        rt.spawn(certs.clone().watch());
+        cert_store = Some(certs);
+        acceptor
+    } else {
+        if default_cert.is_some() || !sni_certs.is_empty() {
+            warn!("TLS certificates were configured, but no listener uses `tls:`");
+        }
+        None
+    };
 
-    let rt = RuntimeBuilder::new_current_thread()
-        .enable_all()
-        .thread_name("tunnelbana-worker")
-        .build()
-        .map_err(|e| e!("Invalid runtime config", e))?;
+    if let (Some(metrics), Some(config)) = (&metrics, &config.metrics) {
+        let source = ListenerSource::Bind(ListenSpec {
+            addr: config.listen.clone(),
+            tls: false,
+        });
+        let _guard = rt.enter();
+        let listener = Listener::open(source, None, false).map_err(|e| {
+            e!(
+                "Failed to open metrics listener",
+                BindError(config.listen.to_string(), e)
+            )
+        })?;
+        let addr = listener
+            .local_addr()
+            .map_or_else(|_| config.listen.to_string(), |addr| addr.to_string());
+        info!(%addr, "Serving metrics");
+        rt.spawn(metrics.clone().serve(listener));
+    }
 
-    let listener = rt
-        .block_on(TcpListener::bind("0.0.0.0:8080"))
-        .map_err(|e| e!("Failed to bind to port 8080", e))?;
+    let server = Server {
+        service,
+        metrics,
+        shutdown_timeout: config.shutdown_timeout,
+        alt_svc: http3::alt_svc(&http3_addrs),
+        bandwidth: config.rate_limit.as_ref().and_then(|limit| limit.bandwidth),
+        limits,
+    };
+    let shutdown = CancellationToken::new();
+
+    // QUIC endpoints can't share a port between workers, so each address has
+    // one endpoint, served by the main runtime.
+    let mut http3_tasks = Vec::with_capacity(http3_addrs.len());
+    if let Some(certs) = cert_store.as_ref().filter(|_| !http3_addrs.is_empty()) {
+        let quic_config = http3::server_config(certs, &server.limits)
+            .map_err(|e| e!("Failed to build QUIC config", e))?;
+        let _guard = rt.enter();
+        for addr in http3_addrs {
+            let endpoint = http3::bind(addr, quic_config.clone()).map_err(|e| {
+                e!(
+                    "Failed to open HTTP/3 listener",
+                    BindError(addr.to_string(), e)
+                )
+            })?;
+            let addr = endpoint.local_addr().unwrap_or(addr);
+            info!(%addr, "Serving HTTP/3");
+            http3_tasks.push(rt.spawn(http3::serve(server.clone(), endpoint, shutdown.clone())));
+        }
+    }
+    let mut worker_threads = Vec::new();
+    let mut worker_task = None;
+    if config.reuse_port {
+        let sources = sources
+            .into_iter()
+            .map(ListenerSource::into_shared)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e!("Failed to bind shared listener", e))?;
+        let mut worker_runtimes = Vec::with_capacity(workers);
+        for id in 0..workers {
+            let worker_rt = RuntimeBuilder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|e| e!("Invalid runtime config", e))?;
+            let worker_sources = sources
+                .iter()
+                .map(ListenerSource::try_clone)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| e!("Failed to share listener", e))?;
+            let listeners = open_listeners(
+                &worker_rt,
+                worker_sources,
+                tls_acceptor.as_ref(),
+                config.proxy.protocol,
+                true,
+                id == 0,
+            )?;
+            worker_runtimes.push((worker_rt, listeners));
+        }
+        for (id, (worker_rt, listeners)) in worker_runtimes.into_iter().enumerate() {
+            let server = server.clone();
+            let shutdown = shutdown.clone();
+            let thread = std::thread::Builder::new()
+                .name(format!("tunnelbana-worker-{id}"))
+                .spawn(move || worker_rt.block_on(server.serve(listeners, shutdown)))
+                .map_err(|e| e!("Failed to start worker thread", e))?;
+            worker_threads.push(thread);
+        }
+        info!(workers, "Started SO_REUSEPORT workers");
+    } else {
+        let listeners = open_listeners(
+            &rt,
+            sources,
+            tls_acceptor.as_ref(),
+            config.proxy.protocol,
+            false,
+            true,
+        )?;
+        worker_task = Some(rt.spawn(server.serve(listeners, shutdown.clone())));
+        info!(workers, "Started multi-threaded runtime");
+    }
 
-    info!(addr = ?listener.local_addr(), "Listening for new connections");
+    for site in &sites {
+        let site_watcher = site.watch().map_err(|e| e!("Failed to watch site", e))?;
+        rt.spawn(site_watcher);
+    }
+    #[cfg(unix)]
+    rt.spawn(reload_on_sighup(sites, cert_store, access_log));
 
-    let server = ConnBuilder::new(TokioExecutor::new());
-    let graceful = GracefulShutdown::new();
-    let tasks = TaskTracker::new();
     let ctrl_c = vss::shutdown_signal();
-
-    let main_task = rt.spawn(async move {
-        let mut ctrl_c = pin!(ctrl_c);
-        loop {
-            let service = service.clone();
-            let listener_fut = pin!(listener.accept());
-            let selected = futures_util::future::select(listener_fut, ctrl_c.as_mut()).await;
-            let Either::Left((conn, _)) = selected else {
-                info!("Ctrl-C received, starting shutdown");
-                break;
-            };
-            let (stream, peer_addr) = match conn {
-                Ok(v) => v,
-                Err(e) => {
-                    warn!("accept error: {}", e);
-                    continue;
-                }
-            };
-            info!("incoming connection accepted: {}", peer_addr);
-            let stream = TokioIo::new(Box::pin(stream));
-
-            let conn = server
-                .serve_connection_with_upgrades(stream, TowerToHyperService::new(service))
-                .into_owned();
-            let conn = graceful.watch(conn.into_owned());
-
-            tasks.spawn(async move {
-                if let Err(err) = conn.await {
-                    warn!("connection error: {}", err);
-                }
-                debug!("connection dropped: {}", peer_addr);
-            });
-        }
-        shut_down(graceful, tasks).await;
+    let on_ctrl_c = shutdown.clone();
+    rt.spawn(async move {
+        ctrl_c.await;
+        info!("Ctrl-C received, starting shutdown");
+        on_ctrl_c.cancel();
     });
 
-    rt.block_on(main_task)
-        .map_err(|e| e!("Background task failed", e))?;
+    #[cfg(unix)]
+    systemd::notify_ready();
+
+    rt.block_on(shutdown.cancelled());
+    #[cfg(unix)]
+    systemd::notify_stopping();
+
+    if let Some(worker_task) = worker_task {
+        rt.block_on(worker_task)
+            .map_err(|e| e!("Background task failed", e))?;
+    }
+    for task in http3_tasks {
+        rt.block_on(task)
+            .map_err(|e| e!("Background task failed", e))?;
+    }
+    for thread in worker_threads {
+        thread.join().map_err(|_| e!("A worker thread panicked"))?;
+    }
     Ok(())
 }
 
-async fn shut_down(graceful: GracefulShutdown, tasks: TaskTracker) {
-    const SHUTDOWN_GRACEFUL_DEADLINE: Duration = Duration::from_secs(5);
-    match futures_util::future::select(
-        pin!(graceful.shutdown()),
-        pin!(tokio::time::sleep(SHUTDOWN_GRACEFUL_DEADLINE)),
-    )
-    .await
-    {
-        Either::Left(_) => {
-            info!("Gracefully shutdown!");
-        }
-        Either::Right(_) => {
-            error!("Waited 10 seconds for graceful shutdown, aborting...");
-            return;
-        }
+/// Load the root site and every vhost, and build the service which picks between them.
+fn load_sites(config: &Config) -> Result<(Vec<Site>, SiteService), Error> {
+    let derived_etags = config.compression.is_some();
+    let preload = config.preload;
+    let site_options = site_options(config);
+    let mut sites = Vec::with_capacity(site_options.len());
+    let mut services = Vec::with_capacity(site_options.len());
+    for (root, options) in site_options {
+        let site = load_site(root, derived_etags, preload)?;
+        let service = site
+            .service(&options)
+            .map_err(|e| e!("Failed to build site service", e))?;
+        sites.push(site);
+        services.push(service);
+    }
+    if config.vhosts.is_empty() {
+        let service = services.pop().ok_or_else(|| {
+            e!("Expected a directory to serve, as an argument, `root` or `[[vhosts]]` in the config file")
+        })?;
+        return Ok((sites, service));
     }
 
-    tasks.close();
+    let vhosts = route_hosts(config, &services)?;
+    info!(sites = sites.len(), "Serving virtual hosts");
+    Ok((sites, BoxCloneSyncService::new(vhosts)))
+}
 
-    match futures_util::future::select(
-        pin!(tasks.wait()),
-        pin!(tokio::time::sleep(SHUTDOWN_GRACEFUL_DEADLINE)),
-    )
-    .await
-    {
-        Either::Left(_) => {
-            info!("Gracefully shutdown!");
-        }
-        Either::Right(_) => {
-            error!("Waited 10 seconds for graceful shutdown, aborting...");
+/// The root of each site in `config` and the options it's served with,
+/// starting with `root` if there is one, followed by the vhosts.
+fn site_options(config: &Config) -> Vec<(&Path, ServeOptions)> {
+    let options = ServeOptions::from(config);
+    let vhosts = config.vhosts.iter().map(|vhost| {
+        let options = ServeOptions {
+            spa: vhost.spa.unwrap_or(config.spa),
+            ..options.clone()
+        };
+        (vhost.root.as_path(), options)
+    });
+    config
+        .root
+        .as_deref()
+        .map(|root| (root, options.clone()))
+        .into_iter()
+        .chain(vhosts)
+        .collect()
+}
+
+/// Route each host in `config` to its entry in `sites`, which are in the
+/// order [`site_options`] returns them.
+fn route_hosts<S: Clone>(config: &Config, sites: &[S]) -> Result<VirtualHosts<S>, Error> {
+    let (default, vhosts) = match (&config.root, sites) {
+        (Some(_), [default, vhosts @ ..]) => (Some(default), vhosts),
+        _ => (None, sites),
+    };
+    let mut builder = VirtualHosts::builder();
+    for (vhost, site) in config.vhosts.iter().zip(vhosts) {
+        builder = builder
+            .hosts(vhost.hosts.iter().map(String::as_str), site)
+            .map_err(|e| e!("Invalid vhost config", e))?;
+    }
+    builder = match (default, &config.default_host) {
+        (Some(site), _) => builder.default_service(site.clone()),
+        (None, Some(host)) => builder
+            .default_host(host)
+            .map_err(|e| e!("Invalid vhost config", e))?,
+        (None, None) => builder,
+    };
+    Ok(builder.build())
+}
+
+/// Print how the sites in `config` would handle the request in `args`.
+fn explain_request(config: &Config, args: &ExplainArgs) -> Result<(), Error> {
+    let request = args.request().map_err(|e| e!("Invalid request", e))?;
+    let (sites, service) = load_sites(config)?;
+    let options: Vec<ServeOptions> = site_options(config)
+        .into_iter()
+        .map(|(_, options)| options)
+        .collect();
+    let indexes: Vec<usize> = (0..sites.len()).collect();
+    let site = route_hosts(config, &indexes)?
+        .route(&request)
+        .map(|&i| (&sites[i], &options[i]));
+
+    let rt = RuntimeBuilder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| e!("Invalid runtime config", e))?;
+    let explanation = rt.block_on(explain::explain(site, service, request));
+    print!("{explanation}");
+    Ok(())
+}
+
+fn load_site(
+    root: &Path,
+    derived_etags: bool,
+    preload: Option<PreloadConfig>,
+) -> Result<Site, Error> {
+    if !(root.is_dir() || (root.is_file() && ArchiveKind::of(root).is_some())) {
+        return Err(e!(
+            "Expected the site root to be a directory or a .tar, .tar.zst or .zip archive",
+            SiteRootError(
+                root.to_path_buf(),
+                IoError::from(IoErrorKind::NotADirectory).into()
+            )
+        ));
+    }
+    let root = root.canonicalize().map_err(|e| {
+        e!(
+            "Could not canonicalize site root",
+            SiteRootError(root.to_path_buf(), e.into())
+        )
+    })?;
+    Site::load(root.clone(), derived_etags, preload)
+        .map_err(|e| e!("Failed to load site", SiteRootError(root, e.into())))
+}
+
+/// Open a worker's listeners inside `rt`, so they use its IO driver.
+fn open_listeners(
+    rt: &Runtime,
+    sources: Vec<ListenerSource>,
+    tls_acceptor: Option<&TlsAcceptor>,
+    proxy_protocol: bool,
+    reuse_port: bool,
+    log: bool,
+) -> Result<Vec<Listener>, Error> {
+    let _guard = rt.enter();
+    let mut listeners = Vec::with_capacity(sources.len());
+    for source in sources {
+        let tls = source.tls().then(|| tls_acceptor.cloned()).flatten();
+        let name = source.to_string();
+        let listener = Listener::open(source, tls, reuse_port)
+            .map_err(|e| e!("Failed to open listener", BindError(name.clone(), e)))?
+            .with_proxy_protocol(proxy_protocol);
+        if log {
+            let addr = listener.local_addr().map_or(name, |addr| addr.to_string());
+            info!(%addr, "Listening for new connections");
         }
+        listeners.push(listener);
     }
+    Ok(listeners)
 }
 
-fn read_with_default_if_nonexistent(path: impl AsRef<Path>) -> Result<String, IoError> {
-    match std::fs::read_to_string(path.as_ref()) {
-        Ok(v) => Ok(v),
-        Err(e) => match e.kind() {
-            IoErrorKind::NotFound => Ok(String::new()),
-            _ => Err(e),
-        },
+/// Reopen the access log, and reload the TLS certificates and sites, whenever we receive a SIGHUP.
+/// The log comes first, since re-hashing every site can take a while after logrotate.
+#[cfg(unix)]
+async fn reload_on_sighup(
+    sites: Vec<Site>,
+    cert_store: Option<Arc<CertStore>>,
+    access_log: Option<AccessLogLayer>,
+) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("SIGHUP received, reloading");
+        if let Some(Err(e)) = access_log.as_ref().map(AccessLogLayer::reopen) {
+            error!("Failed to reopen access log: {}", e);
+        }
+        if let Some(cert_store) = &cert_store {
+            cert_store.reload_changed();
+        }
+        for site in &sites {
+            site.reload_in_background().await;
+        }
     }
 }
@@ -0,0 +1,450 @@
+//! Files held in memory, and a service which serves them the way `ServeDir`
+//! serves a directory.
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    future::{Ready, ready},
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    path::Path,
+    sync::Arc,
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use futures_util::future::Either;
+use http::{HeaderValue, Method, Request, Response, StatusCode, header};
+use http_body_util::{BodyExt, Empty, Full};
+use percent_encoding::percent_decode_str;
+use tower::Service;
+use tunnelbana_etags::{ETagMap, TagMapBuildError};
+
+use crate::{
+    compress::{Encoding, preferred_encoding},
+    config::{Precompressed, PreloadConfig},
+    listing::Entry,
+    site::{RequestBody, ResponseBody, SiteService},
+};
+
+#[derive(Debug)]
+/// A tree of files held in memory, keyed by their URL path, like `/index.html`.
+pub struct MemoryFs {
+    files: HashMap<String, Bytes>,
+    /// Every directory which holds a file, like `/docs`
+    dirs: HashSet<String>,
+    /// Sent as `Last-Modified` with every file
+    modified: Option<HeaderValue>,
+    size: usize,
+}
+
+impl MemoryFs {
+    /// An empty tree, whose files were all last modified at `modified`.
+    pub fn new(modified: Option<SystemTime>) -> Self {
+        let modified =
+            modified.and_then(|time| HeaderValue::from_str(&httpdate::fmt_http_date(time)).ok());
+        Self {
+            files: HashMap::new(),
+            dirs: HashSet::new(),
+            modified,
+            size: 0,
+        }
+    }
+
+    /// Add a file at `path`, relative to the root of the tree.
+    /// Returns `false` if the path leaves the tree or names the root itself.
+    pub fn insert(&mut self, path: &str, data: Bytes) -> bool {
+        let Some(key) = normalize(path).filter(|key| key != "/") else {
+            return false;
+        };
+        let mut dir = key.as_str();
+        while let Some((parent, _)) = dir.rsplit_once('/') {
+            if parent.is_empty() || !self.dirs.insert(parent.to_owned()) {
+                break;
+            }
+            dir = parent;
+        }
+        self.size += data.len();
+        if let Some(old) = self.files.insert(key, data) {
+            self.size -= old.len();
+        }
+        true
+    }
+
+    /// Read the files in `root` into memory while hashing them, until a file
+    /// is over `limits.max_file_size` or the total would go over
+    /// `limits.max_size`. A file whose precompressed siblings don't all fit
+    /// is left on disk with them, so it's never served without them.
+    /// # Errors
+    /// If a file can't be read or hashed.
+    pub fn preload(
+        root: &Path,
+        limits: PreloadConfig,
+        derive_missing: bool,
+    ) -> Result<(Self, ETagMap), TagMapBuildError> {
+        let mut fs = Self::new(Some(SystemTime::now()));
+        let mut skipped = HashSet::new();
+        let etags = ETagMap::with_loader(root, derive_missing, |key, path| {
+            let len = std::fs::metadata(path)?.len();
+            let total = u64::try_from(fs.size).unwrap_or(u64::MAX);
+            if len > limits.max_file_size || total.saturating_add(len) > limits.max_size {
+                skipped.insert(key.to_owned());
+                return Ok(None);
+            }
+            let data = Bytes::from(std::fs::read(path)?);
+            fs.insert(key, data.clone());
+            Ok(Some(data))
+        })?;
+
+        let partial: Vec<String> = fs
+            .files
+            .keys()
+            .filter(|key| {
+                Encoding::ALL
+                    .iter()
+                    .any(|encoding| skipped.contains(&format!("{key}{}", encoding.extension())))
+            })
+            .cloned()
+            .collect();
+        for key in &partial {
+            if let Some(data) = fs.files.remove(key) {
+                fs.size -= data.len();
+            }
+        }
+        info!(
+            root = ?root,
+            files = fs.len(),
+            bytes = fs.size,
+            on_disk = skipped.len() + partial.len(),
+            "Preloaded site"
+        );
+        Ok((fs, etags))
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.files.contains_key(path)
+    }
+
+    /// Whether `dir`, like `/docs/`, holds any files.
+    pub fn is_dir(&self, dir: &str) -> bool {
+        let dir = dir.trim_end_matches('/');
+        dir.is_empty() || self.dirs.contains(dir)
+    }
+
+    /// The files and directories directly in `dir`, like `/docs/`, or `None`
+    /// if it isn't a directory.
+    pub fn list(&self, dir: &str) -> Option<Vec<Entry>> {
+        if !self.is_dir(dir) {
+            return None;
+        }
+        let modified = self
+            .modified
+            .as_ref()
+            .and_then(|modified| httpdate::parse_http_date(modified.to_str().ok()?).ok());
+        let prefix = format!("{}/", dir.trim_end_matches('/'));
+        let mut dirs = HashSet::new();
+        let mut entries = Vec::new();
+        for (key, data) in &self.files {
+            let Some(rest) = key.strip_prefix(&prefix) else {
+                continue;
+            };
+            match rest.split_once('/') {
+                Some((name, _)) if dirs.insert(name) => entries.push(Entry {
+                    name: name.to_owned(),
+                    dir: true,
+                    size: 0,
+                    modified,
+                }),
+                Some(_) => {}
+                None => entries.push(Entry {
+                    name: rest.to_owned(),
+                    dir: false,
+                    size: data.len() as u64,
+                    modified,
+                }),
+            }
+        }
+        Some(entries)
+    }
+
+    /// The total size of every file, in bytes.
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Read the file at `path` as UTF-8, or `None` if there isn't one.
+    /// # Errors
+    /// If the file isn't valid UTF-8.
+    pub fn read_to_string(&self, path: &str) -> Result<Option<String>, IoError> {
+        self.files
+            .get(path)
+            .map(|data| {
+                String::from_utf8(data.to_vec())
+                    .map_err(|e| IoError::new(IoErrorKind::InvalidData, e))
+            })
+            .transpose()
+    }
+
+    /// Hash every file, like [`ETagMap::new`] does for a directory.
+    /// # Errors
+    /// If a tag can't be built.
+    pub fn etags(&self, derive_missing: bool) -> Result<ETagMap, TagMapBuildError> {
+        let files = self
+            .files
+            .iter()
+            .map(|(path, data)| (path.as_str(), data.as_ref()));
+        ETagMap::from_files(files, derive_missing)
+    }
+}
+
+/// Turn a `/`-separated path into a key like `/docs/index.html`, or `None`
+/// if it would leave the root.
+fn normalize(path: &str) -> Option<String> {
+    let mut key = String::with_capacity(path.len() + 1);
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            segment if segment.contains('\\') => return None,
+            segment => {
+                key.push('/');
+                key.push_str(segment);
+            }
+        }
+    }
+    if key.is_empty() {
+        key.push('/');
+    }
+    Some(key)
+}
+
+#[derive(Clone)]
+/// A [`tower::Service`] which serves files from a [`MemoryFs`], which may be
+/// replaced while it's being served.
+///
+/// Like `ServeDir`, it appends `index.html` to directories, redirects
+/// directories without a trailing slash, serves the precompressed siblings
+/// the client accepts, and calls its fallback when there's no file.
+pub struct ServeMemory {
+    files: Arc<ArcSwap<MemoryFs>>,
+    precompressed: Precompressed,
+    /// Serve this file for every request, like `ServeFile`
+    file: Option<Arc<str>>,
+    fallback: Option<SiteService>,
+}
+
+impl ServeMemory {
+    /// Serve every file in `files`.
+    pub const fn dir(files: Arc<ArcSwap<MemoryFs>>, precompressed: Precompressed) -> Self {
+        Self {
+            files,
+            precompressed,
+            file: None,
+            fallback: None,
+        }
+    }
+
+    /// Serve the file at `path` in `files` for every request.
+    pub fn file(files: Arc<ArcSwap<MemoryFs>>, precompressed: Precompressed, path: &str) -> Self {
+        Self {
+            files,
+            precompressed,
+            file: normalize(path).map(Into::into),
+            fallback: None,
+        }
+    }
+
+    /// Call `fallback` when there's no file to serve, rather than sending an empty 404.
+    #[must_use]
+    pub fn fallback(mut self, fallback: SiteService) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    fn respond<B>(&self, req: &Request<B>) -> Option<Response<ResponseBody>> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            let mut response = empty_response(StatusCode::METHOD_NOT_ALLOWED);
+            response
+                .headers_mut()
+                .insert(header::ALLOW, HeaderValue::from_static("GET,HEAD"));
+            return Some(response);
+        }
+
+        let files = self.files.load();
+        let key = if let Some(file) = &self.file {
+            file.to_string()
+        } else {
+            let path = req.uri().path();
+            let decoded = percent_decode_str(path).decode_utf8().ok()?;
+            let key = normalize(&decoded)?;
+            if path.ends_with('/') || key == "/" {
+                format!("{}/index.html", key.trim_end_matches('/'))
+            } else if files.dirs.contains(&key) {
+                return Some(redirect_to_dir(req));
+            } else {
+                key
+            }
+        };
+
+        let data = files.files.get(&key)?;
+        let available = |encoding: Encoding| {
+            self.precompressed.enabled(encoding)
+                && files
+                    .files
+                    .contains_key(&format!("{key}{}", encoding.extension()))
+        };
+        let (body, encoding) = match preferred_encoding(req.headers(), available) {
+            Some(encoding) => {
+                let sibling = format!("{key}{}", encoding.extension());
+                (files.files[&sibling].clone(), Some(encoding))
+            }
+            None => (data.clone(), None),
+        };
+
+        let mime = mime_guess::from_path(&key).first_or_octet_stream();
+        let mut response = Response::new(if req.method() == Method::HEAD {
+            ResponseBody::new(Empty::new().map_err(|e| match e {}))
+        } else {
+            ResponseBody::new(Full::new(body.clone()).map_err(|e| match e {}))
+        });
+        let headers = response.headers_mut();
+        if let Ok(content_type) = HeaderValue::from_str(mime.as_ref()) {
+            headers.insert(header::CONTENT_TYPE, content_type);
+        }
+        headers.insert(header::CONTENT_LENGTH, body.len().into());
+        if let Some(encoding) = encoding {
+            headers.insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.name()),
+            );
+        }
+        if let Some(modified) = &files.modified {
+            headers.insert(header::LAST_MODIFIED, modified.clone());
+        }
+        Some(response)
+    }
+}
+
+/// A `307` to the requested path with a trailing slash, keeping the query.
+fn redirect_to_dir<B>(req: &Request<B>) -> Response<ResponseBody> {
+    let mut location = format!("{}/", req.uri().path());
+    if let Some(query) = req.uri().query() {
+        location.push('?');
+        location.push_str(query);
+    }
+    let mut response = empty_response(StatusCode::TEMPORARY_REDIRECT);
+    if let Ok(location) = HeaderValue::try_from(location) {
+        response.headers_mut().insert(header::LOCATION, location);
+    }
+    response
+}
+
+pub fn empty_response(status: StatusCode) -> Response<ResponseBody> {
+    let mut response = Response::new(ResponseBody::new(Empty::new().map_err(|e| match e {})));
+    *response.status_mut() = status;
+    response
+}
+
+impl Service<Request<RequestBody>> for ServeMemory {
+    type Error = Infallible;
+    type Future = Either<
+        Ready<Result<Response<ResponseBody>, Infallible>>,
+        <SiteService as Service<Request<RequestBody>>>::Future,
+    >;
+    type Response = Response<ResponseBody>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<RequestBody>) -> Self::Future {
+        if let Some(response) = self.respond(&req) {
+            return Either::Left(ready(Ok(response)));
+        }
+        let Some(fallback) = &mut self.fallback else {
+            return Either::Left(ready(Ok(empty_response(StatusCode::NOT_FOUND))));
+        };
+        Either::Right(fallback.call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn request(path: &str, accept_encoding: &str) -> Request<RequestBody> {
+        Request::get(path)
+            .header(header::ACCEPT_ENCODING, accept_encoding)
+            .body(RequestBody::new(Empty::new().map_err(|e| match e {})))
+            .unwrap()
+    }
+
+    async fn body(response: Response<ResponseBody>) -> Bytes {
+        response.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[tokio::test]
+    async fn serves_like_serve_dir() {
+        let mut fs = MemoryFs::new(None);
+        assert!(fs.insert("./index.html", Bytes::from_static(b"home")));
+        assert!(fs.insert("docs/index.html", Bytes::from_static(b"docs")));
+        assert!(fs.insert("docs/index.html.br", Bytes::from_static(b"br docs")));
+        assert!(fs.insert("404.html", Bytes::from_static(b"missing")));
+        assert!(!fs.insert("../etc/passwd", Bytes::new()));
+        let files = Arc::new(ArcSwap::from_pointee(fs));
+        let precompressed = Precompressed::default();
+        let not_found = ServeMemory::file(files.clone(), precompressed, "/404.html");
+        let svc = ServeMemory::dir(files, precompressed).fallback(SiteService::new(not_found));
+
+        let response = svc.clone().oneshot(request("/", "")).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html");
+        assert_eq!(body(response).await, "home");
+
+        let response = svc.clone().oneshot(request("/docs?a=1", "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(response.headers()[header::LOCATION], "/docs/?a=1");
+
+        let response = svc
+            .clone()
+            .oneshot(request("/docs/", "gzip, br"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "br");
+        assert_eq!(body(response).await, "br docs");
+
+        let response = svc.clone().oneshot(request("/%2e%2e/x", "")).await.unwrap();
+        assert_eq!(body(response).await, "missing");
+        let response = svc.oneshot(request("/nope", "")).await.unwrap();
+        assert_eq!(body(response).await, "missing");
+    }
+
+    #[test]
+    fn preload_leaves_large_files_on_disk() {
+        let root = std::env::temp_dir().join(format!("tunnelbana-preload-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("index.html"), "small").unwrap();
+        std::fs::write(root.join("large.bin"), "far too large").unwrap();
+        // the sibling is too large, so the file has to stay on disk with it
+        std::fs::write(root.join("docs/app.js"), "small").unwrap();
+        std::fs::write(root.join("docs/app.js.br"), "far too large").unwrap();
+
+        let limits = PreloadConfig {
+            max_size: 1024,
+            max_file_size: 8,
+        };
+        let (fs, etags) = MemoryFs::preload(&root, limits, false).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(fs.len(), 1);
+        assert_eq!(fs.size(), 5);
+        assert!(fs.files.contains_key("/index.html"));
+        assert_eq!(etags.len(), 4);
+        assert!(etags["/docs/app.js"].brotli.is_some());
+    }
+}
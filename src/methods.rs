@@ -0,0 +1,174 @@
+//! Which methods a site answers. Its files can only be read, so `GET` and
+//! `HEAD` are served, `OPTIONS` is answered with what's allowed, and
+//! anything else gets a `405 Method Not Allowed`.
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use http::{HeaderValue, Method, Request, Response, StatusCode, header};
+use http_body_util::{BodyExt, Empty};
+use tower::{Layer, Service};
+
+use crate::{
+    memfs::empty_response,
+    site::{RequestBody, ResponseBody},
+};
+
+/// The methods every path allows, as sent in `Allow`.
+const ALLOW: &str = "GET, HEAD, OPTIONS";
+
+#[derive(Clone, Copy, Debug)]
+/// Response extension marking an `OPTIONS` answer or a 405, which this layer
+/// sent rather than the site.
+pub struct MethodAnswered;
+
+#[derive(Clone, Copy, Debug, Default)]
+/// A [`tower::Layer`] which answers `OPTIONS` and disallowed methods itself,
+/// and passes `HEAD` on as a `GET`, so it gets the same headers, `ETag` and
+/// redirects, and only drops the body.
+pub struct MethodsLayer;
+
+impl<S> Layer<S> for MethodsLayer {
+    type Service = Methods<S>;
+
+    fn layer(&self, inner: S) -> Methods<S> {
+        Methods { inner }
+    }
+}
+
+#[derive(Clone)]
+/// A [`tower::Service`] which only passes `GET` and `HEAD` to the service it wraps.
+pub struct Methods<S> {
+    inner: S,
+}
+
+impl<S> Service<Request<RequestBody>> for Methods<S>
+where
+    S: Service<Request<RequestBody>, Response = Response<ResponseBody>, Error = Infallible>,
+{
+    type Error = Infallible;
+    type Future = ResponseFuture<S::Future>;
+    type Response = Response<ResponseBody>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<RequestBody>) -> Self::Future {
+        let status = match *req.method() {
+            Method::GET => {
+                return ResponseFuture::Inner {
+                    inner: self.inner.call(req),
+                    head: false,
+                };
+            }
+            Method::HEAD => {
+                *req.method_mut() = Method::GET;
+                return ResponseFuture::Inner {
+                    inner: self.inner.call(req),
+                    head: true,
+                };
+            }
+            Method::OPTIONS => StatusCode::NO_CONTENT,
+            _ => StatusCode::METHOD_NOT_ALLOWED,
+        };
+        let mut response = empty_response(status);
+        response
+            .headers_mut()
+            .insert(header::ALLOW, HeaderValue::from_static(ALLOW));
+        response.extensions_mut().insert(MethodAnswered);
+        ResponseFuture::Answered(Some(response))
+    }
+}
+
+#[pin_project::pin_project(project = ResponseFutureProj)]
+/// Future which is either the wrapped service's response, or one this layer answered.
+pub enum ResponseFuture<F> {
+    Inner {
+        #[pin]
+        inner: F,
+        /// Whether the request was a `HEAD`, so the body is dropped
+        head: bool,
+    },
+    Answered(Option<Response<ResponseBody>>),
+}
+
+impl<F> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<ResponseBody>, Infallible>>,
+{
+    type Output = Result<Response<ResponseBody>, Infallible>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            ResponseFutureProj::Inner { inner, head } => {
+                let Ok(response) = std::task::ready!(inner.poll(cx));
+                if !*head {
+                    return Poll::Ready(Ok(response));
+                }
+                // the headers, `Content-Length` included, still describe the GET
+                let response =
+                    response.map(|_| ResponseBody::new(Empty::new().map_err(|e| match e {})));
+                Poll::Ready(Ok(response))
+            }
+            ResponseFutureProj::Answered(response) => Poll::Ready(Ok(response
+                .take()
+                .expect("ResponseFuture polled after completion"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn request(method: Method) -> Request<RequestBody> {
+        Request::builder()
+            .method(method)
+            .uri("/file.txt")
+            .body(RequestBody::new(Empty::new().map_err(|e| match e {})))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_by_method() {
+        let svc = MethodsLayer.layer(tower::service_fn(|req: Request<RequestBody>| {
+            let body = format!("{} body", req.method());
+            let mut response = Response::new(ResponseBody::new(
+                Full::new(Bytes::from(body)).map_err(|e| match e {}),
+            ));
+            response
+                .headers_mut()
+                .insert(header::CONTENT_LENGTH, HeaderValue::from_static("8"));
+            std::future::ready(Ok(response))
+        }));
+
+        let response = svc.clone().oneshot(request(Method::GET)).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "GET body");
+
+        let response = svc.clone().oneshot(request(Method::HEAD)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "8");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.is_empty());
+
+        let response = svc.clone().oneshot(request(Method::OPTIONS)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(response.extensions().get::<MethodAnswered>().is_some());
+        assert_eq!(response.headers()[header::ALLOW], "GET, HEAD, OPTIONS");
+
+        for method in [Method::POST, Method::PUT, Method::DELETE, Method::PATCH] {
+            let response = svc.clone().oneshot(request(method)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+            assert_eq!(response.headers()[header::ALLOW], "GET, HEAD, OPTIONS");
+        }
+    }
+}
@@ -0,0 +1,445 @@
+//! Prometheus metrics for the server and the tunnelbana layers, served on
+//! their own listener.
+use std::{
+    convert::Infallible,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use bytes::Bytes;
+use http::{HeaderValue, Method, Request, Response, StatusCode, header};
+use http_body_util::Full;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder as ConnBuilder,
+};
+use prometheus_client::{
+    encoding::{EncodeLabelSet, text::encode},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{Histogram, exponential_buckets},
+    },
+    registry::Registry,
+};
+use tokio::sync::mpsc;
+use tower::{Layer, Service};
+use tunnelbana_headers::HeadersApplied;
+use tunnelbana_redirects::Redirected;
+
+use crate::{
+    listener::Listener,
+    observe::{BodyObserver, ObservedBody, Source},
+    rate_limit::Limited,
+};
+
+const METRICS_PATH: &str = "/metrics";
+const METRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+const METRICS_ACCEPT_QUEUE_LEN: usize = 8;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    class: &'static str,
+    source: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RedirectLabels {
+    rule: Arc<str>,
+    status: u16,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RuleLabels {
+    rule: Arc<str>,
+}
+
+/// Every metric tunnelbana exports.
+pub struct Metrics {
+    registry: Registry,
+    requests: Family<RequestLabels, Counter>,
+    request_duration: Histogram,
+    response_bytes: Counter,
+    open_connections: Gauge,
+    refused_connections: Counter,
+    redirects: Family<RedirectLabels, Counter>,
+    hidden: Counter,
+    not_modified: Counter,
+    headers_applied: Family<RuleLabels, Counter>,
+    rate_limited: Family<RuleLabels, Counter>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut registry = Registry::with_prefix("tunnelbana");
+        let requests = Family::default();
+        registry.register(
+            "http_requests",
+            "Requests served, by status class and what produced the response",
+            requests.clone(),
+        );
+        // 1ms to ~16s
+        let request_duration = Histogram::new(exponential_buckets(0.001, 2.0, 15));
+        registry.register(
+            "http_request_duration_seconds",
+            "Time from receiving a request until its response body was sent",
+            request_duration.clone(),
+        );
+        let response_bytes = Counter::default();
+        registry.register(
+            "http_response_body_bytes",
+            "Response body bytes sent",
+            response_bytes.clone(),
+        );
+        let open_connections = Gauge::default();
+        registry.register(
+            "open_connections",
+            "Client connections currently open",
+            open_connections.clone(),
+        );
+        let refused_connections = Counter::default();
+        registry.register(
+            "refused_connections",
+            "Client connections closed straight away, because too many were open",
+            refused_connections.clone(),
+        );
+        let redirects = Family::default();
+        registry.register(
+            "redirects",
+            "Responses from _redirects rules. A 500 means the rendered target was an invalid header",
+            redirects.clone(),
+        );
+        let hidden = Counter::default();
+        registry.register(
+            "hidden_path_hits",
+            "Requests for hidden paths",
+            hidden.clone(),
+        );
+        let not_modified = Counter::default();
+        registry.register(
+            "etag_not_modified",
+            "304 Not Modified responses sent for a matching If-None-Match",
+            not_modified.clone(),
+        );
+        let headers_applied = Family::default();
+        registry.register(
+            "headers_applied",
+            "Responses which had a _headers group applied",
+            headers_applied.clone(),
+        );
+        let rate_limited = Family::default();
+        registry.register(
+            "rate_limited",
+            "429 Too Many Requests responses sent, by rate limit rule",
+            rate_limited.clone(),
+        );
+        Self {
+            registry,
+            requests,
+            request_duration,
+            response_bytes,
+            open_connections,
+            refused_connections,
+            redirects,
+            hidden,
+            not_modified,
+            headers_applied,
+            rate_limited,
+        }
+    }
+
+    /// Count an open connection until the returned guard is dropped.
+    pub fn connection_opened(self: &Arc<Self>) -> ConnectionGuard {
+        self.open_connections.inc();
+        ConnectionGuard(self.clone())
+    }
+
+    /// Count a connection which was refused.
+    pub fn connection_refused(&self) {
+        self.refused_connections.inc();
+    }
+
+    fn record_response<B>(&self, response: &Response<B>) -> Source {
+        let source = Source::of(response);
+        let extensions = response.extensions();
+        if let Some(redirected) = extensions.get::<Redirected>() {
+            let labels = RedirectLabels {
+                rule: redirected.rule.clone(),
+                status: response.status().as_u16(),
+            };
+            self.redirects.get_or_create(&labels).inc();
+        }
+        if let Some(applied) = extensions.get::<HeadersApplied>() {
+            let labels = RuleLabels {
+                rule: applied.rule.clone(),
+            };
+            self.headers_applied.get_or_create(&labels).inc();
+        }
+        if let Some(limited) = extensions.get::<Limited>() {
+            let labels = RuleLabels {
+                rule: limited.route.clone(),
+            };
+            self.rate_limited.get_or_create(&labels).inc();
+        }
+        match source {
+            Source::Hidden => _ = self.hidden.inc(),
+            Source::NotModified => _ = self.not_modified.inc(),
+            Source::Redirect
+            | Source::Denied
+            | Source::Limited
+            | Source::Method
+            | Source::Misdirected
+            | Source::HeadersTooLarge
+            | Source::File => {}
+        }
+        source
+    }
+
+    fn render(&self) -> Response<Full<Bytes>> {
+        let mut text = String::new();
+        if let Err(e) = encode(&mut text, &self.registry) {
+            error!("failed to encode metrics: {}", e);
+            return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        let mut response = Response::new(Full::new(Bytes::from(text)));
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(METRICS_CONTENT_TYPE),
+        );
+        response
+    }
+
+    fn respond<B>(&self, req: &Request<B>) -> Response<Full<Bytes>> {
+        if req.uri().path() != METRICS_PATH {
+            return status_response(StatusCode::NOT_FOUND);
+        }
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return status_response(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        self.render()
+    }
+
+    /// Serve `/metrics` on `listener` forever.
+    pub async fn serve(self: Arc<Self>, listener: Listener) {
+        let (conn_tx, mut conn_rx) = mpsc::channel(METRICS_ACCEPT_QUEUE_LEN);
+        let acceptor = tokio::spawn(listener.accept_into(conn_tx));
+        let server = ConnBuilder::new(TokioExecutor::new());
+        while let Some(accepted) = conn_rx.recv().await {
+            let metrics = self.clone();
+            let server = server.clone();
+            tokio::spawn(async move {
+                let stream = match accepted.into_stream().await {
+                    Ok((stream, _)) => TokioIo::new(stream),
+                    Err(e) => {
+                        debug!("metrics handshake failed: {}", e);
+                        return;
+                    }
+                };
+                let service = hyper::service::service_fn(move |req| {
+                    let response = metrics.respond(&req);
+                    async move { Ok::<_, Infallible>(response) }
+                });
+                if let Err(e) = server.serve_connection(stream, service).await {
+                    debug!("metrics connection error: {}", e);
+                }
+            });
+        }
+        acceptor.abort();
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::default());
+    *response.status_mut() = status;
+    response
+}
+
+/// Decrements the open connection count when dropped.
+pub struct ConnectionGuard(Arc<Metrics>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.open_connections.dec();
+    }
+}
+
+#[derive(Clone)]
+/// A [`tower::Layer`] which records metrics for every request.
+pub struct MetricsLayer {
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsLayer {
+    pub const fn new(metrics: Arc<Metrics>) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> MetricsService<S> {
+        MetricsService {
+            metrics: self.metrics.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+/// A [`tower::Service`] which records metrics for the service it wraps.
+pub struct MetricsService<S> {
+    metrics: Arc<Metrics>,
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for MetricsService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+{
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+    type Response = Response<ObservedBody<ResBody, PendingRequest>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        ResponseFuture {
+            inner: self.inner.call(req),
+            start: Instant::now(),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[pin_project::pin_project]
+/// Future which records per-layer metrics once the response is ready.
+pub struct ResponseFuture<F> {
+    #[pin]
+    inner: F,
+    start: Instant,
+    metrics: Arc<Metrics>,
+}
+
+impl<F, B, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    type Output = Result<Response<ObservedBody<B, PendingRequest>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let response = std::task::ready!(this.inner.poll(cx))?;
+        let source = this.metrics.record_response(&response);
+        let pending = PendingRequest {
+            labels: RequestLabels {
+                class: status_class(response.status()),
+                source: source.as_str(),
+            },
+            start: *this.start,
+            metrics: this.metrics.clone(),
+        };
+        Poll::Ready(Ok(response.map(|body| ObservedBody::new(body, pending))))
+    }
+}
+
+/// A request whose count, duration and size are recorded once its body has been sent.
+pub struct PendingRequest {
+    labels: RequestLabels,
+    start: Instant,
+    metrics: Arc<Metrics>,
+}
+
+impl BodyObserver for PendingRequest {
+    fn finished(self, bytes: u64) {
+        self.metrics.requests.get_or_create(&self.labels).inc();
+        self.metrics
+            .request_duration
+            .observe(self.start.elapsed().as_secs_f64());
+        self.metrics.response_bytes.inc_by(bytes);
+    }
+}
+
+const fn status_class(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{BodyExt, Empty};
+    use tower::{ServiceBuilder, ServiceExt};
+
+    use super::*;
+    use crate::{
+        config::Config,
+        site::{RequestBody, ServeOptions, Site},
+    };
+
+    fn request(path: &str, if_none_match: Option<&HeaderValue>) -> Request<RequestBody> {
+        let mut req = Request::get(path);
+        if let Some(tag) = if_none_match {
+            req = req.header(header::IF_NONE_MATCH, tag);
+        }
+        req.body(RequestBody::new(Empty::new().map_err(|e| match e {})))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn counts_each_layer() {
+        let root = std::env::temp_dir().join(format!("tunnelbana-metrics-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "hello").unwrap();
+        std::fs::write(root.join("_headers"), "/\n  X-Frame-Options: DENY\n").unwrap();
+        std::fs::write(root.join("_redirects"), "/old/{*rest} /new/{rest}\n").unwrap();
+        let site = Site::load(root.clone(), false, None).unwrap();
+        let site = site
+            .service(&ServeOptions::from(&Config::default()))
+            .unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let metrics = Arc::new(Metrics::new());
+        let svc = ServiceBuilder::new()
+            .layer(MetricsLayer::new(metrics.clone()))
+            .service(site);
+        let send = |req| async {
+            let response = svc.clone().oneshot(req).await.unwrap();
+            let status = response.status();
+            let etag = response.headers().get(header::ETAG).cloned();
+            response.into_body().collect().await.unwrap();
+            (status, etag)
+        };
+
+        // DEL is allowed in a path, but not in the rendered `Location` header
+        let (status, _) = send(request("/old/\u{7f}", None)).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        let (status, _) = send(request("/_redirects", None)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (_, etag) = send(request("/", None)).await;
+        let (status, _) = send(request("/", etag.as_ref())).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+
+        let get = Request::get(METRICS_PATH).body(()).unwrap();
+        let text = metrics.respond(&get).into_body().collect().await.unwrap();
+        let text = String::from_utf8(text.to_bytes().to_vec()).unwrap();
+        for line in [
+            r#"tunnelbana_redirects_total{rule="/old/{*rest}",status="500"} 1"#,
+            r#"tunnelbana_http_requests_total{class="5xx",source="redirect"} 1"#,
+            "tunnelbana_hidden_path_hits_total 1",
+            "tunnelbana_etag_not_modified_total 1",
+            r#"tunnelbana_headers_applied_total{rule="/"} 2"#,
+        ] {
+            assert!(text.contains(line), "missing {line} in {text}");
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Working out what happened to a response, shared by the access log and metrics.
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Buf;
+use http::Response;
+use http_body::{Body, Frame, SizeHint};
+use tunnelbana_etags::NotModified;
+use tunnelbana_hidepaths::Hidden;
+use tunnelbana_redirects::Redirected;
+
+use crate::{
+    connections::HeadersTooLarge, ip_filter::Denied, methods::MethodAnswered, rate_limit::Limited,
+    vhost::Misdirected,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Which part of the stack produced a response.
+pub enum Source {
+    Redirect,
+    Hidden,
+    /// The client's address isn't allowed to request the route
+    Denied,
+    /// The client has used up its rate limit for the route
+    Limited,
+    /// An `OPTIONS` request, or a method which isn't allowed
+    Method,
+    NotModified,
+    Misdirected,
+    /// The request had too many bytes of headers
+    HeadersTooLarge,
+    File,
+}
+
+impl Source {
+    pub fn of<B>(response: &Response<B>) -> Self {
+        let extensions = response.extensions();
+        if extensions.get::<Denied>().is_some() {
+            Self::Denied
+        } else if extensions.get::<Limited>().is_some() {
+            Self::Limited
+        } else if extensions.get::<MethodAnswered>().is_some() {
+            Self::Method
+        } else if extensions.get::<Redirected>().is_some() {
+            Self::Redirect
+        } else if extensions.get::<Hidden>().is_some() {
+            Self::Hidden
+        } else if extensions.get::<NotModified>().is_some() {
+            Self::NotModified
+        } else if extensions.get::<Misdirected>().is_some() {
+            Self::Misdirected
+        } else if extensions.get::<HeadersTooLarge>().is_some() {
+            Self::HeadersTooLarge
+        } else {
+            Self::File
+        }
+    }
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Redirect => "redirect",
+            Self::Hidden => "hidden",
+            Self::Denied => "denied",
+            Self::Limited => "limited",
+            Self::Method => "method",
+            Self::NotModified => "not-modified",
+            Self::Misdirected => "misdirected",
+            Self::HeadersTooLarge => "headers-too-large",
+            Self::File => "file",
+        }
+    }
+}
+
+/// Told about a response once its body has been sent, or dropped early.
+pub trait BodyObserver {
+    fn finished(self, bytes: u64);
+}
+
+#[pin_project::pin_project(PinnedDrop)]
+/// A response body which counts the bytes sent, and calls its observer when dropped.
+pub struct ObservedBody<B, O: BodyObserver> {
+    #[pin]
+    inner: B,
+    bytes: u64,
+    observer: Option<O>,
+}
+
+impl<B, O: BodyObserver> ObservedBody<B, O> {
+    pub const fn new(inner: B, observer: O) -> Self {
+        Self {
+            inner,
+            bytes: 0,
+            observer: Some(observer),
+        }
+    }
+}
+
+impl<B: Body, O: BodyObserver> Body for ObservedBody<B, O> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let frame = std::task::ready!(this.inner.poll_frame(cx));
+        if let Some(Ok(Some(data))) = frame.as_ref().map(|f| f.as_ref().map(Frame::data_ref)) {
+            *this.bytes += data.remaining() as u64;
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[pin_project::pinned_drop]
+impl<B, O: BodyObserver> PinnedDrop for ObservedBody<B, O> {
+    fn drop(self: Pin<&mut Self>) {
+        let this = self.project();
+        if let Some(observer) = this.observer.take() {
+            observer.finished(*this.bytes);
+        }
+    }
+}
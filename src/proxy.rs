@@ -0,0 +1,351 @@
+//! Finding the real client address behind load balancers and reverse proxies,
+//! from PROXY protocol headers and trusted `Forwarded` or `X-Forwarded-For` headers.
+use std::{
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use http::{HeaderMap, Request, header};
+use ipnet::IpNet;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tower::{Layer, Service};
+
+use crate::listener::PeerAddr;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest a v1 header can be, including the CRLF.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Shorter than any header, so reading it never consumes data after one.
+const MIN_HEADER_LEN: usize = 15;
+
+const V2_LOCAL: u8 = 0x20;
+const V2_PROXY: u8 = 0x21;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+/// Read a PROXY protocol v1 or v2 header from the start of `stream`. Returns
+/// the client address it names, or `None` for health checks from the proxy
+/// itself, which don't name one. Nothing after the header is consumed.
+/// # Errors
+/// If the stream doesn't start with a valid header, or can't be read.
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>, IoError> {
+    let mut header = vec![0; MIN_HEADER_LEN];
+    stream.read_exact(&mut header).await?;
+    if header.starts_with(V2_SIGNATURE) {
+        header.resize(16, 0);
+        stream.read_exact(&mut header[MIN_HEADER_LEN..]).await?;
+        let len = u16::from_be_bytes([header[14], header[15]]);
+        let mut body = vec![0; len.into()];
+        stream.read_exact(&mut body).await?;
+        parse_v2(header[12], header[13], &body)
+    } else if header.starts_with(V1_PREFIX) {
+        // v1 headers are short, and have to be read a byte at a time to find their end
+        while !header.ends_with(b"\r\n") {
+            if header.len() == V1_MAX_LEN {
+                return Err(invalid("PROXY v1 header is too long"));
+            }
+            header.push(stream.read_u8().await?);
+        }
+        parse_v1(&header)
+    } else {
+        Err(invalid("connection did not start with a PROXY header"))
+    }
+}
+
+fn parse_v1(header: &[u8]) -> Result<Option<SocketAddr>, IoError> {
+    let header = std::str::from_utf8(header)
+        .map_err(|_| invalid("PROXY v1 header is not ASCII"))?
+        .trim_end_matches("\r\n");
+    let mut fields = header.split(' ').skip(1);
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("PROXY v1 header has an unknown protocol")),
+    }
+    let (Some(src), Some(_dst), Some(src_port), Some(_dst_port), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return Err(invalid("PROXY v1 header has the wrong number of fields"));
+    };
+    let ip: IpAddr = src
+        .parse()
+        .map_err(|_| invalid("PROXY v1 header has an invalid source address"))?;
+    let port: u16 = src_port
+        .parse()
+        .map_err(|_| invalid("PROXY v1 header has an invalid source port"))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> Result<Option<SocketAddr>, IoError> {
+    match version_command {
+        V2_LOCAL => return Ok(None),
+        V2_PROXY => {}
+        _ => return Err(invalid("PROXY v2 header has an unknown version or command")),
+    }
+    let addr = match family {
+        V2_TCP4 if body.len() >= 12 => {
+            let ip: [u8; 4] = body[0..4].try_into().unwrap_or_default();
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            SocketAddr::new(Ipv4Addr::from(ip).into(), port)
+        }
+        V2_TCP6 if body.len() >= 36 => {
+            let ip: [u8; 16] = body[0..16].try_into().unwrap_or_default();
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            SocketAddr::new(Ipv6Addr::from(ip).into(), port)
+        }
+        V2_TCP4 | V2_TCP6 => return Err(invalid("PROXY v2 header is too short")),
+        // unix sockets, UDP and unspecified families don't name a TCP client
+        _ => return Ok(None),
+    };
+    Ok(Some(addr))
+}
+
+fn invalid(msg: &'static str) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, msg)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Added to the extensions of every request: the address of the client which
+/// sent it, after following trusted proxies. `None` for unix socket clients
+/// which didn't forward an address.
+pub struct ClientAddr(pub Option<IpAddr>);
+
+#[derive(Clone)]
+/// A [`tower::Layer`] which adds a [`ClientAddr`] to each request, from the
+/// [`PeerAddr`] extension and the headers set by trusted proxies.
+pub struct ClientAddrLayer {
+    trusted: Arc<[IpNet]>,
+}
+
+impl ClientAddrLayer {
+    /// Follow `Forwarded` and `X-Forwarded-For` through proxies in `trusted`.
+    /// With no trusted proxies, the headers are ignored.
+    pub fn new(trusted: Vec<IpNet>) -> Self {
+        Self {
+            trusted: trusted.into(),
+        }
+    }
+}
+
+impl<S> Layer<S> for ClientAddrLayer {
+    type Service = ResolveClientAddr<S>;
+
+    fn layer(&self, inner: S) -> ResolveClientAddr<S> {
+        ResolveClientAddr {
+            trusted: self.trusted.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+/// A [`tower::Service`] which adds a [`ClientAddr`] to requests to the service it wraps.
+pub struct ResolveClientAddr<S> {
+    trusted: Arc<[IpNet]>,
+    inner: S,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for ResolveClientAddr<S>
+where
+    S: Service<Request<ReqBody>>,
+{
+    type Error = S::Error;
+    type Future = S::Future;
+    type Response = S::Response;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let peer = req.extensions().get::<PeerAddr>();
+        let client = client_addr(peer, req.headers(), &self.trusted);
+        req.extensions_mut().insert(ClientAddr(client));
+        self.inner.call(req)
+    }
+}
+
+/// Walk the forwarded addresses from the nearest hop outwards, stopping at
+/// the first one which isn't a trusted proxy. Unix socket peers count as
+/// trusted, as long as any proxies are.
+///
+/// IPv4 addresses of dual-stack listeners' peers show up as IPv4-mapped IPv6,
+/// so every address is made canonical before it's matched or returned.
+fn client_addr(peer: Option<&PeerAddr>, headers: &HeaderMap, trusted: &[IpNet]) -> Option<IpAddr> {
+    let peer_ip = peer.and_then(PeerAddr::ip).map(|ip| ip.to_canonical());
+    if trusted.is_empty() {
+        return peer_ip;
+    }
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if peer_ip.as_ref().is_some_and(|ip| !is_trusted(ip)) {
+        return peer_ip;
+    }
+    let mut client = peer_ip;
+    for hop in forwarded_for(headers).into_iter().rev() {
+        let Some(ip) = hop else {
+            // obfuscated or garbled, so nothing further out can be trusted
+            break;
+        };
+        let ip = ip.to_canonical();
+        client = Some(ip);
+        if !is_trusted(&ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// Every forwarded address, furthest first. `Forwarded` is used if any is
+/// present, `X-Forwarded-For` otherwise. Addresses which can't be parsed are `None`.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+    };
+    if headers.contains_key(header::FORWARDED) {
+        values(header::FORWARDED)
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node.trim_matches('"')))
+            })
+            .collect()
+    } else {
+        values(header::HeaderName::from_static("x-forwarded-for"))
+            .map(parse_node)
+            .collect()
+    }
+}
+
+/// Parse an address which may have a port, and IPv6 brackets.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    let bracketed = node.strip_prefix('[')?;
+    let (ip, _) = bracketed.split_once(']')?;
+    ip.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    async fn read(mut data: &[u8]) -> (Result<Option<SocketAddr>, IoError>, &[u8]) {
+        let header = read_header(&mut data).await;
+        (header, data)
+    }
+
+    #[tokio::test]
+    async fn proxy_v1() {
+        let (addr, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 4000 443\r\nGET /").await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:4000".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (addr, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n").await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        let (addr, rest) = read(b"PROXY UNKNOWN\r\n\x16\x03").await;
+        assert_eq!(addr.unwrap(), None);
+        assert_eq!(rest, b"\x16\x03");
+
+        assert!(
+            read(b"GET / HTTP/1.1\r\nHost: example.org\r\n")
+                .await
+                .0
+                .is_err()
+        );
+        assert!(read(b"PROXY TCP4 192.0.2.1 4000 443\r\n").await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn proxy_v2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([V2_PROXY, V2_TCP4, 0, 12]);
+        data.extend([192, 0, 2, 1, 198, 51, 100, 1]);
+        data.extend(4000u16.to_be_bytes());
+        data.extend(443u16.to_be_bytes());
+        data.extend(b"GET /");
+        let (addr, rest) = read(&data).await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:4000".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([V2_LOCAL, 0, 0, 0]);
+        assert_eq!(read(&local).await.0.unwrap(), None);
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn follows_trusted_proxies() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let proxy = PeerAddr::Tcp("10.0.0.2:4000".parse().unwrap());
+        let outsider = PeerAddr::Tcp("192.0.2.9:4000".parse().unwrap());
+        let xff = headers(&[("x-forwarded-for", "203.0.113.5, 198.51.100.7, 10.0.0.3")]);
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+
+        assert_eq!(
+            client_addr(Some(&proxy), &xff, &trusted),
+            ip("198.51.100.7")
+        );
+        assert_eq!(
+            client_addr(Some(&outsider), &xff, &trusted),
+            ip("192.0.2.9")
+        );
+        assert_eq!(client_addr(Some(&proxy), &xff, &[]), ip("10.0.0.2"));
+
+        let forwarded = headers(&[
+            ("forwarded", "for=203.0.113.5"),
+            ("forwarded", "for=\"[2001:db8::1]:4711\";proto=https"),
+            ("x-forwarded-for", "198.51.100.7"),
+        ]);
+        assert_eq!(
+            client_addr(Some(&proxy), &forwarded, &trusted),
+            ip("2001:db8::1")
+        );
+
+        let hidden = headers(&[("forwarded", "for=_hidden, for=10.0.0.3")]);
+        assert_eq!(client_addr(Some(&proxy), &hidden, &trusted), ip("10.0.0.3"));
+
+        // an IPv4 proxy as a dual-stack listener sees it
+        let mapped = PeerAddr::Tcp("[::ffff:10.0.0.2]:4000".parse().unwrap());
+        assert_eq!(
+            client_addr(Some(&mapped), &xff, &trusted),
+            ip("198.51.100.7")
+        );
+        let mapped_hop = headers(&[("x-forwarded-for", "203.0.113.5, ::ffff:10.0.0.3")]);
+        assert_eq!(
+            client_addr(Some(&mapped), &mapped_hop, &trusted),
+            ip("203.0.113.5")
+        );
+        assert_eq!(client_addr(Some(&mapped), &xff, &[]), ip("10.0.0.2"));
+    }
+}
@@ -0,0 +1,711 @@
+//! Limiting how often each client may request a route, and how many of its
+//! responses may be in flight at once, so a few scripted downloads can't take
+//! all of the bandwidth. Connections can also be throttled with [`Throttled`].
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    future::Future,
+    io::Error as IoError,
+    net::{IpAddr, Ipv6Addr},
+    num::NonZeroU32,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use http::{HeaderValue, Request, Response, StatusCode, header};
+use matchit::{InsertError, Router};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+use tower::{Layer, Service};
+
+use crate::{
+    config::RateLimitConfig,
+    memfs::empty_response,
+    observe::{BodyObserver, ObservedBody},
+    proxy::ClientAddr,
+    site::{RequestBody, ResponseBody},
+};
+
+/// Clients a rule keeps state for before it forgets the oldest.
+const MAX_TRACKED_CLIENTS: usize = 4096;
+/// Busy clients passed over when forgetting one, before the oldest is
+/// forgotten whether it's busy or not.
+const EVICTION_TRIES: usize = 8;
+
+#[derive(Clone, Copy, Debug)]
+/// How much each client may request a route.
+pub struct Limits {
+    /// Requests a second each client may make, on average. Unlimited if `None`
+    pub rate: Option<f64>,
+    /// Requests each client may make at once before `rate` applies
+    pub burst: NonZeroU32,
+    /// Responses each client may be sent at once. Unlimited if `None`
+    pub concurrent: Option<NonZeroU32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Why a request was limited.
+pub enum Limit {
+    /// The client has used up its requests, and gets another after this long
+    Rate(Duration),
+    /// The client already has as many responses in flight as it may
+    Concurrent,
+}
+
+#[derive(Clone, Debug)]
+/// Added to the extensions of every response for a request which was limited.
+pub struct Limited {
+    /// The route which limited it, as passed to [`RateLimitLayerBuilder::rule`]
+    pub route: Arc<str>,
+    pub limit: Limit,
+}
+
+#[derive(Clone, Copy, Debug)]
+/// A token bucket, which holds up to `burst` requests and refills at `rate` a second.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(burst: NonZeroU32, now: Instant) -> Self {
+        Self {
+            tokens: burst.get().into(),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, burst: NonZeroU32, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = elapsed.mul_add(rate, self.tokens).min(burst.get().into());
+        self.updated = now;
+    }
+
+    /// Take a request from the bucket, or say how long until one can be taken.
+    fn take(&mut self, rate: f64, burst: NonZeroU32, now: Instant) -> Result<(), Duration> {
+        self.refill(rate, burst, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::try_from_secs_f64((1.0 - self.tokens) / rate).unwrap_or(Duration::MAX))
+        }
+    }
+}
+
+struct Client {
+    bucket: Bucket,
+    in_flight: u32,
+}
+
+impl Client {
+    /// Whether forgetting the client wouldn't change its limits.
+    fn idle(&mut self, limits: &Limits, now: Instant) -> bool {
+        if let Some(rate) = limits.rate {
+            self.bucket.refill(rate, limits.burst, now);
+        }
+        self.in_flight == 0 && self.bucket.tokens >= limits.burst.get().into()
+    }
+}
+
+#[derive(Default)]
+/// Every client a rule keeps state for, up to [`MAX_TRACKED_CLIENTS`].
+struct Clients {
+    clients: HashMap<IpAddr, Client>,
+    /// Keys in the order they were first seen, so the oldest is forgotten first
+    order: VecDeque<IpAddr>,
+}
+
+impl Clients {
+    /// The state of the client at `key`, making room for it if it's new.
+    fn get(&mut self, key: IpAddr, limits: &Limits, now: Instant) -> &mut Client {
+        if !self.clients.contains_key(&key) {
+            if self.clients.len() >= MAX_TRACKED_CLIENTS {
+                self.evict(limits, now);
+            }
+            self.order.push_back(key);
+        }
+        self.clients.entry(key).or_insert_with(|| Client {
+            bucket: Bucket::full(limits.burst, now),
+            in_flight: 0,
+        })
+    }
+
+    /// Forget the oldest idle client, or the oldest one if the first few are busy.
+    fn evict(&mut self, limits: &Limits, now: Instant) {
+        for _ in 0..EVICTION_TRIES {
+            let Some(oldest) = self.order.pop_front() else {
+                return;
+            };
+            let idle = self
+                .clients
+                .get_mut(&oldest)
+                .is_none_or(|client| client.idle(limits, now));
+            if idle {
+                self.clients.remove(&oldest);
+                return;
+            }
+            // busy clients go to the back, so their limits are kept for now
+            self.order.push_back(oldest);
+        }
+        if let Some(oldest) = self.order.pop_front() {
+            self.clients.remove(&oldest);
+        }
+    }
+}
+
+struct Rule {
+    route: Arc<str>,
+    limits: Limits,
+    clients: Mutex<Clients>,
+}
+
+impl Rule {
+    /// Let a request from `client` through, counting it as in flight until
+    /// the returned guard is dropped, or say why it's limited.
+    fn admit(self: &Arc<Self>, client: IpAddr, now: Instant) -> Result<Option<InFlight>, Limit> {
+        let Limits {
+            rate,
+            burst,
+            concurrent,
+        } = self.limits;
+        let key = client_key(client);
+        let mut clients = self.clients.lock().unwrap_or_else(PoisonError::into_inner);
+        let client = clients.get(key, &self.limits, now);
+        if concurrent.is_some_and(|concurrent| client.in_flight >= concurrent.get()) {
+            return Err(Limit::Concurrent);
+        }
+        if let Some(rate) = rate {
+            client.bucket.take(rate, burst, now).map_err(Limit::Rate)?;
+        }
+        if concurrent.is_none() {
+            return Ok(None);
+        }
+        client.in_flight += 1;
+        drop(clients);
+        Ok(Some(InFlight {
+            rule: self.clone(),
+            key,
+        }))
+    }
+}
+
+/// What `client` is limited by. IPv6 clients usually have a whole /64 to
+/// pick addresses from, so they share the limits of everyone in it.
+fn client_key(client: IpAddr) -> IpAddr {
+    match client.to_canonical() {
+        IpAddr::V6(ip) => Ipv6Addr::from_bits(ip.to_bits() & (u128::MAX << 64)).into(),
+        ip @ IpAddr::V4(_) => ip,
+    }
+}
+
+/// Counts a response as in flight until it's dropped.
+pub struct InFlight {
+    rule: Arc<Rule>,
+    key: IpAddr,
+}
+
+impl BodyObserver for InFlight {
+    fn finished(self, _bytes: u64) {}
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut clients = self
+            .rule
+            .clients
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(client) = clients.clients.get_mut(&self.key) {
+            client.in_flight = client.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+/// Build a [`matchit::Router`] of routes which each client may only request so often.
+pub struct RateLimitLayerBuilder {
+    rules: Router<Arc<Rule>>,
+    errors: Vec<RateLimitError>,
+}
+
+impl RateLimitLayerBuilder {
+    /// Limit how much each client may request `route`, which is a [`matchit`]
+    /// route. Like in `_auth`, a trailing `*` also covers the path above it.
+    #[must_use]
+    pub fn rule(mut self, route: impl Into<String>, limits: Limits) -> Self {
+        let route = route.into();
+        if limits
+            .rate
+            .is_some_and(|rate| !(rate.is_finite() && rate > 0.0))
+        {
+            self.errors.push(RateLimitError::Rate(route));
+            return self;
+        }
+        let value = Arc::new(Rule {
+            route: route.as_str().into(),
+            limits,
+            clients: Mutex::default(),
+        });
+        for path in tunnelbana_auth::routes(&route) {
+            if let Err(err) = self.rules.insert(path, value.clone()) {
+                self.errors.push(RateLimitError::Route(route.clone(), err));
+            }
+        }
+        self
+    }
+
+    /// Convenience method for calling [`Self::rule`] in a loop.
+    #[must_use]
+    pub fn rules<R: Into<String>>(mut self, rules: impl IntoIterator<Item = (R, Limits)>) -> Self {
+        for (route, limits) in rules {
+            self = self.rule(route, limits);
+        }
+        self
+    }
+
+    /// Build this [`RateLimitLayer`].
+    /// # Errors
+    /// If a route couldn't be inserted into the router, or its rate isn't
+    /// positive. Only the first error is returned.
+    pub fn build(self) -> Result<RateLimitLayer, RateLimitError> {
+        if let Some(err) = self.errors.into_iter().next() {
+            return Err(err);
+        }
+        Ok(RateLimitLayer {
+            rules: Arc::new(self.rules),
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RateLimitError {
+    #[error("Could not limit `{0}`: {1}")]
+    Route(String, InsertError),
+    #[error("The rate for `{0}` must be a positive number of requests a second")]
+    Rate(String),
+}
+
+#[derive(Clone)]
+/// A [`tower::Layer`] which answers requests for a route with a `429 Too Many
+/// Requests` once their client has used up its limits. Clients are identified
+/// by the [`ClientAddr`] extension, so trusted proxies are looked through, and
+/// clients without an address aren't limited. Routes are matched against
+/// [`tunnelbana_auth::route_path`], like `_auth`.
+pub struct RateLimitLayer {
+    rules: Arc<Router<Arc<Rule>>>,
+}
+
+impl RateLimitLayer {
+    pub fn builder() -> RateLimitLayerBuilder {
+        RateLimitLayerBuilder {
+            rules: Router::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Build the layer for the `[[rate_limit.rules]]` in `config`. Without a
+    /// `burst`, a rule lets each client make a second's worth of requests at once.
+    /// # Errors
+    /// See [`RateLimitLayerBuilder::build`].
+    pub fn from_config(config: &RateLimitConfig) -> Result<Self, RateLimitError> {
+        let rules = config.rules.iter().map(|rule| {
+            let burst = rule.burst.unwrap_or_else(|| default_burst(rule.rate));
+            let limits = Limits {
+                rate: rule.rate,
+                burst,
+                concurrent: rule.concurrent,
+            };
+            (rule.path.as_str(), limits)
+        });
+        Self::builder().rules(rules).build()
+    }
+
+    /// Let a request for `path` from `client` through, or say which route
+    /// limited it and why.
+    fn admit(&self, path: &str, client: IpAddr) -> Result<Option<InFlight>, (Arc<str>, Limit)> {
+        let path = tunnelbana_auth::route_path(path);
+        let Ok(matched) = self.rules.at(&path) else {
+            return Ok(None);
+        };
+        let rule = matched.value;
+        rule.admit(client, Instant::now())
+            .map_err(|limit| (rule.route.clone(), limit))
+    }
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn default_burst(rate: Option<f64>) -> NonZeroU32 {
+    let burst = rate.map_or(1, |rate| rate.ceil().clamp(1.0, u32::MAX.into()) as u32);
+    NonZeroU32::new(burst).unwrap_or(NonZeroU32::MIN)
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> RateLimit<S> {
+        RateLimit {
+            layer: self.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+/// A [`tower::Service`] which limits how much each client may request from the service it wraps.
+pub struct RateLimit<S> {
+    layer: RateLimitLayer,
+    inner: S,
+}
+
+impl<S> Service<Request<RequestBody>> for RateLimit<S>
+where
+    S: Service<Request<RequestBody>, Response = Response<ResponseBody>, Error = Infallible>,
+{
+    type Error = Infallible;
+    type Future = ResponseFuture<S::Future>;
+    type Response = Response<ResponseBody>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<RequestBody>) -> Self::Future {
+        let client = req.extensions().get::<ClientAddr>().and_then(|c| c.0);
+        let Some(client) = client else {
+            return ResponseFuture::Inner {
+                inner: self.inner.call(req),
+                in_flight: None,
+            };
+        };
+        match self.layer.admit(req.uri().path(), client) {
+            Ok(in_flight) => ResponseFuture::Inner {
+                inner: self.inner.call(req),
+                in_flight,
+            },
+            Err((route, limit)) => {
+                debug!(path = req.uri().path(), %client, %route, ?limit, "Limited request");
+                ResponseFuture::Limited(Some(Limited { route, limit }))
+            }
+        }
+    }
+}
+
+#[pin_project::pin_project(project = ResponseFutureProj)]
+/// Future which is either the wrapped service's response, or a `429`.
+pub enum ResponseFuture<F> {
+    Inner {
+        #[pin]
+        inner: F,
+        in_flight: Option<InFlight>,
+    },
+    Limited(Option<Limited>),
+}
+
+impl<F> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<ResponseBody>, Infallible>>,
+{
+    type Output = Result<Response<ResponseBody>, Infallible>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            ResponseFutureProj::Inner { inner, in_flight } => {
+                let Ok(response) = std::task::ready!(inner.poll(cx));
+                let Some(in_flight) = in_flight.take() else {
+                    return Poll::Ready(Ok(response));
+                };
+                // the response is in flight until its whole body has been sent
+                let response =
+                    response.map(|body| ResponseBody::new(ObservedBody::new(body, in_flight)));
+                Poll::Ready(Ok(response))
+            }
+            ResponseFutureProj::Limited(limited) => {
+                let limited = limited.take().expect("limit polled after completion");
+                Poll::Ready(Ok(limited_response(limited)))
+            }
+        }
+    }
+}
+
+fn limited_response(limited: Limited) -> Response<ResponseBody> {
+    let mut response = empty_response(StatusCode::TOO_MANY_REQUESTS);
+    if let Limit::Rate(wait) = limited.limit {
+        // rounded up, so the client never comes back too early
+        let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+    }
+    response.extensions_mut().insert(limited);
+    response
+}
+
+/// A connection which is sent at most a number of bytes a second. Up to a
+/// second's worth can be sent at once, after the connection has been idle.
+pub struct Throttled<S> {
+    inner: S,
+    /// Bytes a second
+    rate: f64,
+    /// Bytes which may be sent now
+    allowance: f64,
+    updated: tokio::time::Instant,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, bytes_per_second: NonZeroU32) -> Self {
+        let rate = bytes_per_second.get().into();
+        Self {
+            inner,
+            rate,
+            allowance: rate,
+            updated: tokio::time::Instant::now(),
+            sleep: None,
+        }
+    }
+
+    /// Wait until at least a tenth of a second's worth of bytes may be sent,
+    /// so small writes don't each wait for a timer.
+    fn poll_allowance(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            if let Some(sleep) = &mut self.sleep {
+                std::task::ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            let now = tokio::time::Instant::now();
+            let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+            self.allowance = elapsed.mul_add(self.rate, self.allowance).min(self.rate);
+            self.updated = now;
+            if self.allowance >= 1.0 {
+                return Poll::Ready(());
+            }
+            let wanted = (self.rate / 10.0).max(1.0);
+            let wait = Duration::from_secs_f64((wanted - self.allowance) / self.rate);
+            self.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, IoError>> {
+        if buf.is_empty() {
+            return Pin::new(&mut self.inner).poll_write(cx, buf);
+        }
+        std::task::ready!(self.poll_allowance(cx));
+        let len = buf.len().min(self.allowance as usize);
+        let written = std::task::ready!(Pin::new(&mut self.inner).poll_write(cx, &buf[..len]))?;
+        self.allowance -= written as f64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{BodyExt, Empty};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn rule(limits: Limits) -> Arc<Rule> {
+        Arc::new(Rule {
+            route: "/releases/*".into(),
+            limits,
+            clients: Mutex::default(),
+        })
+    }
+
+    #[test]
+    fn buckets_refill() {
+        let releases = rule(Limits {
+            rate: Some(0.5),
+            burst: NonZeroU32::new(2).unwrap(),
+            concurrent: None,
+        });
+        let client = "192.0.2.1".parse().unwrap();
+        let start = Instant::now();
+        assert!(releases.admit(client, start).is_ok());
+        assert!(releases.admit(client, start).is_ok());
+        assert_eq!(
+            releases.admit(client, start).err(),
+            Some(Limit::Rate(Duration::from_secs(2)))
+        );
+        assert_eq!(
+            releases.admit(client, start + Duration::from_secs(1)).err(),
+            Some(Limit::Rate(Duration::from_secs(1)))
+        );
+        assert!(
+            releases
+                .admit(client, start + Duration::from_secs(2))
+                .is_ok()
+        );
+        assert!(releases.admit("192.0.2.2".parse().unwrap(), start).is_ok());
+
+        // a whole /64 shares its limits
+        let a = "2001:db8::1".parse().unwrap();
+        let b = "2001:db8::2".parse().unwrap();
+        assert!(releases.admit(a, start).is_ok());
+        assert!(releases.admit(b, start).is_ok());
+        assert!(releases.admit(a, start).is_err());
+    }
+
+    #[test]
+    fn caps_responses_in_flight() {
+        let releases = rule(Limits {
+            rate: None,
+            burst: NonZeroU32::MIN,
+            concurrent: NonZeroU32::new(2),
+        });
+        let client = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+        let first = releases.admit(client, now).unwrap();
+        let second = releases.admit(client, now).unwrap();
+        assert_eq!(releases.admit(client, now).err(), Some(Limit::Concurrent));
+        drop(first);
+        assert!(releases.admit(client, now).is_ok());
+        drop(second);
+    }
+
+    #[test]
+    fn forgets_oldest_clients() {
+        let releases = rule(Limits {
+            rate: Some(1.0),
+            burst: NonZeroU32::MIN,
+            concurrent: NonZeroU32::new(1),
+        });
+        let now = Instant::now();
+        let busy: IpAddr = "192.0.2.1".parse().unwrap();
+        let in_flight = releases.admit(busy, now).unwrap();
+        let idle: IpAddr = "192.0.2.2".parse().unwrap();
+        assert!(releases.admit(idle, now).is_ok());
+        let later = now + Duration::from_secs(1);
+        for i in 0..u32::try_from(MAX_TRACKED_CLIENTS).unwrap() {
+            let client = IpAddr::from(std::net::Ipv4Addr::from_bits(0x0a00_0000 + i));
+            assert!(releases.admit(client, later).is_ok());
+        }
+
+        let clients = releases.clients.lock().unwrap();
+        assert_eq!(clients.clients.len(), MAX_TRACKED_CLIENTS);
+        assert_eq!(clients.order.len(), MAX_TRACKED_CLIENTS);
+        // the busy client was passed over, and the idle one forgotten instead
+        assert!(clients.clients.contains_key(&busy));
+        assert!(!clients.clients.contains_key(&idle));
+        drop(clients);
+        assert_eq!(releases.admit(busy, later).err(), Some(Limit::Concurrent));
+        drop(in_flight);
+    }
+
+    #[tokio::test]
+    async fn limits_requests() {
+        let limits = Limits {
+            rate: Some(1.0),
+            burst: NonZeroU32::MIN,
+            concurrent: NonZeroU32::new(1),
+        };
+        let layer = RateLimitLayer::builder()
+            .rule("/releases/*", limits)
+            .build()
+            .unwrap();
+        let svc = layer.layer(tower::service_fn(|_| {
+            std::future::ready(Ok(empty_response(StatusCode::OK)))
+        }));
+        let get = |path: &'static str, client: Option<&'static str>| {
+            let svc = svc.clone();
+            async move {
+                let mut req = Request::get(path)
+                    .body(RequestBody::new(Empty::new().map_err(|e| match e {})))
+                    .unwrap();
+                let client = client.map(|client| client.parse().unwrap());
+                req.extensions_mut().insert(ClientAddr(client));
+                svc.oneshot(req).await.unwrap()
+            }
+        };
+
+        let download = get("/releases/app.tar.gz", Some("192.0.2.1")).await;
+        assert_eq!(download.status(), StatusCode::OK);
+        let response = get("/releases/app.zip", Some("192.0.2.1")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(!response.headers().contains_key(header::RETRY_AFTER));
+        assert_eq!(
+            response.extensions().get::<Limited>().map(|l| &*l.route),
+            Some("/releases/*")
+        );
+
+        // the first download is finished once its body is
+        download.into_body().collect().await.unwrap();
+        let response = get("/releases", Some("192.0.2.1")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+
+        assert_eq!(
+            get("/about", Some("192.0.2.1")).await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            get("/releases/app.zip", None).await.status(),
+            StatusCode::OK
+        );
+
+        let invalid = Limits {
+            rate: Some(0.0),
+            ..limits
+        };
+        assert!(
+            RateLimitLayer::builder()
+                .rule("/a", invalid)
+                .build()
+                .is_err()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttles_connections() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut server = Throttled::new(server, NonZeroU32::new(1000).unwrap());
+        let start = tokio::time::Instant::now();
+        let writer = tokio::spawn(async move {
+            server.write_all(&[0; 3000]).await.unwrap();
+            server.shutdown().await.unwrap();
+        });
+        let mut received = Vec::new();
+        let mut client = client;
+        client.read_to_end(&mut received).await.unwrap();
+        writer.await.unwrap();
+        assert_eq!(received.len(), 3000);
+        // a second's worth is sent at once, then the rest at the rate
+        assert!(start.elapsed() >= Duration::from_secs(2));
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+}
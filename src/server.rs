@@ -0,0 +1,279 @@
+//! Serving connections from a worker's listeners until shutdown.
+use std::{convert::Infallible, num::NonZeroU32, pin::pin, sync::Arc, time::Duration};
+
+use futures_util::future::Either;
+use http::{HeaderValue, Request, Response, header};
+use http_body_util::BodyExt;
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto::Builder as ConnBuilder, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use tokio::{sync::mpsc, task::JoinSet};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tower::{ServiceExt, util::BoxCloneSyncService};
+
+use crate::{
+    connections::ConnectionLimits,
+    listener::{Connection, Listener, PeerAddr},
+    metrics::Metrics,
+    rate_limit::Throttled,
+    site::{RequestBody, ResponseBody, SiteService},
+};
+
+const ACCEPT_QUEUE_LEN: usize = 64;
+
+#[derive(Clone)]
+/// Everything a worker needs to serve connections. Each worker gets its own clone.
+pub struct Server {
+    pub service: SiteService,
+    pub metrics: Option<Arc<Metrics>>,
+    pub shutdown_timeout: Duration,
+    /// `Alt-Svc` header advertising HTTP/3, if it is served
+    pub alt_svc: Option<HeaderValue>,
+    /// Bytes a second each connection may be sent, if it's throttled
+    pub bandwidth: Option<NonZeroU32>,
+    pub limits: ConnectionLimits,
+}
+
+impl Server {
+    /// Serve connections from `listeners` until `shutdown` is cancelled, then wait for
+    /// open connections to finish. If every listener stops, `shutdown` is cancelled so
+    /// the other workers stop too.
+    pub async fn serve(self, listeners: Vec<Listener>, shutdown: CancellationToken) {
+        let mut server = ConnBuilder::new(TokioExecutor::new());
+        self.limits.configure(&mut server);
+        let graceful = GracefulShutdown::new();
+        let tasks = TaskTracker::new();
+
+        let (conn_tx, mut conn_rx) = mpsc::channel(ACCEPT_QUEUE_LEN);
+        // dropping this set aborts every accept loop
+        let mut acceptors = JoinSet::new();
+        for listener in listeners {
+            acceptors.spawn(listener.accept_into(conn_tx.clone()));
+        }
+        drop(conn_tx);
+
+        let mut cancelled = pin!(shutdown.cancelled());
+        loop {
+            let next_conn = pin!(conn_rx.recv());
+            let selected = futures_util::future::select(next_conn, cancelled.as_mut()).await;
+            let accepted = match selected {
+                Either::Left((Some(conn), _)) => conn,
+                Either::Left((None, _)) => {
+                    error!("All listeners have stopped, starting shutdown");
+                    shutdown.cancel();
+                    break;
+                }
+                Either::Right(_) => break,
+            };
+            let peer_addr = accepted.peer.clone();
+            let Some(permit) = self.limits.open() else {
+                // dropping the connection closes it, before any handshake
+                warn!(
+                    "refusing connection from {}: {} are already open",
+                    peer_addr,
+                    self.limits.max()
+                );
+                if let Some(metrics) = &self.metrics {
+                    metrics.connection_refused();
+                }
+                continue;
+            };
+            info!("incoming connection accepted: {}", peer_addr);
+
+            let service = self.service.clone();
+            let alt_svc = self.alt_svc.clone();
+            let server = server.clone();
+            let watcher = graceful.watcher();
+            let metrics = self.metrics.clone();
+            let bandwidth = self.bandwidth;
+            let limits = self.limits.clone();
+            tasks.spawn(async move {
+                let _permit = permit;
+                let _connection = metrics.as_ref().map(Metrics::connection_opened);
+                let (stream, peer) = match accepted.into_stream().await {
+                    Ok((stream, peer)) => {
+                        let stream = limits.idle_timeout(throttle(stream, bandwidth));
+                        (TokioIo::new(stream), peer)
+                    }
+                    Err(err) => {
+                        debug!("handshake with {} failed: {}", peer_addr, err);
+                        return;
+                    }
+                };
+                let service = connection_service(service, peer, alt_svc);
+                let conn = server
+                    .serve_connection_with_upgrades(stream, TowerToHyperService::new(service));
+                match watcher.watch(conn.into_owned()).await {
+                    Err(err) if timed_out(&*err) => {
+                        debug!("connection from {} timed out: {}", peer_addr, err);
+                    }
+                    Err(err) => warn!("connection error: {}", err),
+                    Ok(()) => {}
+                }
+                debug!("connection dropped: {}", peer_addr);
+            });
+        }
+        drop(acceptors);
+        shut_down(graceful, tasks, self.shutdown_timeout).await;
+    }
+}
+
+/// The service for one connection, which tags requests with the client's address.
+/// It is boxed, since rustc can't prove the closures' futures are Send inside the task.
+fn connection_service(
+    service: SiteService,
+    peer: PeerAddr,
+    alt_svc: Option<HeaderValue>,
+) -> BoxCloneSyncService<Request<Incoming>, Response<ResponseBody>, Infallible> {
+    let service = service
+        .map_request(move |req| {
+            let mut req = box_request(req);
+            req.extensions_mut().insert(peer.clone());
+            req
+        })
+        .map_response(move |mut res| {
+            if let Some(alt_svc) = &alt_svc {
+                res.headers_mut().insert(header::ALT_SVC, alt_svc.clone());
+            }
+            res
+        });
+    BoxCloneSyncService::new(service)
+}
+
+/// Whether `err` is from a connection being closed by one of its timeouts,
+/// which happens to idle clients all the time.
+fn timed_out(err: &(dyn std::error::Error + 'static)) -> bool {
+    std::iter::successors(Some(err), |err| err.source()).any(|err| {
+        err.downcast_ref::<hyper::Error>()
+            .is_some_and(hyper::Error::is_timeout)
+            || err
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|err| err.kind() == std::io::ErrorKind::TimedOut)
+    })
+}
+
+fn throttle(stream: Connection, bandwidth: Option<NonZeroU32>) -> Connection {
+    match bandwidth {
+        Some(bandwidth) => Box::pin(Throttled::new(stream, bandwidth)),
+        None => stream,
+    }
+}
+
+fn box_request(req: Request<Incoming>) -> Request<RequestBody> {
+    req.map(|body| RequestBody::new(body.map_err(Into::into)))
+}
+
+async fn shut_down(graceful: GracefulShutdown, tasks: TaskTracker, timeout: Duration) {
+    match futures_util::future::select(pin!(graceful.shutdown()), pin!(tokio::time::sleep(timeout)))
+        .await
+    {
+        Either::Left(_) => {
+            info!("Gracefully shutdown!");
+        }
+        Either::Right(_) => {
+            error!(
+                ?timeout,
+                "Timed out waiting for graceful shutdown, aborting..."
+            );
+            return;
+        }
+    }
+
+    tasks.close();
+
+    match futures_util::future::select(pin!(tasks.wait()), pin!(tokio::time::sleep(timeout))).await
+    {
+        Either::Left(_) => {
+            info!("Gracefully shutdown!");
+        }
+        Either::Right(_) => {
+            error!(
+                ?timeout,
+                "Timed out waiting for graceful shutdown, aborting..."
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use http_body_util::Empty;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use tower::Layer;
+
+    use super::*;
+    use crate::{
+        config::ConnectionsConfig,
+        listener::{ListenAddr, ListenerSource},
+        observe::Source,
+    };
+
+    #[tokio::test]
+    async fn answers_oversized_headers_in_the_stack() {
+        let limits = ConnectionLimits::new(&ConnectionsConfig::default()).unwrap();
+        let sources = Arc::new(Mutex::new(Vec::new()));
+        let recorded = sources.clone();
+        let site = tower::service_fn(|_: Request<RequestBody>| {
+            std::future::ready(Ok(Response::new(ResponseBody::new(
+                Empty::new().map_err(|e| match e {}),
+            ))))
+        });
+        let service = limits.header_size_layer().layer(site).map_response(
+            move |res: Response<ResponseBody>| {
+                recorded.lock().unwrap().push(Source::of(&res));
+                res
+            },
+        );
+        let server = Server {
+            service: SiteService::new(service),
+            metrics: None,
+            shutdown_timeout: Duration::from_secs(1),
+            alt_svc: Some(HeaderValue::from_static("h3=\":443\"")),
+            bandwidth: None,
+            limits,
+        };
+        let listener = Listener::open(
+            ListenerSource::Bind("127.0.0.1:0".parse().unwrap()),
+            None,
+            false,
+        )
+        .unwrap();
+        let ListenAddr::Tcp(addr) = listener.local_addr().unwrap().addr else {
+            unreachable!("bound to a TCP address");
+        };
+        let shutdown = CancellationToken::new();
+        let serving = tokio::spawn(server.serve(vec![listener], shutdown.clone()));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        // pipelined behind a small request, the oversized one arrives in a
+        // single read once hyper has grown its buffer, so it gets parsed
+        let cookie = "a".repeat(16 * 1024 - 28);
+        let requests = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n\
+             GET / HTTP/1.1\r\nHost: localhost\r\nCookie: {cookie}\r\nConnection: close\r\n\r\n"
+        );
+        stream.write_all(requests.as_bytes()).await.unwrap();
+        let mut responses = String::new();
+        stream.read_to_string(&mut responses).await.unwrap();
+
+        let oversized = responses.rfind("HTTP/1.1").unwrap();
+        let response = &responses[oversized..];
+        assert!(response.starts_with("HTTP/1.1 431"), "{responses}");
+        assert!(response.contains("alt-svc: h3=\":443\""), "{responses}");
+        assert_eq!(
+            *sources.lock().unwrap(),
+            [Source::File, Source::HeadersTooLarge]
+        );
+
+        shutdown.cancel();
+        serving.await.unwrap();
+    }
+}
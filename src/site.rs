@@ -0,0 +1,846 @@
+//! A directory or archive of static files, along with the `_headers`,
+//! `_redirects`, `_auth` and etags generated from it.
+use std::{
+    convert::Infallible,
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use http::{HeaderValue, Request, Response, StatusCode};
+use http_body::Body;
+use http_body_util::{BodyExt, combinators::UnsyncBoxBody};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tower::{BoxError, Layer, Service, ServiceBuilder, util::BoxCloneSyncService};
+use tower_http::{
+    services::{ServeDir, ServeFile},
+    set_header::SetResponseHeaderLayer,
+};
+use tunnelbana_auth::{AuthGroup, AuthLayer, AuthParseError, ServedPath};
+use tunnelbana_etags::{ETagLayer, ETagMap, TagMapBuildError};
+use tunnelbana_headers::{HeaderGroup, HeaderParseError, HeadersLayer};
+use tunnelbana_hidepaths::HidePathsLayerBuilderError;
+use tunnelbana_redirects::{Redirect, RedirectParseError, RedirectsLayer};
+
+use crate::{
+    archive::{self, ArchiveError, ArchiveKind},
+    cache_control::CacheControlLayer,
+    clean_urls::{CleanUrlsLayer, TrailingSlash},
+    compress::{Compression, CompressionLayer},
+    config::{Config, IpFilterConfig, ListingConfig, Precompressed, PreloadConfig},
+    error_pages::ErrorPagesLayer,
+    ip_filter::{IpFilterError, IpFilterLayer, IpRule},
+    listing::{ListingLayer, ListingSource},
+    memfs::{MemoryFs, ServeMemory, empty_response},
+    methods::MethodsLayer,
+};
+
+/// Always hidden, whatever the configuration says.
+pub const RESERVED_PATHS: [&str; 3] = ["/_headers", "/_redirects", "/_auth"];
+
+/// How long the site has to stay unchanged before it is reloaded,
+/// so a deploy which touches many files only causes one reload.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Request bodies are boxed, so HTTP/1.1, HTTP/2 and HTTP/3 requests can share a service.
+pub type RequestBody = UnsyncBoxBody<Bytes, BoxError>;
+pub type ResponseBody = UnsyncBoxBody<Bytes, BoxError>;
+pub type SiteService =
+    BoxCloneSyncService<Request<RequestBody>, Response<ResponseBody>, Infallible>;
+
+/// Enable each precompressed variant `$which` asks for on a `ServeDir` or `ServeFile`.
+macro_rules! precompressed {
+    ($svc:expr, $which:expr) => {{
+        let mut svc = $svc;
+        if $which.br {
+            svc = svc.precompressed_br();
+        }
+        if $which.deflate {
+            svc = svc.precompressed_deflate();
+        }
+        if $which.gzip {
+            svc = svc.precompressed_gzip();
+        }
+        if $which.zstd {
+            svc = svc.precompressed_zstd();
+        }
+        svc
+    }};
+}
+
+#[derive(Clone, Debug)]
+/// How a [`Site`] is served.
+pub struct ServeOptions {
+    /// Fall back to `index.html` rather than `404.html`
+    pub spa: bool,
+    /// Paths to hide, in addition to `/_headers`, `/_redirects` and `/_auth`
+    pub hidden_paths: Vec<String>,
+    pub cache_control: CacheControlLayer,
+    /// Serve `.html` files without their extension, with these trailing slashes
+    pub clean_urls: Option<TrailingSlash>,
+    /// List directories without an `index.html`
+    pub listing: Option<ListingConfig>,
+    /// Paths every site requires credentials for, in addition to its `_auth`
+    pub auth: AuthLayer,
+    /// Routes only some client addresses may request
+    pub ip_filter: Option<IpFilterConfig>,
+    pub precompressed: Precompressed,
+    /// Compress responses on the fly when there's no precompressed file
+    pub compression: Option<Compression>,
+}
+
+impl From<&Config> for ServeOptions {
+    fn from(config: &Config) -> Self {
+        Self {
+            spa: config.spa,
+            hidden_paths: config.reserved_paths.clone(),
+            cache_control: CacheControlLayer::new(
+                config.cache_control.clone(),
+                config.immutable.as_ref(),
+            ),
+            clean_urls: config
+                .clean_urls
+                .map(|clean_urls| clean_urls.trailing_slash),
+            listing: config.listing.clone(),
+            auth: config.auth.clone(),
+            ip_filter: config.ip_filter.clone(),
+            precompressed: config.precompressed,
+            compression: config.compression.as_ref().map(Compression::new),
+        }
+    }
+}
+
+#[derive(Clone)]
+/// A served directory or archive. Cloning a site is cheap, and all clones
+/// share the same reloadable state.
+pub struct Site {
+    root: PathBuf,
+    /// Set when some or all of the files are served from memory
+    memory: Option<InMemory>,
+    headers: HeadersLayer,
+    redirects: RedirectsLayer,
+    auth: AuthLayer,
+    etags: ETagLayer,
+    /// Whether compressed variants without a file get derived etags
+    derived_etags: bool,
+}
+
+/// Everything a [`Site`] is built from, read but not yet parsed.
+pub struct SiteContents {
+    /// Set when some or all of the files are served from memory
+    memory: Option<InMemory>,
+    /// The text of `_headers`, empty if there isn't one
+    pub headers: String,
+    /// The text of `_redirects`, empty if there isn't one
+    pub redirects: String,
+    /// The text of `_auth`, empty if there isn't one
+    pub auth: String,
+    /// The etags of every file in the site, keyed by path
+    pub etags: ETagMap,
+}
+
+impl SiteContents {
+    /// Read `_headers`, `_redirects` and `_auth`, and hash every file in `root`, which
+    /// is either a directory or an archive that [`ArchiveKind::of`] recognizes.
+    /// With `derived_etags`, files get etags for encodings which are compressed
+    /// on the fly. With `preload`, a directory's files are read into memory as
+    /// they're hashed, as far as its limits allow.
+    /// # Errors
+    /// If the archive or any of the configuration files can't be read, or a
+    /// file can't be hashed.
+    pub fn read(
+        root: &Path,
+        derived_etags: bool,
+        preload: Option<PreloadConfig>,
+    ) -> Result<Self, SiteError> {
+        let source = ArchiveKind::of(root)
+            .filter(|_| !root.is_dir())
+            .map(MemorySource::Archive)
+            .or_else(|| preload.map(MemorySource::Preload));
+        let (files, etags) = read_files(root, source, derived_etags)?;
+        let config_files = files
+            .as_ref()
+            .filter(|_| source.is_some_and(MemorySource::is_archive));
+        let headers = read_config(root, config_files, "_headers")?;
+        let redirects = read_config(root, config_files, "_redirects")?;
+        let auth = read_config(root, config_files, "_auth")?;
+
+        let memory = source.zip(files).map(|(source, files)| InMemory {
+            source,
+            files: Arc::new(ArcSwap::from_pointee(files)),
+        });
+        Ok(Self {
+            memory,
+            headers,
+            redirects,
+            auth,
+            etags,
+        })
+    }
+}
+
+impl Site {
+    /// Read a site with [`SiteContents::read`], and build it.
+    /// # Errors
+    /// If the archive or any of the configuration files can't be read or
+    /// parsed, or a file can't be hashed.
+    pub fn load(
+        root: PathBuf,
+        derived_etags: bool,
+        preload: Option<PreloadConfig>,
+    ) -> Result<Self, SiteError> {
+        let contents = SiteContents::read(&root, derived_etags, preload)?;
+        Self::from_contents(root, contents, derived_etags)
+    }
+
+    /// Parse the configuration files in `contents`, and build a site from them.
+    /// `derived_etags` should be what the contents were read with.
+    /// # Errors
+    /// If `_headers`, `_redirects` or `_auth` can't be parsed.
+    pub fn from_contents(
+        root: PathBuf,
+        contents: SiteContents,
+        derived_etags: bool,
+    ) -> Result<Self, SiteError> {
+        let SiteContents {
+            memory,
+            headers,
+            redirects,
+            auth,
+            etags,
+        } = contents;
+        let headers = tunnelbana_headers::parse(&headers)?;
+        let redirects = tunnelbana_redirects::parse(&redirects)?;
+        let auth = tunnelbana_auth::parse(&auth)?;
+
+        let headers = HeadersLayer::new(headers).map_err(SiteError::HeadersRouter)?;
+        let redirects = RedirectsLayer::new(redirects).map_err(SiteError::RedirectsRouter)?;
+        let auth = AuthLayer::new(auth).map_err(SiteError::AuthRouter)?;
+        let etags = ETagLayer::new(etags);
+
+        Ok(Self {
+            root,
+            memory,
+            headers,
+            redirects,
+            auth,
+            etags,
+            derived_etags,
+        })
+    }
+
+    /// Re-read the files in memory and re-hash every file, then re-read
+    /// `_headers`, `_redirects` and `_auth`. Each part which fails to load is logged and
+    /// keeps its last good configuration. If the site is served from an
+    /// archive which can't be read, the whole site is kept.
+    pub fn reload(&self) {
+        let source = self.memory.as_ref().map(|memory| memory.source);
+        let is_archive = source.is_some_and(MemorySource::is_archive);
+        let files = match read_files(&self.root, source, self.derived_etags) {
+            Ok((files, etags)) => {
+                let files = files.map(Arc::new);
+                if let Some((memory, files)) = self.memory.as_ref().zip(files.as_ref()) {
+                    memory.files.store(files.clone());
+                }
+                self.etags.reload(etags);
+                files
+            }
+            Err(e) if is_archive => {
+                error!(root = ?self.root, error = %e, "Failed to re-read site archive, keeping the old site");
+                return;
+            }
+            Err(e) => {
+                error!(root = ?self.root, error = %e, "Failed to re-read files, keeping the old ones");
+                None
+            }
+        };
+        let config_files = files.as_deref().filter(|_| is_archive);
+
+        let headers = read_headers(&self.root, config_files).and_then(|headers| {
+            self.headers
+                .reload(headers)
+                .map_err(SiteError::HeadersRouter)
+        });
+        if let Err(e) = headers {
+            error!(root = ?self.root, error = %e, "Failed to reload _headers, keeping the old ones");
+        }
+
+        let redirects = read_redirects(&self.root, config_files).and_then(|redirects| {
+            self.redirects
+                .reload(redirects)
+                .map_err(SiteError::RedirectsRouter)
+        });
+        if let Err(e) = redirects {
+            error!(root = ?self.root, error = %e, "Failed to reload _redirects, keeping the old ones");
+        }
+
+        let auth = read_auth(&self.root, config_files)
+            .and_then(|auth| self.auth.reload(auth).map_err(SiteError::AuthRouter));
+        if let Err(e) = auth {
+            error!(root = ?self.root, error = %e, "Failed to reload _auth, keeping the old groups");
+        }
+
+        info!(root = ?self.root, "Finished reloading site");
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub const fn headers(&self) -> &HeadersLayer {
+        &self.headers
+    }
+
+    pub const fn redirects(&self) -> &RedirectsLayer {
+        &self.redirects
+    }
+
+    /// The layer which serves this site's clean URLs, which passes every
+    /// request through unless `options` turns them on.
+    pub fn clean_urls(&self, options: &ServeOptions) -> CleanUrlsLayer {
+        CleanUrlsLayer::new(self.etags.clone(), options.clean_urls)
+    }
+
+    /// Whether the site has a file at `key`, like `/index.html`, in memory or on disk.
+    pub fn has_file(&self, key: &str) -> bool {
+        if let Some(memory) = &self.memory {
+            if memory.files.load().contains(key) {
+                return true;
+            }
+            if memory.source.is_archive() {
+                return false;
+            }
+        }
+        self.root.join(key.trim_start_matches('/')).is_file()
+    }
+
+    /// Build the service which serves this site.
+    /// # Errors
+    /// If the reserved paths can't be hidden, or the IP filter's routes can't be routed.
+    pub fn service(&self, options: &ServeOptions) -> Result<SiteService, SiteError> {
+        // without a fallback, so missing error pages aren't replaced by the not found response
+        let error_pages = self.serve_files(options.precompressed, None);
+        let not_found_svc = if options.spa {
+            self.not_found_file(options.precompressed, "index.html")
+        } else {
+            // filled in with the nearest 404.html by the error pages layer
+            SiteService::new(tower::service_fn(|_| {
+                std::future::ready(Ok(empty_response(StatusCode::NOT_FOUND)))
+            }))
+        };
+        let serve_files = self.serve_files(options.precompressed, Some(&not_found_svc));
+        let ip_filter = self.ip_filter(options.ip_filter.as_ref(), &error_pages)?;
+
+        let hide_special_files = tunnelbana_hidepaths::HidePathsLayer::builder()
+            .hide_all(RESERVED_PATHS)
+            .hide_all(
+                options
+                    .hidden_paths
+                    .iter()
+                    .map(String::as_str)
+                    .filter(|path| !RESERVED_PATHS.contains(path)),
+            )
+            .with_not_found_service(not_found_svc)
+            .build()?;
+        let listing = ListingLayer::new(
+            options.listing.as_ref(),
+            self.listing_source(),
+            self.etags.clone(),
+            hide_special_files.clone(),
+        );
+
+        let set_vary = SetResponseHeaderLayer::appending(
+            http::header::VARY,
+            HeaderValue::from_name(http::header::ACCEPT_ENCODING),
+        );
+
+        // boxed so the compression layer only has to handle one body type
+        let files = ServiceBuilder::new()
+            .map_response(|res: Response<_>| res.map(ResponseBody::new))
+            .layer(hide_special_files)
+            .layer(set_vary)
+            .layer(options.cache_control.clone())
+            .layer(listing)
+            .service(serve_files);
+        let files = BoxCloneSyncService::new(files);
+
+        let clean_urls = self.clean_urls(options);
+        let served_path = clean_urls.clone();
+
+        // outside the headers, so a `Cache-Control` from `_headers` is made private too
+        let service = ServiceBuilder::new()
+            // so the IP filter and auth also guard the file a request is served from
+            .map_request(move |mut req: Request<RequestBody>| {
+                if let Some(path) = served_path.served_path(req.uri().path()) {
+                    req.extensions_mut().insert(ServedPath(path));
+                }
+                req
+            })
+            .layer(ip_filter)
+            .layer(options.auth.clone())
+            .layer(self.auth.clone())
+            .layer(self.headers.clone())
+            .layer(MethodsLayer)
+            .layer(ErrorPagesLayer::new(self.etags.clone(), error_pages))
+            .layer(self.redirects.clone())
+            .layer(clean_urls)
+            .layer(self.etags.clone())
+            .layer(CompressionLayer::new(
+                options.compression.clone(),
+                &self.root,
+            ))
+            .service(files);
+
+        Ok(BoxCloneSyncService::new(service))
+    }
+
+    /// The layer which denies requests from clients `config` doesn't allow,
+    /// sending them its status with the site's error page.
+    fn ip_filter(
+        &self,
+        config: Option<&IpFilterConfig>,
+        error_pages: &SiteService,
+    ) -> Result<IpFilterLayer, SiteError> {
+        let Some(config) = config else {
+            return Ok(IpFilterLayer::builder().build()?);
+        };
+        let status = config.status.status();
+        let denied = ErrorPagesLayer::new(self.etags.clone(), error_pages.clone()).layer(
+            tower::service_fn(move |_| std::future::ready(Ok(empty_response(status)))),
+        );
+        let rules = config.rules.iter().map(|rule| {
+            let ip_rule = IpRule {
+                allow: rule.allow.clone(),
+                deny: rule.deny.clone(),
+            };
+            (rule.path.as_str(), ip_rule)
+        });
+        Ok(IpFilterLayer::builder()
+            .rules(rules)
+            .with_denied_service(SiteService::new(denied))
+            .build()?)
+    }
+
+    /// Where the files to list are. Preloaded sites are listed from disk,
+    /// since only some of their files are in memory.
+    fn listing_source(&self) -> ListingSource {
+        match &self.memory {
+            Some(InMemory {
+                source: MemorySource::Archive(_),
+                files,
+            }) => ListingSource::Memory(files.clone()),
+            _ => ListingSource::Disk(self.root.as_path().into()),
+        }
+    }
+
+    /// The service which serves the file at `path` for every request.
+    fn not_found_file(&self, precompressed: Precompressed, path: &str) -> SiteService {
+        let from_disk = || {
+            boxed(precompressed!(
+                ServeFile::new(self.root.join(path)),
+                precompressed
+            ))
+        };
+        match &self.memory {
+            None => from_disk(),
+            Some(InMemory {
+                source: MemorySource::Archive(_),
+                files,
+            }) => SiteService::new(ServeMemory::file(files.clone(), precompressed, path)),
+            Some(InMemory {
+                source: MemorySource::Preload(_),
+                files,
+            }) => SiteService::new(
+                ServeMemory::file(files.clone(), precompressed, path).fallback(from_disk()),
+            ),
+        }
+    }
+
+    /// The service which serves the site's files, calling `fallback` when
+    /// there's no file, or sending an empty 404 without one. It's boxed, so
+    /// the rest of the stack is the same whether files are served from disk
+    /// or from memory.
+    fn serve_files(
+        &self,
+        precompressed: Precompressed,
+        fallback: Option<&SiteService>,
+    ) -> SiteService {
+        let serve_dir = || {
+            let serve_dir = precompressed!(
+                ServeDir::new(&self.root).append_index_html_on_directories(true),
+                precompressed
+            );
+            match fallback.cloned() {
+                Some(fallback) => boxed(serve_dir.fallback(fallback)),
+                None => boxed(serve_dir),
+            }
+        };
+        match &self.memory {
+            None => serve_dir(),
+            Some(InMemory {
+                source: MemorySource::Archive(_),
+                files,
+            }) => {
+                let serve_memory = ServeMemory::dir(files.clone(), precompressed);
+                match fallback.cloned() {
+                    Some(fallback) => SiteService::new(serve_memory.fallback(fallback)),
+                    None => SiteService::new(serve_memory),
+                }
+            }
+            // files which didn't fit in memory are still on disk
+            Some(InMemory {
+                source: MemorySource::Preload(_),
+                files,
+            }) => SiteService::new(
+                ServeMemory::dir(files.clone(), precompressed).fallback(serve_dir()),
+            ),
+        }
+    }
+
+    /// Watch the site directory or archive, and reload the site whenever it changes.
+    /// # Errors
+    /// If the filesystem watcher can't be started.
+    pub fn watch(&self) -> Result<impl Future<Output = ()> + use<>, notify::Error> {
+        // archives are usually replaced by renaming a new one over them, which
+        // only shows up when watching the directory they're in
+        let is_archive = self
+            .memory
+            .as_ref()
+            .is_some_and(|memory| memory.source.is_archive());
+        let (target, mode, only) = match (is_archive, self.root.parent()) {
+            (true, Some(parent)) => (parent, RecursiveMode::NonRecursive, Some(self.root.clone())),
+            _ => (self.root.as_path(), RecursiveMode::Recursive, None),
+        };
+        let (change_tx, mut change_rx) = mpsc::channel(1);
+        let mut watcher = RecommendedWatcher::new(
+            move |event: notify::Result<notify::Event>| match event {
+                Ok(event) if event.kind.is_access() => {}
+                Ok(event)
+                    if only
+                        .as_ref()
+                        .is_some_and(|only| !event.paths.contains(only)) => {}
+                // a full channel means a reload is already pending
+                Ok(_) => _ = change_tx.try_send(()),
+                Err(e) => warn!("filesystem watch error: {}", e),
+            },
+            notify::Config::default(),
+        )?;
+        watcher.watch(target, mode)?;
+
+        let site = self.clone();
+        Ok(async move {
+            // the watcher stops when it is dropped
+            let _watcher = watcher;
+            while change_rx.recv().await.is_some() {
+                while tokio::time::timeout(RELOAD_DEBOUNCE, change_rx.recv()).await == Ok(Some(()))
+                {
+                }
+                site.reload_in_background().await;
+            }
+        })
+    }
+
+    /// Reload the site on the blocking thread pool, since hashing can take a while.
+    pub async fn reload_in_background(&self) {
+        let site = self.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || site.reload()).await {
+            error!("Site reload panicked: {}", e);
+        }
+    }
+}
+
+#[derive(Clone)]
+/// Files served from memory rather than from the site directory.
+struct InMemory {
+    source: MemorySource,
+    /// Replaced whenever the site is reloaded
+    files: Arc<ArcSwap<MemoryFs>>,
+}
+
+#[derive(Clone, Copy)]
+enum MemorySource {
+    /// The whole site, read out of an archive
+    Archive(ArchiveKind),
+    /// The files in the site directory which fit within the limits. The rest
+    /// are served from disk
+    Preload(PreloadConfig),
+}
+
+impl MemorySource {
+    const fn is_archive(self) -> bool {
+        matches!(self, Self::Archive(_))
+    }
+}
+
+/// Box a file service, so each kind of site builds the same stack on top of it.
+fn boxed<S, B>(service: S) -> SiteService
+where
+    S: Service<Request<RequestBody>, Response = Response<B>, Error = Infallible>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    S::Future: Send + 'static,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    BoxCloneSyncService::new(
+        ServiceBuilder::new()
+            .map_response(|res: Response<B>| {
+                res.map(|body| ResponseBody::new(body.map_err(Into::into)))
+            })
+            .service(service),
+    )
+}
+
+/// Hash the site's files, reading them into memory first if `source` says to.
+fn read_files(
+    root: &Path,
+    source: Option<MemorySource>,
+    derived: bool,
+) -> Result<(Option<MemoryFs>, ETagMap), SiteError> {
+    match source {
+        Some(MemorySource::Archive(kind)) => {
+            let files = archive::read(root, kind)?;
+            let etags = files.etags(derived)?;
+            Ok((Some(files), etags))
+        }
+        Some(MemorySource::Preload(limits)) => {
+            let (files, etags) = MemoryFs::preload(root, limits, derived)?;
+            Ok((Some(files), etags))
+        }
+        None if derived => Ok((None, ETagMap::with_derived_tags(root)?)),
+        None => Ok((None, ETagMap::new(root)?)),
+    }
+}
+
+fn read_headers(root: &Path, files: Option<&MemoryFs>) -> Result<Vec<HeaderGroup>, SiteError> {
+    let headers = read_config(root, files, "_headers")?;
+    Ok(tunnelbana_headers::parse(&headers)?)
+}
+
+fn read_redirects(root: &Path, files: Option<&MemoryFs>) -> Result<Vec<Redirect>, SiteError> {
+    let redirects = read_config(root, files, "_redirects")?;
+    Ok(tunnelbana_redirects::parse(&redirects)?)
+}
+
+fn read_auth(root: &Path, files: Option<&MemoryFs>) -> Result<Vec<AuthGroup>, SiteError> {
+    let auth = read_config(root, files, "_auth")?;
+    Ok(tunnelbana_auth::parse(&auth)?)
+}
+
+/// Read a configuration file from the site's root, from `files` if the site is an archive.
+fn read_config(
+    root: &Path,
+    files: Option<&MemoryFs>,
+    name: &'static str,
+) -> Result<String, SiteError> {
+    if let Some(files) = files {
+        return files
+            .read_to_string(&format!("/{name}"))
+            .map(Option::unwrap_or_default)
+            .map_err(|e| SiteError::Read(name, e));
+    }
+    read_with_default_if_nonexistent(root.join(name)).map_err(|e| SiteError::Read(name, e))
+}
+
+fn read_with_default_if_nonexistent(path: impl AsRef<Path>) -> Result<String, IoError> {
+    match std::fs::read_to_string(path.as_ref()) {
+        Ok(v) => Ok(v),
+        Err(e) => match e.kind() {
+            IoErrorKind::NotFound => Ok(String::new()),
+            _ => Err(e),
+        },
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+/// Errors from loading a site's configuration.
+pub enum SiteError {
+    #[error("Failed to read archive: {0}")]
+    Archive(#[from] ArchiveError),
+    #[error("Failed to read {0}: {1}")]
+    Read(&'static str, IoError),
+    #[error("Failed to parse _headers: {0}")]
+    Headers(#[from] HeaderParseError),
+    #[error("Failed to parse _redirects: {0}")]
+    Redirects(#[from] RedirectParseError),
+    #[error("Failed to parse _auth: {0}")]
+    Auth(#[from] AuthParseError),
+    #[error("Failed to build headers router: {0}")]
+    HeadersRouter(tunnelbana_headers::InsertError),
+    #[error("Failed to build redirects router: {0}")]
+    RedirectsRouter(tunnelbana_redirects::InsertError),
+    #[error("Failed to build auth router: {0}")]
+    AuthRouter(tunnelbana_auth::InsertError),
+    #[error("Failed to generate etags: {0}")]
+    ETags(#[from] TagMapBuildError),
+    #[error("Failed to build path hide layer: {0}")]
+    HidePaths(#[from] HidePathsLayerBuilderError),
+    #[error("Failed to build IP filter: {0}")]
+    IpFilter(#[from] IpFilterError),
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{BodyExt, Empty};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{ip_filter::Denied, proxy::ClientAddr};
+
+    fn request(path: &str) -> Request<RequestBody> {
+        let body = RequestBody::new(Empty::new().map_err(|e| match e {}));
+        Request::builder().uri(path).body(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_last_good_config() {
+        let root = std::env::temp_dir().join(format!("tunnelbana-site-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "hello").unwrap();
+        std::fs::write(root.join("_headers"), "/\n  X-Version: first\n").unwrap();
+        std::fs::write(root.join("_redirects"), "/old /first\n").unwrap();
+
+        let site = Site::load(root.clone(), false, None).unwrap();
+        let options = ServeOptions::from(&Config::default());
+        let service = site.service(&options).unwrap();
+
+        // a header line without a path can't be parsed
+        std::fs::write(root.join("_headers"), "  X-Version: broken\n").unwrap();
+        std::fs::write(root.join("_redirects"), "/old /second\n").unwrap();
+        site.reload();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let resp = service.clone().oneshot(request("/")).await.unwrap();
+        assert_eq!(resp.headers()["x-version"], "first");
+        let resp = service.oneshot(request("/old")).await.unwrap();
+        assert_eq!(resp.headers()[http::header::LOCATION], "/second");
+    }
+
+    #[tokio::test]
+    async fn ip_filter_guards_clean_urls() {
+        let root = std::env::temp_dir().join(format!("tunnelbana-site-ip-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("admin.html"), "admin").unwrap();
+        let site = Site::load(root.clone(), false, None).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        let ip_filter = IpFilterConfig {
+            rules: vec![crate::config::IpFilterRule {
+                path: "/admin.html".to_owned(),
+                allow: Vec::new(),
+                deny: vec!["0.0.0.0/0".parse().unwrap()],
+            }],
+            ..IpFilterConfig::default()
+        };
+        let options = ServeOptions {
+            clean_urls: Some(TrailingSlash::Auto),
+            ip_filter: Some(ip_filter),
+            ..ServeOptions::from(&Config::default())
+        };
+        let service = site.service(&options).unwrap();
+
+        for path in ["/admin", "/admin.html"] {
+            let mut req = request(path);
+            req.extensions_mut()
+                .insert(ClientAddr(Some("192.0.2.1".parse().unwrap())));
+            let resp = service.clone().oneshot(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{path}");
+            assert!(resp.extensions().get::<Denied>().is_some(), "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn auth_guards_clean_urls() {
+        let root =
+            std::env::temp_dir().join(format!("tunnelbana-site-auth-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("team")).unwrap();
+        std::fs::write(root.join("private.html"), "secret").unwrap();
+        std::fs::write(root.join("team/index.html"), "roster").unwrap();
+        std::fs::write(
+            root.join("_auth"),
+            "/private.html\n  Bearer s3cret\n/team/index.html\n  Bearer s3cret\n",
+        )
+        .unwrap();
+        let site = Site::load(root.clone(), false, None).unwrap();
+        let options = ServeOptions {
+            clean_urls: Some(TrailingSlash::Auto),
+            ..ServeOptions::from(&Config::default())
+        };
+        let service = site.service(&options).unwrap();
+
+        for path in ["/private", "/private.html", "/team/"] {
+            let resp = service.clone().oneshot(request(path)).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{path}");
+        }
+        // auth is checked before methods are answered
+        let mut req = request("/private");
+        *req.method_mut() = http::Method::OPTIONS;
+        let resp = service.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        // not served, only redirected to `/private`
+        let resp = service.clone().oneshot(request("/private/")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+
+        let mut req = request("/private");
+        req.headers_mut().insert(
+            http::header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer s3cret"),
+        );
+        let resp = service.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn head_is_served_like_get() {
+        let root =
+            std::env::temp_dir().join(format!("tunnelbana-site-head-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("page.txt"), "hello world").unwrap();
+        std::fs::write(root.join("_redirects"), "/old /page.txt\n").unwrap();
+        let site = Site::load(root.clone(), false, None).unwrap();
+        let options = ServeOptions::from(&Config::default());
+        let service = site.service(&options).unwrap();
+        let head = |path: &str| {
+            let mut req = request(path);
+            *req.method_mut() = http::Method::HEAD;
+            req
+        };
+
+        let get = service.clone().oneshot(request("/page.txt")).await.unwrap();
+        let resp = service.clone().oneshot(head("/page.txt")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        for name in [http::header::ETAG, http::header::CONTENT_LENGTH] {
+            assert_eq!(
+                resp.headers().get(&name),
+                get.headers().get(&name),
+                "{name}"
+            );
+        }
+        assert_eq!(resp.headers()[http::header::CONTENT_LENGTH], "11");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.is_empty());
+
+        let mut req = head("/page.txt");
+        req.headers_mut().insert(
+            http::header::IF_NONE_MATCH,
+            get.headers()[http::header::ETAG].clone(),
+        );
+        let resp = service.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        let get = service.clone().oneshot(request("/old")).await.unwrap();
+        let resp = service.oneshot(head("/old")).await.unwrap();
+        assert!(get.status().is_redirection());
+        assert_eq!(resp.status(), get.status());
+        assert_eq!(resp.headers()[http::header::LOCATION], "/page.txt");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+//! systemd integration: socket activation and readiness notification.
+//!
+//! Both are no-ops when tunnelbana isn't started by systemd.
+use std::{
+    io::Error as IoError,
+    os::fd::{FromRawFd, OwnedFd},
+};
+
+use sd_notify::NotifyState;
+
+use crate::listener::ListenerSource;
+
+/// Sockets named with `FileDescriptorName=tls` in the socket unit
+/// have TLS terminated on them.
+pub const TLS_FD_NAME: &str = "tls";
+
+/// A listening socket passed to us by the service manager.
+pub struct InheritedSocket {
+    pub fd: OwnedFd,
+    /// The `FileDescriptorName=` of the socket, or `unknown`.
+    pub name: String,
+}
+
+impl From<InheritedSocket> for ListenerSource {
+    fn from(socket: InheritedSocket) -> Self {
+        Self::Inherited {
+            tls: socket.name == TLS_FD_NAME,
+            fd: socket.fd,
+        }
+    }
+}
+
+/// Take ownership of the sockets passed by systemd through `LISTEN_FDS`,
+/// if `LISTEN_PID` says they were meant for this process.
+///
+/// This must only be called once.
+/// # Errors
+/// If the systemd environment variables are malformed.
+pub fn inherited_sockets() -> Result<Vec<InheritedSocket>, IoError> {
+    let sockets = sd_notify::listen_fds_with_names(false)?
+        .map(|(fd, name)| InheritedSocket {
+            // SAFETY: systemd hands these descriptors to this process, and this function
+            // is only called once, so nothing else owns them.
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            name,
+        })
+        .collect();
+    Ok(sockets)
+}
+
+/// Tell systemd that we're accepting connections.
+pub fn notify_ready() {
+    notify(NotifyState::Ready);
+}
+
+/// Tell systemd that we've started shutting down.
+pub fn notify_stopping() {
+    notify(NotifyState::Stopping);
+}
+
+fn notify(state: NotifyState) {
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        warn!("failed to notify systemd: {}", e);
+    }
+}
@@ -0,0 +1,367 @@
+//! TLS termination, with certificates picked by SNI and reloaded from disk
+//! when they change.
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+use arc_swap::ArcSwap;
+use rustls::{
+    ServerConfig,
+    crypto::CryptoProvider,
+    pki_types::{
+        CertificateDer, PrivateKeyDer,
+        pem::{Error as PemError, PemObject},
+    },
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tokio_rustls::TlsAcceptor;
+
+const CERT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A PEM certificate chain and the private key which goes with it.
+pub struct CertSource {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl CertSource {
+    fn load(&self, provider: &CryptoProvider) -> Result<CertifiedKey, CertLoadError> {
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+            .map_err(|e| CertLoadError::Certs(self.cert.clone(), e))?;
+        if certs.is_empty() {
+            return Err(CertLoadError::NoCerts(self.cert.clone()));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .map_err(|e| CertLoadError::Key(self.key.clone(), e))?;
+        CertifiedKey::from_der(certs, key, provider)
+            .map_err(|e| CertLoadError::Rustls(self.cert.clone(), e))
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let cert = std::fs::metadata(&self.cert).and_then(|m| m.modified());
+        let key = std::fs::metadata(&self.key).and_then(|m| m.modified());
+        cert.ok().zip(key.ok())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A certificate for one hostname, as passed to `--tls-sni`.
+///
+/// Written as `HOST=CERT,KEY`, for example
+/// `example.org=/etc/tls/example.org.pem,/etc/tls/example.org.key`.
+/// The host may start with `*.` to match any single subdomain.
+pub struct SniCert {
+    pub host: String,
+    pub source: CertSource,
+}
+
+impl FromStr for SniCert {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, paths) = s
+            .split_once('=')
+            .ok_or_else(|| format!("`{s}` should be written as HOST=CERT,KEY"))?;
+        let (cert, key) = paths
+            .split_once(',')
+            .ok_or_else(|| format!("`{s}` is missing a key path, expected HOST=CERT,KEY"))?;
+        if host.is_empty() || cert.is_empty() || key.is_empty() {
+            return Err(format!("`{s}` should be written as HOST=CERT,KEY"));
+        }
+        Ok(Self {
+            host: host.to_ascii_lowercase(),
+            source: CertSource {
+                cert: cert.into(),
+                key: key.into(),
+            },
+        })
+    }
+}
+
+#[derive(Debug)]
+struct CertEntry {
+    source: CertSource,
+    key: ArcSwap<CertifiedKey>,
+    modified: Mutex<Option<(SystemTime, SystemTime)>>,
+}
+
+impl CertEntry {
+    fn new(source: CertSource, provider: &CryptoProvider) -> Result<Self, CertLoadError> {
+        let modified = source.modified();
+        let key = source.load(provider)?;
+        Ok(Self {
+            source,
+            key: ArcSwap::from_pointee(key),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Reload this certificate if it has changed on disk. If the new
+    /// certificate can't be loaded, the old one keeps being served.
+    fn reload_if_changed(&self, provider: &CryptoProvider) {
+        let modified = self.source.modified();
+        if modified.is_none() || modified == *self.last_modified() {
+            return;
+        }
+        match self.source.load(provider) {
+            Ok(key) => {
+                self.key.store(Arc::new(key));
+                *self.last_modified() = modified;
+                info!(cert = ?self.source.cert, "Reloaded TLS certificate");
+            }
+            Err(e) => error!(error = %e, "Failed to reload TLS certificate, keeping the old one"),
+        }
+    }
+
+    fn last_modified(&self) -> MutexGuard<'_, Option<(SystemTime, SystemTime)>> {
+        self.modified.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[derive(Debug)]
+/// A [`ResolvesServerCert`] which picks certificates by SNI hostname, falling
+/// back to an optional default.
+pub struct CertStore {
+    provider: Arc<CryptoProvider>,
+    default: Option<CertEntry>,
+    by_host: HashMap<String, CertEntry>,
+}
+
+impl CertStore {
+    /// Load every certificate from disk.
+    /// # Errors
+    /// If any certificate or key can't be read, or they don't match.
+    pub fn new(default: Option<CertSource>, sni: Vec<SniCert>) -> Result<Self, CertLoadError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let default = default
+            .map(|source| CertEntry::new(source, &provider))
+            .transpose()?;
+        let mut by_host = HashMap::with_capacity(sni.len());
+        for SniCert { host, source } in sni {
+            let entry = CertEntry::new(source, &provider)?;
+            by_host.insert(host, entry);
+        }
+        Ok(Self {
+            provider,
+            default,
+            by_host,
+        })
+    }
+
+    /// Build a [`TlsAcceptor`] which negotiates h2 and http/1.1 using this store.
+    /// # Errors
+    /// If the default protocol versions are unsupported by the crypto provider.
+    pub fn acceptor(self: &Arc<Self>) -> Result<TlsAcceptor, rustls::Error> {
+        let mut config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /// Build a TLS 1.3 only config which negotiates h3 using this store, for QUIC.
+    /// # Errors
+    /// If TLS 1.3 is unsupported by the crypto provider.
+    pub fn quic_config(self: &Arc<Self>) -> Result<ServerConfig, rustls::Error> {
+        let mut config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"h3".to_vec()];
+        Ok(config)
+    }
+
+    /// Reload every certificate which has changed on disk.
+    pub fn reload_changed(&self) {
+        for entry in self.default.iter().chain(self.by_host.values()) {
+            entry.reload_if_changed(&self.provider);
+        }
+    }
+
+    /// Poll certificates for changes forever.
+    pub async fn watch(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(CERT_POLL_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.reload_changed();
+        }
+    }
+
+    fn entry_for(&self, host: &str) -> Option<&CertEntry> {
+        let host = host.to_ascii_lowercase();
+        if let Some(entry) = self.by_host.get(&host) {
+            return Some(entry);
+        }
+        let (_, parent) = host.split_once('.')?;
+        self.by_host.get(&format!("*.{parent}"))
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let entry = client_hello
+            .server_name()
+            .and_then(|name| self.entry_for(name))
+            .or(self.default.as_ref());
+        if entry.is_none() {
+            debug!(sni = ?client_hello.server_name(), "No certificate for TLS client");
+        }
+        entry.map(|entry| entry.key.load_full())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+/// Errors from reading certificates and keys off disk.
+pub enum CertLoadError {
+    #[error("could not read certificates from {0:?}: {1}")]
+    Certs(PathBuf, PemError),
+    #[error("no certificates found in {0:?}")]
+    NoCerts(PathBuf),
+    #[error("could not read private key from {0:?}: {1}")]
+    Key(PathBuf, PemError),
+    #[error("invalid certificate or key for {0:?}: {1}")]
+    Rustls(PathBuf, rustls::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use rustls::{ClientConfig, RootCertStore, pki_types::ServerName};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    /// Write a self-signed certificate for `hosts` into `dir`, returning its DER too.
+    fn write_cert(dir: &Path, name: &str, hosts: &[&str]) -> (CertSource, CertificateDer<'static>) {
+        let hosts: Vec<String> = hosts.iter().map(ToString::to_string).collect();
+        let generated = rcgen::generate_simple_self_signed(hosts).unwrap();
+        let source = CertSource {
+            cert: dir.join(format!("{name}.pem")),
+            key: dir.join(format!("{name}.key")),
+        };
+        std::fs::write(&source.cert, generated.cert.pem()).unwrap();
+        std::fs::write(&source.key, generated.signing_key.serialize_pem()).unwrap();
+        (source, generated.cert.der().clone())
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("tunnelbana-tls-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Connect to `store` as `host`, returning the certificate and protocol it picked.
+    async fn handshake(
+        store: &Arc<CertStore>,
+        roots: &[&CertificateDer<'static>],
+        host: &'static str,
+    ) -> (CertificateDer<'static>, Option<Vec<u8>>) {
+        let mut root_store = RootCertStore::empty();
+        for root in roots {
+            root_store.add((*root).clone()).unwrap();
+        }
+        let mut config = ClientConfig::builder_with_provider(store.provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let acceptor = store.acceptor().unwrap();
+        let server = tokio::spawn(async move { acceptor.accept(server).await.unwrap() });
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from(host).unwrap(), client)
+            .await
+            .unwrap();
+        server.await.unwrap();
+        let (_, conn) = stream.get_ref();
+        let cert = conn.peer_certificates().unwrap()[0].clone();
+        (cert, conn.alpn_protocol().map(<[u8]>::to_vec))
+    }
+
+    #[tokio::test]
+    async fn picks_certificates_by_sni() {
+        let dir = temp_dir("sni");
+        let (default, default_der) = write_cert(&dir, "default", &["fallback.test"]);
+        let (exact, exact_der) = write_cert(&dir, "exact", &["example.org"]);
+        let (wildcard, wildcard_der) = write_cert(&dir, "wildcard", &["*.example.net"]);
+        let sni = vec![
+            SniCert {
+                host: "example.org".to_owned(),
+                source: exact,
+            },
+            SniCert {
+                host: "*.example.net".to_owned(),
+                source: wildcard,
+            },
+        ];
+        let store = Arc::new(CertStore::new(Some(default), sni).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(store.entry_for("EXAMPLE.org").is_some());
+        assert!(store.entry_for("www.example.net").is_some());
+        assert!(store.entry_for("a.b.example.net").is_none());
+        assert!(store.entry_for("example.net").is_none());
+
+        let roots = [&default_der, &exact_der, &wildcard_der];
+        let (cert, alpn) = handshake(&store, &roots, "example.org").await;
+        assert_eq!(cert, exact_der);
+        assert_eq!(alpn.as_deref(), Some(&b"h2"[..]));
+        let (cert, _) = handshake(&store, &roots, "www.example.net").await;
+        assert_eq!(cert, wildcard_der);
+        let (cert, _) = handshake(&store, &roots, "fallback.test").await;
+        assert_eq!(cert, default_der);
+    }
+
+    #[test]
+    fn reload_keeps_old_certificate_on_failure() {
+        let dir = temp_dir("reload");
+        let (source, first_der) = write_cert(&dir, "site", &["example.org"]);
+        let store = CertStore::new(Some(source.clone()), Vec::new()).unwrap();
+        let served = || store.default.as_ref().unwrap().key.load().cert[0].clone();
+        let touch = |path: &Path, secs: u64| {
+            let time = SystemTime::now() + Duration::from_secs(secs);
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        };
+
+        std::fs::write(&source.cert, "not a certificate").unwrap();
+        touch(&source.cert, 10);
+        store.reload_changed();
+        assert_eq!(served(), first_der);
+
+        let (_, second_der) = write_cert(&dir, "site", &["example.org"]);
+        touch(&source.cert, 20);
+        touch(&source.key, 20);
+        store.reload_changed();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(served(), second_der);
+        assert_ne!(first_der, second_der);
+    }
+
+    #[test]
+    fn parse_sni_cert() {
+        let cert: SniCert = "Example.org=/tls/cert.pem,/tls/key.pem".parse().unwrap();
+        assert_eq!(cert.host, "example.org");
+        assert_eq!(cert.source.cert, PathBuf::from("/tls/cert.pem"));
+        assert_eq!(cert.source.key, PathBuf::from("/tls/key.pem"));
+        assert!("example.org=/tls/cert.pem".parse::<SniCert>().is_err());
+        assert!("/tls/cert.pem,/tls/key.pem".parse::<SniCert>().is_err());
+    }
+}
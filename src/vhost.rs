@@ -0,0 +1,253 @@
+//! Picking which site serves a request by its `Host` header or `:authority`.
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Ready,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures_util::future::Either;
+use http::{Request, Response, StatusCode, header, uri::Authority};
+use tower::{Service, ServiceExt, util::Oneshot};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A site served for a set of hostnames, as passed to `--vhost`.
+///
+/// Written as `HOST[,HOST...]=DIR`, for example
+/// `example.org,www.example.org=/srv/example.org`. A host may start with
+/// `*.` to match any single subdomain.
+pub struct VhostConfig {
+    pub hosts: Vec<String>,
+    pub root: PathBuf,
+    /// Overrides the top-level `spa` for this site
+    pub spa: Option<bool>,
+}
+
+impl FromStr for VhostConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (hosts, root) = s
+            .split_once('=')
+            .ok_or_else(|| format!("`{s}` should be written as HOST[,HOST...]=DIR"))?;
+        let hosts: Vec<String> = hosts.split(',').map(normalize_host).collect();
+        if hosts.iter().any(String::is_empty) || root.is_empty() {
+            return Err(format!("`{s}` should be written as HOST[,HOST...]=DIR"));
+        }
+        Ok(Self {
+            hosts,
+            root: root.into(),
+            spa: None,
+        })
+    }
+}
+
+/// Lowercase a hostname and remove any trailing dot, so equivalent names compare equal.
+pub fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[derive(Clone, Copy, Debug)]
+/// Response extension marking a 421 for a host which isn't served here.
+pub struct Misdirected;
+
+#[derive(Clone)]
+/// A [`tower::Service`] which passes each request to the service for its host,
+/// falling back to a default service or a `421 Misdirected Request`.
+pub struct VirtualHosts<S> {
+    by_host: Arc<HashMap<String, S>>,
+    default: Option<S>,
+}
+
+impl<S> VirtualHosts<S> {
+    pub fn builder() -> VirtualHostsBuilder<S> {
+        VirtualHostsBuilder {
+            by_host: HashMap::new(),
+            default: None,
+        }
+    }
+
+    /// The service for the host `req` is for, if any.
+    pub fn route<B>(&self, req: &Request<B>) -> Option<&S> {
+        let authority = request_authority(req);
+        self.service_for(authority.as_ref().map(Authority::host))
+    }
+
+    fn service_for(&self, host: Option<&str>) -> Option<&S> {
+        let Some(host) = host.map(normalize_host) else {
+            return self.default.as_ref();
+        };
+        if let Some(service) = self.by_host.get(&host) {
+            return Some(service);
+        }
+        host.split_once('.')
+            .and_then(|(_, parent)| self.by_host.get(&format!("*.{parent}")))
+            .or(self.default.as_ref())
+    }
+}
+
+/// The authority a request is for. HTTP/2 requests carry it in the `:authority`,
+/// which ends up in the URI, and HTTP/1.1 requests in the `Host` header.
+fn request_authority<B>(req: &Request<B>) -> Option<Authority> {
+    if let Some(authority) = req.uri().authority() {
+        return Some(authority.clone());
+    }
+    req.headers().get(header::HOST)?.to_str().ok()?.parse().ok()
+}
+
+/// Builds a [`VirtualHosts`], refusing hostnames which are claimed twice.
+pub struct VirtualHostsBuilder<S> {
+    by_host: HashMap<String, S>,
+    default: Option<S>,
+}
+
+impl<S: Clone> VirtualHostsBuilder<S> {
+    /// Serve `hosts` with `service`.
+    /// # Errors
+    /// If one of the hosts already has a service.
+    pub fn hosts<'a>(
+        mut self,
+        hosts: impl IntoIterator<Item = &'a str>,
+        service: &S,
+    ) -> Result<Self, VhostError> {
+        for host in hosts {
+            let host = normalize_host(host);
+            if self.by_host.contains_key(&host) {
+                return Err(VhostError::DuplicateHost(host));
+            }
+            self.by_host.insert(host, service.clone());
+        }
+        Ok(self)
+    }
+
+    /// Serve requests for unknown hosts with `service`, rather than a 421.
+    #[must_use]
+    pub fn default_service(mut self, service: S) -> Self {
+        self.default = Some(service);
+        self
+    }
+
+    /// Serve requests for unknown hosts as if they were for `host`.
+    /// # Errors
+    /// If `host` hasn't been given a service.
+    pub fn default_host(self, host: &str) -> Result<Self, VhostError> {
+        let host = normalize_host(host);
+        let service = self
+            .by_host
+            .get(&host)
+            .cloned()
+            .ok_or(VhostError::UnknownDefault(host))?;
+        Ok(self.default_service(service))
+    }
+
+    pub fn build(self) -> VirtualHosts<S> {
+        VirtualHosts {
+            by_host: Arc::new(self.by_host),
+            default: self.default,
+        }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for VirtualHosts<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>, Error = Infallible> + Clone,
+    ResBody: Default,
+{
+    type Error = Infallible;
+    type Future =
+        Either<Oneshot<S, Request<ReqBody>>, Ready<Result<Response<ResBody>, Infallible>>>;
+    type Response = Response<ResBody>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // each request is sent to a clone of its site's service, which is readied then
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        if let Some(service) = self.route(&req) {
+            return Either::Left(service.clone().oneshot(req));
+        }
+        debug!(authority = ?request_authority(&req), "request for unknown host");
+        let mut response = Response::new(ResBody::default());
+        *response.status_mut() = StatusCode::MISDIRECTED_REQUEST;
+        response.extensions_mut().insert(Misdirected);
+        Either::Right(std::future::ready(Ok(response)))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VhostError {
+    #[error("host {0} is served by more than one site")]
+    DuplicateHost(String),
+    #[error("default host {0} isn't served by any site")]
+    UnknownDefault(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{BodyExt, Full};
+    use tower::util::BoxCloneSyncService;
+
+    use super::*;
+
+    type Body = Full<bytes::Bytes>;
+    type Site = BoxCloneSyncService<Request<()>, Response<Body>, Infallible>;
+
+    fn site(name: &'static str) -> Site {
+        BoxCloneSyncService::new(tower::service_fn(move |_: Request<()>| async move {
+            Ok(Response::new(Body::from(name)))
+        }))
+    }
+
+    async fn serve(vhosts: &VirtualHosts<Site>, req: Request<()>) -> (StatusCode, String) {
+        let response = vhosts.clone().oneshot(req).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn host(host: &str) -> Request<()> {
+        Request::builder()
+            .header(header::HOST, host)
+            .body(())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn routes_by_host() {
+        let vhosts = VirtualHosts::builder()
+            .hosts(["example.org", "www.example.org"], &site("example"))
+            .unwrap()
+            .hosts(["*.example.net"], &site("wildcard"))
+            .unwrap()
+            .build();
+        assert_eq!(serve(&vhosts, host("Example.org.:8080")).await.1, "example");
+        assert_eq!(serve(&vhosts, host("www.example.org")).await.1, "example");
+        assert_eq!(serve(&vhosts, host("docs.example.net")).await.1, "wildcard");
+        let http2 = Request::get("https://www.example.org/").body(()).unwrap();
+        assert_eq!(serve(&vhosts, http2).await.1, "example");
+
+        let (status, _) = serve(&vhosts, host("example.com")).await;
+        assert_eq!(status, StatusCode::MISDIRECTED_REQUEST);
+        let (status, _) = serve(&vhosts, Request::new(())).await;
+        assert_eq!(status, StatusCode::MISDIRECTED_REQUEST);
+
+        let vhosts = VirtualHosts::builder()
+            .hosts(["example.org"], &site("example"))
+            .unwrap()
+            .default_host("example.org")
+            .unwrap()
+            .build();
+        assert_eq!(serve(&vhosts, host("[::1]:8080")).await.1, "example");
+        assert!(
+            VirtualHosts::builder()
+                .hosts(["example.org"], &site("a"))
+                .unwrap()
+                .hosts(["EXAMPLE.org"], &site("b"))
+                .is_err()
+        );
+    }
+}